@@ -0,0 +1,27 @@
+extern crate cbindgen;
+
+use std::env;
+use std::path::Path;
+
+// Generate the C header for the FFI interface into `OUT_DIR`
+//   `X86_INTERPRETER_HEADER=include/x86_interpreter.h cargo build` also writes it to the given path
+//   (this is how the copy shipped alongside the staticlib is refreshed)
+fn main() {
+    println!("cargo:rerun-if-changed=src/ffi.rs");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+    println!("cargo:rerun-if-env-changed=X86_INTERPRETER_HEADER");
+
+    let config = cbindgen::Config::from_file("cbindgen.toml").expect("Unable to read cbindgen.toml");
+    let header = cbindgen::Builder::new()
+        .with_config(config)
+        .with_src("src/ffi.rs")
+        .generate()
+        .expect("Unable to generate the C header");
+
+    let out_dir = env::var("OUT_DIR").expect("OUT_DIR isn't set");
+    header.write_to_file(Path::new(&out_dir).join("x86_interpreter.h"));
+
+    if let Some(path) = env::var_os("X86_INTERPRETER_HEADER") {
+        header.write_to_file(path);
+    }
+}
@@ -0,0 +1,214 @@
+#ifndef X86_INTERPRETER_H
+#define X86_INTERPRETER_H
+
+/* Generated by cbindgen from src/ffi.rs (see build.rs), do not edit */
+
+#include <stddef.h>
+#include <stdint.h>
+
+/**
+ * AT&T syntax (`movl $4, %eax`)
+ */
+#define X86_SYNTAX_ATT 0
+
+/**
+ * Intel syntax (`mov eax, 4`)
+ */
+#define X86_SYNTAX_INTEL 1
+
+/**
+ * 32bit Linux memory layout (text at 0x08048000, stack below 0xc0000000)
+ */
+#define X86_LAYOUT_I386 0
+
+/**
+ * 64bit Linux memory layout (text at 0x400000, stack below 0x7ffffffff000)
+ */
+#define X86_LAYOUT_X86_64 1
+
+/**
+ * The program's standard output (see `x86_emulator_output`)
+ */
+#define X86_STREAM_STDOUT 1
+
+/**
+ * The program's standard error
+ */
+#define X86_STREAM_STDERR 2
+
+/**
+ * The emulator's own output (the instruction trace and register dumps)
+ */
+#define X86_STREAM_LOG 3
+
+/**
+ * Status codes returned by every function (errors are negative)
+ */
+typedef enum {
+  /**
+   * The call succeeded
+   */
+  X86_STATUS_OK = 0,
+  /**
+   * `x86_emulator_step`: the program has finished
+   */
+  X86_STATUS_HALTED = 1,
+  /**
+   * A null pointer, invalid utf-8 string or unknown syntax was passed in
+   */
+  X86_STATUS_INVALID_ARGUMENT = -1,
+  X86_STATUS_PARSE = -2,
+  X86_STATUS_UNKNOWN_MNEMONIC = -3,
+  X86_STATUS_INVALID_OPERAND = -4,
+  X86_STATUS_UNKNOWN_REGISTER = -5,
+  X86_STATUS_MEMORY_FAULT = -6,
+  /**
+   * Division by zero or a quotient that doesn't fit (#DE)
+   */
+  X86_STATUS_DIVIDE = -7,
+  X86_STATUS_UNDEFINED_LABEL = -8,
+  X86_STATUS_UNIMPLEMENTED = -9,
+  /**
+   * The file given to `interpret_file` couldn't be read
+   */
+  X86_STATUS_IO = -10,
+  /**
+   * The interpreter hit an internal error (the handle should be freed)
+   */
+  X86_STATUS_PANIC = -11,
+} X86Status;
+
+/**
+ * Opaque emulator handle (created by `x86_emulator_new`, released by `x86_emulator_free`)
+ */
+typedef struct X86Emulator X86Emulator;
+
+/**
+ * Create an emulator with the 32bit layout and default stack size (see `x86_emulator_new_with_layout`)
+ */
+X86Emulator *x86_emulator_new(void);
+
+/**
+ * Create an emulator with one of the `X86_LAYOUT_*` memory layouts and a `stack_size` byte stack
+ * Returns null if the emulator couldn't be created (or the layout is unknown)
+ */
+X86Emulator *x86_emulator_new_with_layout(int layout, uint64_t stack_size);
+
+void x86_emulator_free(X86Emulator *handle);
+
+/**
+ * Message of the most recent failed call on the handle (or null if no call has failed)
+ * The string lives until the next failure or `x86_emulator_free`
+ */
+const char *x86_emulator_last_error(const X86Emulator *handle);
+
+/**
+ * Load a program (`source` holds newline separated lines), registers are kept but the data sections are rewritten
+ */
+X86Status x86_emulator_load(X86Emulator *handle,
+                            const char *source,
+                            int syntax);
+
+/**
+ * Load raw machine code at the start of the text region, the program ends when it runs off the end of the code
+ */
+X86Status x86_emulator_load_bytes(X86Emulator *handle,
+                                  const uint8_t *code,
+                                  size_t len);
+
+/**
+ * Load a statically linked ELF executable, `argv` (including the program name) and `envp` are null terminated
+ * arrays (or null). The memory layout is replaced to match the executable and the registers are cleared
+ */
+X86Status x86_emulator_load_elf(X86Emulator *handle,
+                                const uint8_t *image,
+                                size_t len,
+                                const char *const *argv,
+                                const char *const *envp);
+
+/**
+ * Execute the next instruction, returns `X86_STATUS_HALTED` once the program has finished
+ */
+X86Status x86_emulator_step(X86Emulator *handle);
+
+/**
+ * Run the program until it exits or faults
+ */
+X86Status x86_emulator_run(X86Emulator *handle);
+
+/**
+ * Exit status the program passed to the `exit`/`exit_group` system call (0 if it didn't make one)
+ */
+X86Status x86_emulator_exit_status(X86Emulator *handle, int *status);
+
+/**
+ * Registers are named without the `%` (ie. "eax" or "r8w")
+ */
+X86Status x86_emulator_read_register(X86Emulator *handle, const char *name, uint64_t *value);
+
+/**
+ * The value has to fit in the register (as either a signed or unsigned value)
+ */
+X86Status x86_emulator_write_register(X86Emulator *handle, const char *name, uint64_t value);
+
+/**
+ * Memory accesses stop at the first byte that is out of bounds
+ */
+X86Status x86_emulator_read_memory(X86Emulator *handle, uint64_t addr, uint8_t *buf, size_t len);
+
+X86Status x86_emulator_write_memory(X86Emulator *handle,
+                                    uint64_t addr,
+                                    const uint8_t *buf,
+                                    size_t len);
+
+/**
+ * Disassemble the instructions starting within `[addr, addr + len)` into `buf` (one per line, nul terminated)
+ * Lines look like `0x08048000:  b8 04 00 00 00                mov $0x4,%eax`, fails if `buf_len` is too small
+ */
+X86Status x86_emulator_disassemble(X86Emulator *handle,
+                                   uint64_t addr,
+                                   uint64_t len,
+                                   char *buf,
+                                   size_t buf_len);
+
+/**
+ * Look up the bounds of a memory region ("text", "rodata", "data", "bss", "heap" or "stack")
+ */
+X86Status x86_emulator_region(X86Emulator *handle,
+                              const char *name,
+                              uint64_t *start,
+                              uint64_t *size);
+
+/**
+ * Capture the program's output (and the emulator's own output) instead of printing it, with the `len` bytes
+ * at `input` as its standard input. The captured text is read with `x86_emulator_output`
+ */
+X86Status x86_emulator_capture_io(X86Emulator *handle,
+                                  const uint8_t *input,
+                                  size_t len);
+
+/**
+ * Copy everything captured on one of the `X86_STREAM_*` streams into `buf` (nul terminated),
+ * fails if `buf_len` is too small
+ */
+X86Status x86_emulator_output(X86Emulator *handle, int stream, char *buf, size_t buf_len);
+
+/**
+ * Interpret an AT&T syntax file, the register dump is written to stderr
+ * On failure the message is available through `x86_last_error`
+ */
+X86Status interpret_file(const char *file);
+
+/**
+ * Interpret AT&T syntax code, the register dump is written to stderr
+ * On failure the message is available through `x86_last_error`
+ */
+X86Status interpret_string(const char *c_str_ptr);
+
+/**
+ * Message of the most recent failed `interpret_file`/`interpret_string` call on this thread (or null)
+ * The string lives until the next failure of either function on the same thread
+ */
+const char *x86_last_error(void);
+
+#endif /* X86_INTERPRETER_H */
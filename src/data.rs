@@ -0,0 +1,504 @@
+use emu::Emulator;
+use error::InterpError;
+use parse;
+use ximpl;
+use ximpl::{Code, Command, Datum, Size, Term};
+use nom::IResult;
+use std::str;
+
+// Assemble the data sections of a program (`.data`, `.rodata` and `.bss`)
+//   Lines within a data section are laid out in memory and removed from the code,
+//   with any labels on them bound to the address of their data
+//
+// Code lives in the `.text` section (the default), which can't hold data directives,
+// though it can be aligned (padded with `nop`s, like the output of `gcc -S`)
+//
+// Sections that aren't loaded into memory (ie. `.note.GNU-stack` or `.comment`) are skipped,
+// and common symbols (`.comm`/`.lcomm`) are allocated in `.bss` wherever they're declared
+//
+// Symbol assignments (ie. `len = . - msg`) can go in any section and are evaluated
+// once the code has been placed
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Section {
+    Text,
+    Data,
+    Rodata,
+    Bss,
+    Unloaded
+}
+
+impl Section {
+    fn from_name(name: &str, flags: Option<&str>) -> Option<Section> {
+        // Subsections share their parent's region (ie. `.rodata.str1.1`)
+        let base = name.split('.').nth(1).unwrap_or("");
+        match base {
+            "text" => Some(Section::Text),
+            "data" => Some(Section::Data),
+            "rodata" => Some(Section::Rodata),
+            "bss" => Some(Section::Bss),
+            // Notes and comments only describe the program
+            _ if ["note", "comment", "ident"].contains(&base) || base.starts_with("debug") => Some(Section::Unloaded),
+            _ if flags.is_some_and(|flags| !flags.contains('a')) => Some(Section::Unloaded),
+            _ => None
+        }
+    }
+
+    fn region(&self) -> &'static str {
+        match *self {
+            Section::Text => "text",
+            Section::Data => "data",
+            Section::Rodata => "rodata",
+            Section::Bss => "bss",
+            Section::Unloaded => unreachable!()
+        }
+    }
+}
+
+// Where the next piece of data goes in each section
+struct Cursors {
+    data: u64,
+    rodata: u64,
+    bss: u64
+}
+
+impl Cursors {
+    fn get(&mut self, section: Section) -> &mut u64 {
+        match section {
+            Section::Data => &mut self.data,
+            Section::Rodata => &mut self.rodata,
+            Section::Bss => &mut self.bss,
+            Section::Text | Section::Unloaded => unreachable!()
+        }
+    }
+}
+
+// Labels used as data (ie. `.long table`) are filled in by `link` once every label is known
+pub struct Fixup {
+    addr: u64,
+    size: Size,
+    symbol: String,
+    line: usize
+}
+
+// `name = terms` (`.` is `here`, or the address of the next instruction in the text section)
+pub struct Assignment {
+    name: String,
+    terms: Vec<(i64, Term)>,
+    here: Option<u64>,
+    line: usize
+}
+
+// Everything `link` has to fill in
+pub struct Unresolved {
+    fixups: Vec<Fixup>,
+    assignments: Vec<Assignment>
+}
+
+pub fn assemble(code: &mut Vec<Code>, emu: &mut Emulator) -> Result<Unresolved, InterpError> {
+    let mut cursors = Cursors{
+        data: region_start(emu, Section::Data),
+        rodata: region_start(emu, Section::Rodata),
+        bss: region_start(emu, Section::Bss)
+    };
+    let mut fixups = Vec::new();
+    let mut assignments = Vec::new();
+    let mut section = Section::Text;
+
+    let mut pc = 0;
+    while pc < code.len() {
+        let (text, syntax, line) = match code[pc] {
+            Code::Unread(ref text, syntax, line) => (text.clone(), syntax, line),
+            _ => {
+                pc += 1;
+                continue;
+            }
+        };
+
+        let locate = |e: InterpError| e.at(line + 1, &text);
+        if let Some((name, flags)) = parse::section_directive(&text) {
+            section = Section::from_name(&name, flags.as_ref().map(|f| &f[..]))
+                .ok_or_else(|| locate(InterpError::InvalidOperand(format!("Unsupported section: `{}`", name))))?;
+            code.remove(pc);
+            continue;
+        }
+
+        if section == Section::Unloaded {
+            code.remove(pc);
+            continue;
+        }
+
+        if let Some((name, terms)) = parse::assignment(&text) {
+            let here = match section {
+                Section::Text => None,
+                _ => Some(*cursors.get(section))
+            };
+            assignments.push(Assignment{ name, terms, here, line });
+            code.remove(pc);
+            continue;
+        }
+
+        // Split off any label (the rest of the line is the directive)
+        let (label, rest) = match parse::label(&text) {
+            IResult::Done(rest, Command::Label(lbl)) => (Some(lbl), str::from_utf8(rest).unwrap_or("").to_string()),
+            _ => (None, text.clone())
+        };
+
+        let directive = directive_name(&rest);
+        if directive == Some("comm") || directive == Some("lcomm") {
+            common(emu, cursors.get(Section::Bss), &rest).map_err(&locate)?;
+            code.remove(pc);
+            continue;
+        }
+
+        if section == Section::Text {
+            match directive {
+                // The padding depends on where the code ends up, so the encoder fills it in
+                Some(name) if is_alignment(name) => {
+                    let (align, fill, max) = alignment(&rest).map_err(&locate)?;
+                    code[pc] = Code::Parsed(Command::Align(align, fill.unwrap_or(0x90), max), line);
+                    if let Some(lbl) = label {
+                        code.insert(pc, Code::Unread(format!("{}:", lbl), syntax, line));
+                        pc += 1;
+                    }
+                    pc += 1;
+                },
+                Some(name) if is_data_directive(name) => return Err(locate(InterpError::InvalidOperand(
+                    format!("Data directive `.{}` has to be in a data section (ie. `.data`)", name)))),
+                _ => pc += 1
+            }
+            continue;
+        }
+
+        let cursor = cursors.get(section);
+        if let Some(lbl) = label {
+            emu.addSymbol(&lbl, *cursor);
+        }
+
+        match directive {
+            // Symbol bookkeeping (ie. `.globl` or `.type`) doesn't affect the layout
+            Some(name) if is_alignment(name) => {
+                let (align, fill, max) = alignment(&rest).map_err(&locate)?;
+                let pad = ximpl::align_padding(align, max, *cursor);
+                place(emu, section, cursor, &vec![fill.unwrap_or(0); pad as usize]).map_err(&locate)?;
+            },
+            Some(name) if !is_data_directive(name) => (),
+            Some(_) => {
+                let (name, args) = parse::data_directive(&rest)
+                    .ok_or_else(|| locate(InterpError::Parse(rest.trim().to_string())))?;
+                emit(emu, section, cursor, &name, &args, &mut fixups, line).map_err(&locate)?;
+            },
+            None if rest.trim() == "" => (),
+            None => return Err(locate(InterpError::InvalidOperand(
+                format!("Only data directives can be placed in the `.{}` section", section.region()))))
+        }
+        code.remove(pc);
+    }
+
+    Ok(Unresolved{ fixups, assignments })
+}
+
+// Evaluate the symbol assignments (in order) and write the address of every label used as data
+//   (ie. jump tables of code labels)
+pub fn link(unresolved: Unresolved, emu: &mut Emulator) -> Result<(), InterpError> {
+    for assign in unresolved.assignments {
+        let line = assign.line;
+        let here = assign.here.or_else(|| emu.getLineAddress(line)).unwrap_or(0);
+
+        let mut val = 0i64;
+        for (sign, term) in assign.terms {
+            let term = match term {
+                Term::Here => here as i64,
+                Term::Int(val) => val,
+                Term::Symbol(ref sym) => match lookup(emu, sym) {
+                    Some(addr) => addr as i64,
+                    None => return Err(InterpError::UndefinedLabel(sym.clone()).at(line + 1, &emu_source(emu, line)))
+                }
+            };
+            val = val.wrapping_add(sign.wrapping_mul(term));
+        }
+        emu.addSymbol(&assign.name, val as u64);
+    }
+
+    for fixup in unresolved.fixups {
+        let res = match lookup(emu, &fixup.symbol) {
+            Some(addr) => emu.getMemory(fixup.addr as i64, fixup.size).and_then(|mut m| m.set(addr as i64)),
+            None => Err(InterpError::UndefinedLabel(fixup.symbol.clone()))
+        };
+
+        let line = fixup.line;
+        res.map_err(|e| e.at(line + 1, &emu_source(emu, line)))?;
+    }
+
+    Ok(())
+}
+
+// Name of the directive on the line (without the `.`)
+fn directive_name(line: &str) -> Option<&str> {
+    line.trim().strip_prefix('.')
+        .and_then(|name| name.split(|c: char| !(c.is_alphanumeric() || c == '_')).next())
+}
+
+fn is_data_directive(name: &str) -> bool {
+    ["byte", "word", "short", "long", "int", "quad", "ascii", "asciz", "string",
+     "zero", "skip", "space"].contains(&name)
+}
+
+fn is_alignment(name: &str) -> bool {
+    ["align", "balign", "p2align"].contains(&name)
+}
+
+// Alignment (in bytes), fill byte and most padding allowed by an alignment directive
+//   `.align` is in bytes (as on x86 ELF targets), `.p2align` in powers of two
+fn alignment(line: &str) -> Result<(u64, Option<u8>, Option<u64>), InterpError> {
+    let (name, args) = parse::align_directive(line).ok_or_else(|| InterpError::Parse(line.trim().to_string()))?;
+    let (align, fill, max) = match args[..] {
+        [Some(align)] => (align, None, None),
+        [Some(align), fill] => (align, fill, None),
+        [Some(align), fill, max] => (align, fill, max),
+        _ => return Err(InterpError::InvalidOperand(format!("Invalid operands to `.{}`", name)))
+    };
+
+    let align = match &name[..] {
+        "p2align" if (0..32).contains(&align) => 1 << align,
+        "align" | "balign" if align > 0 && (align & (align - 1)) == 0 => align,
+        _ => return Err(InterpError::InvalidOperand(format!("Invalid alignment for `.{}`: {}", name, align)))
+    };
+
+    match max {
+        Some(max) if max < 0 => Err(InterpError::InvalidOperand(format!("Invalid maximum padding for `.{}`: {}", name, max))),
+        _ => Ok((align as u64, fill.map(|fill| fill as u8), max.map(|max| max as u64)))
+    }
+}
+
+// Allocate a common symbol in `.bss` (`.comm name, size, align` or `.lcomm`, from any section)
+fn common(emu: &mut Emulator, cursor: &mut u64, line: &str) -> Result<(), InterpError> {
+    let (name, args) = parse::data_directive(line).ok_or_else(|| InterpError::Parse(line.trim().to_string()))?;
+    let (sym, size, align) = match args[..] {
+        [Datum::Symbol(ref sym), Datum::Int(size)] => (sym, size, 1),
+        [Datum::Symbol(ref sym), Datum::Int(size), Datum::Int(align)] => (sym, size, align),
+        _ => return Err(InterpError::InvalidOperand(format!("Invalid operands to `.{}`", name)))
+    };
+
+    if size < 0 || size > region_size(emu, Section::Bss) as i64 {
+        return Err(InterpError::InvalidOperand(format!("Invalid size for `.{}`: {}", name, size)));
+    }
+    if align <= 0 || (align & (align - 1)) != 0 {
+        return Err(InterpError::InvalidOperand(format!("Invalid alignment for `.{}`: {}", name, align)));
+    }
+
+    let pad = ximpl::align_padding(align as u64, None, *cursor);
+    place(emu, Section::Bss, cursor, &vec![0; (pad + size as u64) as usize])?;
+    emu.addSymbol(sym, *cursor - size as u64);
+    Ok(())
+}
+
+// Lay out a single directive at the cursor (and move the cursor past it)
+fn emit(emu: &mut Emulator, section: Section, cursor: &mut u64, name: &str, args: &[Datum],
+        fixups: &mut Vec<Fixup>, line: usize) -> Result<(), InterpError> {
+    let mut bytes = Vec::new();
+
+    match name {
+        "byte" | "word" | "short" | "long" | "int" | "quad" => {
+            let size = match name {
+                "byte" => Size::Byte,
+                "word" | "short" => Size::Word,
+                "long" | "int" => Size::Long,
+                _ => Size::Quad
+            };
+
+            for (idx, arg) in args.iter().enumerate() {
+                let val = match *arg {
+                    Datum::Int(val) => val,
+                    Datum::Symbol(ref sym) => {
+                        let addr = *cursor + (idx * size.bytes()) as u64;
+                        fixups.push(Fixup{ addr, size, symbol: sym.clone(), line });
+                        0
+                    },
+                    Datum::Str(_) => return Err(InterpError::InvalidOperand(format!("`.{}` expects numbers, not strings", name)))
+                };
+
+                let bits = 8 * size.bytes();
+                if size != Size::Quad && (val < -(1 << (bits - 1)) || val >= 1 << bits) {
+                    return Err(InterpError::InvalidOperand(
+                        format!("Value {} is too large for a {} byte location", val, size.bytes())));
+                }
+                bytes.extend((0..size.bytes()).map(|byt| (val >> (8 * byt)) as u8));
+            }
+        },
+        "ascii" | "asciz" | "string" => {
+            for arg in args {
+                match *arg {
+                    Datum::Str(ref s) => bytes.extend(s.iter().cloned()),
+                    _ => return Err(InterpError::InvalidOperand(format!("`.{}` expects strings", name)))
+                }
+
+                // Every string gets its own terminator
+                if name != "ascii" {
+                    bytes.push(0);
+                }
+            }
+        },
+        "zero" | "skip" | "space" => {
+            let (len, fill) = match args {
+                [Datum::Int(len)] => (*len, 0),
+                [Datum::Int(len), Datum::Int(fill)] if name != "zero" => (*len, *fill),
+                _ => return Err(InterpError::InvalidOperand(format!("Invalid operands to `.{}`", name)))
+            };
+
+            if len < 0 || len > region_size(emu, section) as i64 {
+                return Err(InterpError::InvalidOperand(format!("Invalid length for `.{}`: {}", name, len)));
+            }
+            bytes.resize(len as usize, fill as u8);
+        },
+        _ => unreachable!()
+    }
+
+    place(emu, section, cursor, &bytes)
+}
+
+// Write the bytes at the cursor (and move the cursor past them)
+fn place(emu: &mut Emulator, section: Section, cursor: &mut u64, bytes: &[u8]) -> Result<(), InterpError> {
+    // `.bss` is only ever zero filled
+    if section == Section::Bss && bytes.iter().any(|&byt| byt != 0) {
+        return Err(InterpError::InvalidOperand("The `.bss` section can't hold initialised data".to_owned()));
+    }
+
+    let end = *cursor + bytes.len() as u64;
+    if end > region_start(emu, section) + region_size(emu, section) {
+        return Err(InterpError::InvalidOperand(
+            format!("The `.{}` section is larger than its {} KiB region", section.region(), region_size(emu, section) / 1024)));
+    }
+
+    for (idx, &byt) in bytes.iter().enumerate() {
+        emu.getMemory((*cursor + idx as u64) as i64, Size::Byte)?.set(byt)?;
+    }
+    *cursor = end;
+    Ok(())
+}
+
+// Data labels (and assigned symbols) take priority over code labels
+fn lookup(emu: &Emulator, sym: &str) -> Option<u64> {
+    emu.getSymbol(sym).or_else(|| emu.getLabel(sym))
+}
+
+fn region_start(emu: &Emulator, section: Section) -> u64 {
+    emu.getRegion(section.region()).map(|r| r.start).unwrap_or(0)
+}
+
+fn region_size(emu: &Emulator, section: Section) -> u64 {
+    emu.getRegion(section.region()).map(|r| r.size()).unwrap_or(0)
+}
+
+fn emu_source(emu: &Emulator, line: usize) -> String {
+    emu.getSource(line).unwrap_or("").to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use emu::Emulator;
+    use error::InterpError;
+    use host::NullIo;
+    use ximpl::Syntax;
+
+    fn load(code: &str) -> Result<Emulator, InterpError> {
+        let mut emu = Emulator::new();
+        emu.setIo(Box::new(NullIo));
+        emu.load(code.lines().map(|l| l.to_owned()), Syntax::ATT).map(|_| emu)
+    }
+
+    fn text_start(emu: &Emulator) -> u64 {
+        emu.getRegion("text").unwrap().start
+    }
+
+    #[test]
+    fn text_alignment() {
+        let code = "movl $1, %eax\n.p2align 4\nsecond: movl $2, %ebx\n.p2align 4,,2\nthird: incl %ebx\n\
+                    .p2align 3\nfourth: .align 16\nfifth: incl %ebx";
+        let mut emu = load(code).unwrap();
+        let start = text_start(&emu);
+
+        // Padding is made of `nop`s (`.p2align 4,,2` is skipped, as it needs more than 2 bytes)
+        assert_eq!(emu.getLabel("second"), Some(start + 16));
+        assert_eq!(emu.readBytes(start + 5, 11).unwrap(), vec![0x90; 11]);
+        assert_eq!(emu.getLabel("third"), Some(start + 21));
+        assert_eq!(emu.getLabel("fourth"), Some(start + 24));
+        assert_eq!(emu.getLabel("fifth"), Some(start + 32));
+
+        emu.execute().unwrap();
+        assert_eq!(emu.getReg("eax").unwrap().get(), 1);
+        assert_eq!(emu.getReg("ebx").unwrap().get(), 4);
+    }
+
+    #[test]
+    fn data_alignment() {
+        let code = ".data\n.byte 1\n.align 8\nfirst: .long 5\n.p2align 4, 0xff\nsecond: .byte 2\n.balign 4,,1\nthird: .byte 3";
+        let mut emu = load(code).unwrap();
+        let start = emu.getRegion("data").unwrap().start;
+
+        assert_eq!(emu.getSymbol("first"), Some(start + 8));
+        assert_eq!(emu.getSymbol("second"), Some(start + 16));
+        assert_eq!(emu.getSymbol("third"), Some(start + 17));
+        assert_eq!(emu.readBytes(start, 17).unwrap(),
+                   vec![1, 0, 0, 0, 0, 0, 0, 0, 5, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 2]);
+
+        assert!(load(".data\n.p2align 32").is_err());
+        assert!(load(".data\n.align 3").is_err());
+        assert!(load(".data\n.balign 4,,-1").is_err());
+    }
+
+    #[test]
+    fn bss_is_zero_filled() {
+        let code = ".bss\nbuf: .zero 6\n.align 4\nwords: .skip 8\nlast: .space 2, 0\n\
+                    .text\nmovl $buf, %esi\nmovl 4(%esi), %eax\nmovl $last, %ebx\nsubl $buf, %ebx";
+        let mut emu = load(code).unwrap();
+        let start = emu.getRegion("bss").unwrap().start;
+
+        assert_eq!(emu.getSymbol("buf"), Some(start));
+        assert_eq!(emu.getSymbol("words"), Some(start + 8));
+        assert_eq!(emu.getSymbol("last"), Some(start + 16));
+        assert_eq!(emu.readBytes(start, 18).unwrap(), vec![0; 18]);
+
+        emu.execute().unwrap();
+        assert_eq!(emu.getReg("eax").unwrap().get(), 0);
+        assert_eq!(emu.getReg("ebx").unwrap().get(), 16);
+
+        assert!(load(".bss\n.byte 1").is_err());
+        assert!(load(".bss\n.skip 4, 1").is_err());
+    }
+
+    #[test]
+    fn common_symbols() {
+        let code = ".bss\nfirst: .byte 0\n.text\n.local counter.0\n.comm counter.0,4,4\n.lcomm buf, 16, 16\n\
+                    .comm flag,1\nincl counter.0\nincl counter.0\nmovl counter.0, %eax";
+        let mut emu = load(code).unwrap();
+        let start = emu.getRegion("bss").unwrap().start;
+
+        assert_eq!(emu.getSymbol("counter.0"), Some(start + 4));
+        assert_eq!(emu.getSymbol("buf"), Some(start + 16));
+        assert_eq!(emu.getSymbol("flag"), Some(start + 32));
+
+        emu.execute().unwrap();
+        assert_eq!(emu.getReg("eax").unwrap().get(), 2);
+
+        assert!(load(".comm buf, -1").is_err());
+        assert!(load(".comm buf, 4, 3").is_err());
+        assert!(load(".comm 4, 4").is_err());
+    }
+
+    #[test]
+    fn compiler_sections() {
+        let code = "\t.text\n\t.section\t.rodata.str1.1,\"aMS\",@progbits,1\nmsg: .string \"hi\"\n\
+                    \t.text\n\t.p2align 4\n\t.globl\tmain\nmain: movl $msg, %eax\n\
+                    \t.section\t.note.GNU-stack,\"\",@progbits\n\
+                    \t.section\t.note.gnu.property,\"a\"\n\t.align 4\n\t.long 4\n\
+                    \t.section\t.comment\n\t.string \"GCC\"";
+        let mut emu = load(code).unwrap();
+        assert_eq!(emu.getLabel("main"), Some(text_start(&emu)));
+        assert_eq!(emu.readBytes(emu.getSymbol("msg").unwrap(), 3).unwrap(), b"hi\0");
+
+        // Allocated sections without a region are still an error
+        assert!(load(".section .init_array,\"aw\"\n.long 0").is_err());
+        assert!(load(".section .custom\n.long 0").is_err());
+    }
+}
@@ -0,0 +1,324 @@
+use std::io;
+use std::io::Write;
+use emu::{Emulator, StopReason};
+use error::InterpError;
+use host::{GuestIo, HostIo, Stream};
+use parse::parse_int;
+use ximpl::{Flag, Mode, REGISTERS};
+
+// Interactive debugger (`x86_inter --debug`)
+//   gdb-like commands drive a loaded program through the step API of the `Emulator`,
+//   and every stop shows the next instruction, the registers and the top of the stack
+
+const HELP: &str = "\
+Commands (an empty line repeats the last one):
+  s, step [<n>]         execute an instruction
+  n, next [<n>]         execute an instruction, running calls through to their return
+  f, finish             run until the current function returns
+  c, continue           run until a breakpoint (or the end of the program)
+  b, break <loc>        stop before the instruction at <loc>
+  d, delete [<loc>]     remove a breakpoint (all of them by default)
+  i, info b|r           list the breakpoints or show the registers
+  p, print <val>        show a register, label or symbol (ie. `p %eax`)
+  x <val> [<len>]       show <len> bytes of memory (16 by default)
+  l, list [<line>]      show the source around the current (or given) line
+  q, quit               leave the debugger
+Locations are labels, line numbers or addresses (`*0x8048000`)";
+
+const FLAGS: [(Flag, &str); 9] = [
+    (Flag::Carry, "CF"), (Flag::Parity, "PF"), (Flag::Adjust, "AF"), (Flag::Zero, "ZF"), (Flag::Sign, "SF"),
+    (Flag::Trap, "TF"), (Flag::Interrupt, "IF"), (Flag::Direction, "DF"), (Flag::Overflow, "OF")
+];
+
+// The program's streams stay on the terminal, but the instruction trace and dumps are left out
+//   (the stops show the state instead)
+struct DebugIo;
+
+impl GuestIo for DebugIo {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        HostIo.read(buf)
+    }
+
+    fn write(&mut self, stream: Stream, bytes: &[u8]) -> io::Result<()> {
+        match stream {
+            Stream::Log => Ok(()),
+            _ => HostIo.write(stream, bytes)
+        }
+    }
+}
+
+// Debug a loaded program until the user quits (or stdin ends), returns its exit status
+pub fn debug(emu: &mut Emulator) -> i32 {
+    emu.setIo(Box::new(DebugIo));
+    println!("Type `help` for a list of commands");
+    show_stop(emu);
+
+    let mut last = String::new();
+    loop {
+        print!("(x86db) ");
+        let _ = io::stdout().flush();
+
+        let mut line = String::new();
+        match io::stdin().read_line(&mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => ()
+        }
+        if !line.trim().is_empty() {
+            last = line.trim().to_owned();
+        }
+
+        match command(emu, &last) {
+            Ok(true) => break,
+            Ok(false) => (),
+            Err(msg) => println!("{}", msg)
+        }
+    }
+    emu.getExitStatus()
+}
+
+// Returns whether to leave the debugger
+fn command(emu: &mut Emulator, line: &str) -> Result<bool, String> {
+    let mut words = line.split_whitespace();
+    let cmd = words.next().unwrap_or("");
+    let args = words.collect::<Vec<_>>();
+
+    match cmd {
+        "s" | "step" | "si" | "stepi" => run(emu, Emulator::stepInstruction, count(&args)?)?,
+        "n" | "next" | "ni" | "nexti" => run(emu, Emulator::stepOver, count(&args)?)?,
+        "f" | "finish" => run(emu, Emulator::stepOut, 1)?,
+        "c" | "continue" => run(emu, Emulator::resume, 1)?,
+        "b" | "break" => {
+            let addr = location(emu, args.first().ok_or("Usage: break <label|line|*addr>")?)?;
+            if !emu.addBreakpoint(addr) {
+                return Err(format!("There already is a breakpoint at {}", describe(emu, addr)));
+            }
+            println!("Breakpoint at {}", describe(emu, addr));
+        },
+        "d" | "delete" => match args.first() {
+            Some(loc) => {
+                let addr = location(emu, loc)?;
+                if !emu.removeBreakpoint(addr) {
+                    return Err(format!("There is no breakpoint at {}", describe(emu, addr)));
+                }
+            },
+            None => for addr in emu.getBreakpoints() {
+                emu.removeBreakpoint(addr);
+            }
+        },
+        "i" | "info" => match args.first().cloned() {
+            Some("b") | Some("break") | Some("breakpoints") => {
+                let breakpoints = emu.getBreakpoints();
+                if breakpoints.is_empty() {
+                    println!("No breakpoints");
+                }
+                for addr in breakpoints {
+                    println!("  {}", describe(emu, addr));
+                }
+            },
+            Some("r") | Some("registers") => print_registers(emu),
+            _ => return Err("Usage: info breakpoints|registers".to_owned())
+        },
+        "p" | "print" => {
+            let val = args.first().ok_or("Usage: print <%reg|label|addr>")?;
+            let num = value(emu, val)?;
+            println!("{} = {:#x} ({})", val, num, num as i64);
+        },
+        "x" => {
+            let addr = value(emu, args.first().ok_or("Usage: x <%reg|label|addr> [<len>]")?)?;
+            let len = match args.get(1) {
+                Some(len) => parse_int(len).ok_or(format!("Invalid length: `{}`", len))?,
+                None => 16
+            };
+            let end = addr.checked_add(len).ok_or(format!("{:#x} + {:#x} is past the end of the address space", addr, len))?;
+            examine(emu, addr, end);
+        },
+        "l" | "list" => {
+            let line = match args.first() {
+                Some(line) => parse_int(line).and_then(|line| line.checked_sub(1)).ok_or(format!("Invalid line: `{}`", line))? as usize,
+                None => emu.getAddressLine(emu.getPC()).ok_or("There is no source for the current instruction")?
+            };
+            list(emu, line);
+        },
+        "h" | "help" => println!("{}", HELP),
+        "q" | "quit" => return Ok(true),
+        "" => (),
+        _ => return Err(format!("Unknown command: `{}` (try `help`)", cmd))
+    }
+    Ok(false)
+}
+
+// Make `times` runs (stopping early at a breakpoint or the end of the program)
+fn run(emu: &mut Emulator, how: fn(&mut Emulator) -> Result<StopReason, InterpError>, times: u64) -> Result<(), String> {
+    if !emu.run() {
+        return Err("The program isn't running".to_owned());
+    }
+
+    for _ in 0..times {
+        match how(emu) {
+            Ok(StopReason::Stepped) => (),
+            Ok(StopReason::Breakpoint(addr)) => {
+                println!("Hit the breakpoint at {}", describe(emu, addr));
+                break;
+            },
+            Ok(StopReason::Exited) => {
+                println!("The program exited with status {}", emu.getExitStatus());
+                return Ok(());
+            },
+            Err(e) => {
+                println!("The program stopped: {}", e);
+                return Ok(());
+            }
+        }
+    }
+
+    show_stop(emu);
+    Ok(())
+}
+
+fn count(args: &[&str]) -> Result<u64, String> {
+    match args.first() {
+        Some(num) => parse_int(num).filter(|&num| num > 0).ok_or(format!("Invalid count: `{}`", num)),
+        None => Ok(1)
+    }
+}
+
+// Breakpoint locations: `*0x8048000` (or just `0x8048000`), a 1-based line number or a label
+fn location(emu: &Emulator, loc: &str) -> Result<u64, String> {
+    if let Some(addr) = loc.strip_prefix('*') {
+        return parse_int(addr).ok_or(format!("Invalid address: `{}`", addr));
+    }
+    if loc.starts_with("0x") {
+        return parse_int(loc).ok_or(format!("Invalid address: `{}`", loc));
+    }
+
+    if loc.chars().all(|c| c.is_ascii_digit()) {
+        let line = loc.parse::<usize>().ok().filter(|&line| line > 0 && emu.getSource(line - 1).is_some())
+            .ok_or(format!("There is no line {}", loc))?;
+        // Lines without an instruction (ie. labels) break on the next one
+        return match (emu.getLineAddress(line - 1), emu.getCodeRange()) {
+            (Some(addr), Some((_, end))) if addr != end => Ok(addr),
+            _ => Err(format!("There are no instructions from line {} on", line))
+        };
+    }
+
+    emu.getLabel(loc).ok_or(format!("Unknown label: `{}`", loc))
+}
+
+// Values to print/examine: `%reg`, a label or symbol, or a number
+fn value(emu: &mut Emulator, val: &str) -> Result<u64, String> {
+    if let Some(reg) = val.strip_prefix('%') {
+        return match reg {
+            "rip" | "eip" => Ok(emu.getPC()),
+            _ => emu.getReg(reg).map(|r| r.get_unsigned()).map_err(|e| e.to_string())
+        };
+    }
+
+    parse_int(val).or_else(|| emu.getSymbol(val)).or_else(|| emu.getLabel(val))
+        .ok_or(format!("Unknown value: `{}` (expected a register, label or number)", val))
+}
+
+// `0x08048005 (line 3: movl $4, %eax)`
+fn describe(emu: &Emulator, addr: u64) -> String {
+    match emu.getAddressLine(addr).and_then(|line| emu.getSource(line).map(|src| (line, src))) {
+        Some((line, src)) => format!("{:#010x} (line {}: `{}`)", addr, line + 1, src.trim()),
+        None => format!("{:#010x}", addr)
+    }
+}
+
+// Where the program is, its registers and the top of the stack
+fn show_stop(emu: &mut Emulator) {
+    let pc = emu.getPC();
+    if let Some(line) = emu.getAddressLine(pc) {
+        println!("{:>5}  {}", line + 1, emu.getSource(line).unwrap_or("").trim());
+    }
+
+    if emu.getCodeRange().map(|(_, end)| end) == Some(pc) {
+        println!("=> {:#010x}:  (end of the program)", pc);
+    } else {
+        match emu.disassemble(pc, 1) {
+            Ok(ref listing) if !listing.is_empty() => println!("=> {}", listing[0]),
+            Ok(_) => (),
+            Err(e) => println!("=> {:#010x}:  {}", pc, e)
+        }
+    }
+
+    print_registers(emu);
+    print_stack(emu);
+}
+
+// The general purpose registers of the mode, `%rip` and the flags that are set
+fn print_registers(emu: &mut Emulator) {
+    let (names, width) = match emu.getMode() {
+        Mode::Bits64 => (REGISTERS.iter().map(|r| r.to_string()).collect::<Vec<_>>(), 18),
+        Mode::Bits32 => (REGISTERS[..8].iter().map(|r| r.replacen('r', "e", 1)).collect::<Vec<_>>(), 10)
+    };
+
+    for row in names.chunks(4) {
+        let regs = row.iter().map(|name| {
+            let val = emu.getReg(name).map(|r| r.get_unsigned()).unwrap_or(0);
+            format!("{:<3} {:#0width$x}", name, val, width = width)
+        }).collect::<Vec<_>>();
+        println!("  {}", regs.join("  "));
+    }
+
+    let flags = FLAGS.iter().filter(|&&(flag, _)| emu.getFlag(flag)).map(|&(_, name)| name).collect::<Vec<_>>();
+    let ip = if width == 10 { "eip" } else { "rip" };
+    println!("  {:<3} {:#0width$x}  eflags {:#x} [ {} ]", ip, emu.getPC(), emu.getEflags(), flags.join(" "), width = width);
+}
+
+// A few words from the top of the stack (stopping at the end of the stack)
+fn print_stack(emu: &mut Emulator) {
+    let size = emu.getMode().stack_size();
+    let sp = emu.getSP();
+
+    let words = (0..4).map_while(|idx| {
+        let addr = sp.wrapping_add(idx * size.bytes() as u64);
+        emu.getMemory(addr as i64, size).ok().map(|m| format!("{:#0width$x}", m.get_unsigned(), width = 2 + 2 * size.bytes()))
+    }).collect::<Vec<_>>();
+
+    if !words.is_empty() {
+        println!("  stack {:#x}: {}", sp, words.join(" "));
+    }
+}
+
+// Hex dump of memory in `[addr, end)` (16 bytes a line, with the printable characters)
+fn examine(emu: &mut Emulator, addr: u64, end: u64) {
+    let mut cur = addr;
+    while cur < end {
+        let row = (end - cur).min(16);
+        // Fall back to single bytes so an unmapped page only blanks out the bytes it covers
+        let bytes: Vec<_> = match emu.readBytes(cur, row) {
+            Ok(bytes) => bytes.into_iter().map(Some).collect(),
+            Err(e) => {
+                let bytes: Vec<_> = (cur..cur + row).map(|at| emu.readBytes(at, 1).ok().map(|byt| byt[0])).collect();
+                if bytes.iter().all(Option::is_none) {
+                    println!("{}", e);
+                    break;
+                }
+                bytes
+            }
+        };
+
+        let hex = bytes.iter().map(|byt| byt.map_or("??".to_string(), |byt| format!("{:02x}", byt))).collect::<Vec<_>>().join(" ");
+        let text = bytes.iter().map(|byt| match *byt {
+            Some(byt) if byt.is_ascii_graphic() || byt == b' ' => byt as char,
+            Some(_) => '.',
+            None => '?'
+        }).collect::<String>();
+        println!("{:#010x}:  {:<47}  {}", cur, hex, text);
+        cur += row;
+    }
+}
+
+// The source around a (0-based) line, marking the current instruction
+fn list(emu: &Emulator, line: usize) {
+    let current = emu.getAddressLine(emu.getPC());
+    for idx in line.saturating_sub(4)..line.saturating_add(5) {
+        let src = match emu.getSource(idx) {
+            Some(src) => src,
+            None => break
+        };
+        let mark = if Some(idx) == current { "=>" } else { "  " };
+        println!("{} {:>4}  {}", mark, idx + 1, src);
+    }
+}
@@ -0,0 +1,566 @@
+use std::fmt;
+use ximpl::{Argument, Command, Mode, Size};
+use encode::{DUMP_OPCODE, EXIT_OPCODE};
+use error::InterpError;
+
+// Decode x86 machine code back into instructions
+//   Instructions come out the way the parser would have produced them (ie. `addl $4, %eax`
+//   is `TwoArg("add", Some(Long), Literal(4), Reg("eax"))`), with relative jumps
+//   resolved to the address of their target (`Argument::Target`)
+
+// Intel Manual: Volume 2, Chapter 2 (Instruction Format) and Appendix A (Opcode Map)
+// http://ref.x86asm.net/coder64.html
+
+// Instructions are at most 15 bytes long
+pub const MAX_LENGTH: usize = 15;
+
+const ARITHMETIC: [&str; 8] = ["add", "or", "adc", "sbb", "and", "sub", "xor", "cmp"];
+const SHIFTS: [&str; 8] = ["rol", "ror", "rcl", "rcr", "shl", "shr", "shl", "sar"];
+const UNARY: [&str; 8] = ["test", "test", "not", "neg", "mul", "imul", "div", "idiv"];
+const CONDITIONS: [&str; 16] = ["o", "no", "b", "ae", "e", "ne", "be", "a",
+                                        "s", "ns", "p", "np", "l", "ge", "le", "g"];
+
+// Decode the instruction at the start of `bytes` (placed at `addr`)
+//   Returns the instruction and its length, running out of bytes is a fault on the first missing one
+pub fn decode(bytes: &[u8], addr: u64, mode: Mode) -> Result<(Command, usize), InterpError> {
+    let mut dec = Decoder{
+        bytes,
+        pos: 0,
+        addr,
+        mode,
+        operand16: false,
+        address32: false,
+        rep: false,
+        rex: None
+    };
+
+    let inst = dec.instruction()?;
+    Ok((inst, dec.pos))
+}
+
+// A decoded instruction as listed by `Emulator::disassemble`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Disassembly {
+    pub addr: u64,
+    pub bytes: Vec<u8>,
+    pub text: String
+}
+
+// Printed like `objdump -d` (ie. `0x08048000:  b8 04 00 00 00                mov $0x4,%eax`)
+//   The bytes column fits a `movabs` (10 bytes)
+impl fmt::Display for Disassembly {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let hex = self.bytes.iter().map(|byt| format!("{:02x}", byt)).collect::<Vec<_>>().join(" ");
+        write!(f, "{:#010x}:  {:<29} {}", self.addr, hex, self.text)
+    }
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    addr: u64,
+    mode: Mode,
+
+    // Prefixes
+    operand16: bool,        // 0x66
+    address32: bool,        // 0x67 (64bit mode only)
+    rep: bool,              // 0xf3
+    rex: Option<u8>
+}
+
+impl<'a> Decoder<'a> {
+    fn instruction(&mut self) -> Result<Command, InterpError> {
+        let mut opcode = self.byte()?;
+        loop {
+            match opcode {
+                0x66 => self.operand16 = true,
+                0x67 if self.mode == Mode::Bits64 => self.address32 = true,
+                0xf3 => self.rep = true,
+                // Segment overrides are ignored (`%cs`, `%ss`, `%ds` and `%es` are flat)
+                0x26 | 0x2e | 0x36 | 0x3e => (),
+                _ => break
+            }
+            opcode = self.byte()?;
+        }
+
+        // REX has to come last, just before the opcode
+        if self.mode == Mode::Bits64 && opcode & 0xf0 == 0x40 {
+            self.rex = Some(opcode);
+            opcode = self.byte()?;
+        }
+
+        // `rep` is only used with string instructions, but it's ignored on `ret` and `nop`
+        //   (ie. `rep ret`) and turns `0x0f 0x1e` into `endbr32`/`endbr64`
+        if self.rep && opcode != 0x0f && opcode != 0x90 && opcode != 0xc3 {
+            return Err(self.unsupported("`rep` prefixed instructions"));
+        }
+
+        match opcode {
+            0x0f => self.two_byte(),
+            0x64 | 0x65 => Err(self.unsupported("`%fs`/`%gs` segment overrides")),
+            0x67 => Err(self.unsupported("16bit addressing")),
+            0xf0 | 0xf2 => Err(self.unsupported("`lock`/`repne` prefixes")),
+
+            // add/or/adc/sbb/and/sub/xor/cmp in all six forms (ie. `0x01 /r` is `add r, r/m`)
+            _ if opcode < 0x40 && opcode & 7 < 6 => {
+                let mne = ARITHMETIC[(opcode >> 3) as usize];
+                let size = self.size(opcode & 1 == 0);
+                match opcode & 7 {
+                    0 | 1 => {
+                        let (reg, rm) = self.modrm_sized(size, size)?;
+                        Ok(two(mne, size, reg, rm))
+                    },
+                    2 | 3 => {
+                        let (reg, rm) = self.modrm_sized(size, size)?;
+                        Ok(two(mne, size, rm, reg))
+                    },
+                    _ => {
+                        let imm = self.immediate(size)?;
+                        Ok(two(mne, size, imm, self.register(0, size)))
+                    }
+                }
+            },
+            0x40 ..= 0x4f if self.mode == Mode::Bits32 => {
+                let size = self.size(false);
+                let mne = if opcode < 0x48 { "inc" } else { "dec" };
+                Ok(Command::OneArg(mne.to_owned(), Some(size), self.register(opcode & 7, size)))
+            },
+            0x50 ..= 0x5f => {
+                let size = self.stack_size();
+                let mne = if opcode < 0x58 { "push" } else { "pop" };
+                let reg = self.register((opcode & 7) | self.rex_bit(1), size);
+                Ok(Command::OneArg(mne.to_owned(), Some(size), reg))
+            },
+            0x63 if self.mode == Mode::Bits64 && self.rex_bit(8) != 0 => {
+                let (reg, rm) = self.modrm_sized(Size::Quad, Size::Long)?;
+                Ok(two("movsl", Size::Quad, rm, reg))
+            },
+            0x68 | 0x6a => {
+                let size = self.stack_size();
+                let imm = self.immediate(if opcode == 0x6a { Size::Byte } else { size })?;
+                Ok(Command::OneArg("push".to_owned(), Some(size), imm))
+            },
+            0x69 | 0x6b => {
+                let size = self.size(false);
+                let (reg, rm) = self.modrm_sized(size, size)?;
+                let imm = self.immediate(if opcode == 0x6b { Size::Byte } else { size })?;
+                Ok(Command::ThreeArg("imul".to_owned(), Some(size), imm, rm, reg))
+            },
+            0x70 ..= 0x7f => {
+                let target = self.relative(Size::Byte)?;
+                Ok(jump(&format!("j{}", CONDITIONS[(opcode & 0xf) as usize]), target))
+            },
+            0x80 | 0x81 | 0x83 => {
+                let size = self.size(opcode == 0x80);
+                let (digit, rm) = self.modrm(size)?;
+                let imm = self.immediate(if opcode == 0x81 { size } else { Size::Byte })?;
+                Ok(two(ARITHMETIC[digit as usize], size, imm, rm))
+            },
+            0x84 ..= 0x8b => {
+                let size = self.size(opcode & 1 == 0);
+                let (reg, rm) = self.modrm_sized(size, size)?;
+                match opcode {
+                    0x84 | 0x85 => Ok(two("test", size, reg, rm)),
+                    0x86 | 0x87 => Ok(two("xchg", size, reg, rm)),
+                    0x88 | 0x89 => Ok(two("mov", size, reg, rm)),
+                    _ => Ok(two("mov", size, rm, reg))
+                }
+            },
+            0x8d => {
+                let size = self.size(false);
+                match self.modrm_sized(size, size)? {
+                    (reg, rm @ Argument::Mem(..)) => Ok(two("lea", size, rm, reg)),
+                    _ => Err(self.invalid())
+                }
+            },
+            0x8f => {
+                let size = self.stack_size();
+                match self.modrm(size)? {
+                    (0, rm) => Ok(Command::OneArg("pop".to_owned(), Some(size), rm)),
+                    _ => Err(self.invalid())
+                }
+            },
+            0x90 if self.rex_bit(1) == 0 => Ok(Command::NoArg("nop".to_owned(), None)),
+            0x90 ..= 0x97 => {
+                let size = self.size(false);
+                let reg = self.register((opcode & 7) | self.rex_bit(1), size);
+                Ok(two("xchg", size, reg, self.register(0, size)))
+            },
+            0x98 | 0x99 => {
+                let mne = match (opcode, self.size(false)) {
+                    (0x98, Size::Word) => "cbtw",
+                    (0x98, Size::Long) => "cwtl",
+                    (0x98, _) => "cltq",
+                    (_, Size::Word) => "cwtd",
+                    (_, Size::Long) => "cltd",
+                    _ => "cqto"
+                };
+                Ok(Command::NoArg(mne.to_owned(), None))
+            },
+            // `mov` between the accumulator and an absolute address
+            0xa0 ..= 0xa3 => {
+                let size = self.size(opcode & 1 == 0);
+                let mem = self.absolute_address()?;
+                if opcode < 0xa2 {
+                    Ok(two("mov", size, mem, self.register(0, size)))
+                } else {
+                    Ok(two("mov", size, self.register(0, size), mem))
+                }
+            },
+            0xa8 | 0xa9 => {
+                let size = self.size(opcode == 0xa8);
+                let imm = self.immediate(size)?;
+                Ok(two("test", size, imm, self.register(0, size)))
+            },
+            0xb0 ..= 0xbf => {
+                let size = self.size(opcode < 0xb8);
+                let reg = self.register((opcode & 7) | self.rex_bit(1), size);
+                // `movabs` takes a full 8 byte immediate
+                if size == Size::Quad {
+                    let imm = Argument::Literal(self.value(8)?);
+                    return Ok(two("movabs", size, imm, reg));
+                }
+                let imm = self.immediate(size)?;
+                Ok(two("mov", size, imm, reg))
+            },
+            0xc0 | 0xc1 | 0xd0 | 0xd1 | 0xd2 | 0xd3 => {
+                let size = self.size(opcode & 1 == 0);
+                let (digit, rm) = self.modrm(size)?;
+                let mne = SHIFTS[digit as usize];
+                match opcode {
+                    0xc0 | 0xc1 => {
+                        let count = self.immediate(Size::Byte)?;
+                        Ok(two(mne, size, count, rm))
+                    },
+                    0xd0 | 0xd1 => Ok(Command::OneArg(mne.to_owned(), Some(size), rm)),
+                    _ => Ok(two(mne, size, Argument::Reg("cl".to_owned()), rm))
+                }
+            },
+            0xc2 => {
+                let bytes = self.value(2)?;
+                Ok(Command::OneArg("ret".to_owned(), self.stack_override(), Argument::Literal(bytes as u16 as i64)))
+            },
+            0xc3 => Ok(Command::NoArg("ret".to_owned(), self.stack_override())),
+            0xc6 | 0xc7 => {
+                let size = self.size(opcode == 0xc6);
+                match self.modrm(size)? {
+                    (0, rm) => {
+                        let imm = self.immediate(size)?;
+                        Ok(two("mov", size, imm, rm))
+                    },
+                    _ => Err(self.invalid())
+                }
+            },
+            0xc8 => {
+                let alloc = self.value(2)? as u16 as i64;
+                let level = self.value(1)? as u8 as i64;
+                Ok(Command::TwoArg("enter".to_owned(), self.stack_override(),
+                                   Argument::Literal(alloc), Argument::Literal(level)))
+            },
+            0xc9 => Ok(Command::NoArg("leave".to_owned(), self.stack_override())),
+            DUMP_OPCODE => Ok(Command::NoArg("dump".to_owned(), None)),
+            0xcd => {
+                let vector = self.value(1)? as u8 as i64;
+                Ok(Command::OneArg("int".to_owned(), None, Argument::Literal(vector)))
+            },
+            0xe8 | 0xe9 | 0xeb => {
+                let target = self.relative(if opcode == 0xeb { Size::Byte } else { Size::Long })?;
+                Ok(jump(if opcode == 0xe8 { "call" } else { "jmp" }, target))
+            },
+            EXIT_OPCODE => Ok(Command::NoArg("exit".to_owned(), None)),
+            0xf6 | 0xf7 => {
+                let size = self.size(opcode == 0xf6);
+                let (digit, rm) = self.modrm(size)?;
+                match UNARY[digit as usize] {
+                    "test" => {
+                        let imm = self.immediate(size)?;
+                        Ok(two("test", size, imm, rm))
+                    },
+                    mne => Ok(Command::OneArg(mne.to_owned(), Some(size), rm))
+                }
+            },
+            0xfe | 0xff => {
+                // Jump targets and pushed values are as wide as the stack
+                let size = match (opcode, self.extension()?) {
+                    (0xff, 2) | (0xff, 4) | (0xff, 6) => self.stack_size(),
+                    _ => self.size(opcode == 0xfe)
+                };
+                let (digit, rm) = self.modrm(size)?;
+                match (opcode, digit) {
+                    (_, 0) => Ok(Command::OneArg("inc".to_owned(), Some(size), rm)),
+                    (_, 1) => Ok(Command::OneArg("dec".to_owned(), Some(size), rm)),
+                    (0xff, 2) | (0xff, 4) | (0xff, 6) => {
+                        let mne = ["call", "jmp", "push"][(digit / 2 - 1) as usize];
+                        Ok(Command::OneArg(mne.to_owned(), Some(size), rm))
+                    },
+                    (0xff, 3) | (0xff, 5) => Err(self.unsupported("far calls and jumps")),
+                    _ => Err(self.invalid())
+                }
+            },
+            _ => Err(self.invalid())
+        }
+    }
+
+    // Opcodes that start with `0x0f`
+    fn two_byte(&mut self) -> Result<Command, InterpError> {
+        let opcode = self.byte()?;
+        if self.rep && opcode != 0x1e {
+            return Err(self.unsupported("`rep` prefixed instructions"));
+        }
+
+        match opcode {
+            0x1e if self.rep => match self.byte()? {
+                0xfa => Ok(Command::NoArg("endbr64".to_owned(), None)),
+                0xfb => Ok(Command::NoArg("endbr32".to_owned(), None)),
+                _ => Err(self.invalid())
+            },
+            0x05 if self.mode == Mode::Bits64 => Ok(Command::NoArg("syscall".to_owned(), None)),
+            // Multi-byte `nop` (ie. `nopl 0x0(%eax)`, used for padding)
+            0x1f => {
+                let size = self.size(false);
+                self.modrm(size)?;
+                Ok(Command::NoArg("nop".to_owned(), None))
+            },
+            0x40 ..= 0x4f => {
+                let size = self.size(false);
+                let (reg, rm) = self.modrm_sized(size, size)?;
+                Ok(two(&format!("cmov{}", CONDITIONS[(opcode & 0xf) as usize]), size, rm, reg))
+            },
+            0x80 ..= 0x8f => {
+                let target = self.relative(Size::Long)?;
+                Ok(jump(&format!("j{}", CONDITIONS[(opcode & 0xf) as usize]), target))
+            },
+            0x90 ..= 0x9f => {
+                let (_, rm) = self.modrm(Size::Byte)?;
+                Ok(Command::OneArg(format!("set{}", CONDITIONS[(opcode & 0xf) as usize]), None, rm))
+            },
+            0xa4 | 0xa5 | 0xac | 0xad => {
+                let size = self.size(false);
+                let (reg, rm) = self.modrm_sized(size, size)?;
+                let count = match opcode & 1 {
+                    0 => self.immediate(Size::Byte)?,
+                    _ => Argument::Reg("cl".to_owned())
+                };
+                let mne = if opcode < 0xa8 { "shld" } else { "shrd" };
+                Ok(Command::ThreeArg(mne.to_owned(), Some(size), count, reg, rm))
+            },
+            0xaf => {
+                let size = self.size(false);
+                let (reg, rm) = self.modrm_sized(size, size)?;
+                Ok(two("imul", size, rm, reg))
+            },
+            // `movz`/`movs` (the mnemonic holds the size of the source)
+            0xb6 | 0xb7 | 0xbe | 0xbf => {
+                let size = self.size(false);
+                let src_size = if opcode & 1 == 0 { Size::Byte } else { Size::Word };
+                let (reg, rm) = self.modrm_sized(size, src_size)?;
+                let mne = format!("mov{}{}", if opcode < 0xb8 { "z" } else { "s" }, src_size.suffix());
+                Ok(two(&mne, size, rm, reg))
+            },
+            _ => Err(self.invalid())
+        }
+    }
+
+
+    // Operand sizes
+    // REX.W beats `0x66`, byte opcodes ignore both
+    fn size(&self, byte: bool) -> Size {
+        if byte {
+            Size::Byte
+        } else if self.rex_bit(8) != 0 {
+            Size::Quad
+        } else if self.operand16 {
+            Size::Word
+        } else {
+            Size::Long
+        }
+    }
+
+    // Stack operations can only be narrowed (to 16bits)
+    fn stack_size(&self) -> Size {
+        if self.operand16 { Size::Word } else { self.mode.stack_size() }
+    }
+
+    // Instructions that are sized by the stack only need a suffix when it's been overridden
+    fn stack_override(&self) -> Option<Size> {
+        if self.operand16 { Some(Size::Word) } else { None }
+    }
+
+    // REX bits (`bit` is 8 for W, 4 for R, 2 for X and 1 for B)
+    //   Returns 8 if the bit is set (the high bit of a register number)
+    fn rex_bit(&self, bit: u8) -> u8 {
+        match self.rex {
+            Some(rex) if rex & bit != 0 => 8,
+            _ => 0
+        }
+    }
+
+
+    // Operands
+    fn register(&self, num: u8, size: Size) -> Argument {
+        Argument::Reg(register_name(num, size, self.rex.is_some()))
+    }
+
+    // Like `modrm`, with the reg field as a `dest_size` register and a `src_size` r/m operand
+    fn modrm_sized(&mut self, dest_size: Size, src_size: Size) -> Result<(Argument, Argument), InterpError> {
+        let (reg, rm) = self.modrm(src_size)?;
+        Ok((self.register(reg | self.rex_bit(4), dest_size), rm))
+    }
+
+    // Decode the ModR/M (and SIB/displacement) into the reg field and a `size` r/m operand
+    //   The reg field is returned as is (ie. the 6 of `0xff /6`), without REX.R
+    fn modrm(&mut self, size: Size) -> Result<(u8, Argument), InterpError> {
+        let modrm = self.byte()?;
+        let (md, reg, rm) = (modrm >> 6, (modrm >> 3) & 7, modrm & 7);
+
+        if md == 3 {
+            return Ok((reg, self.register(rm | self.rex_bit(1), size)));
+        }
+
+        let addr_size = if self.mode == Mode::Bits64 && !self.address32 { Size::Quad } else { Size::Long };
+        let (mut base, mut idx, mut scale) = (None, None, 1);
+        let mut disp_len = match md {
+            1 => 1,
+            2 => 4,
+            _ => 0
+        };
+
+        match rm {
+            // A SIB byte follows
+            4 => {
+                let sib = self.byte()?;
+                let (ss, index, sib_base) = (sib >> 6, ((sib >> 3) & 7) | self.rex_bit(2), sib & 7);
+                if index != 4 {
+                    idx = Some(self.register(index, addr_size));
+                    scale = 1 << ss;
+                }
+
+                if sib_base == 5 && md == 0 {
+                    disp_len = 4;
+                } else {
+                    base = Some(self.register(sib_base | self.rex_bit(1), addr_size));
+                }
+            },
+            // An absolute address in 32bit mode, `%rip` relative in 64bit mode
+            5 if md == 0 => {
+                disp_len = 4;
+                if self.mode == Mode::Bits64 {
+                    let ip = if self.address32 { "eip" } else { "rip" };
+                    base = Some(Argument::Reg(ip.to_owned()));
+                }
+            },
+            _ => base = Some(self.register(rm | self.rex_bit(1), addr_size))
+        }
+
+        let disp = self.value(disp_len)? as i32;
+        Ok((reg, Argument::Mem(base.map(Box::new), idx.map(Box::new), scale, disp, None)))
+    }
+
+    // The address operand of the `0xa0`-`0xa3` forms of `mov`
+    fn absolute_address(&mut self) -> Result<Argument, InterpError> {
+        let len = if self.mode == Mode::Bits64 && !self.address32 { 8 } else { 4 };
+        let addr = self.value(len)?;
+        if addr != addr as i32 as i64 && len == 8 {
+            return Err(self.unsupported("64bit absolute addresses"));
+        }
+        Ok(Argument::Mem(None, None, 1, addr as i32, None))
+    }
+
+    // Immediates are at most 4 bytes (8 byte operands take a sign extended 4 byte immediate)
+    fn immediate(&mut self, size: Size) -> Result<Argument, InterpError> {
+        let len = match size {
+            Size::Quad => 4,
+            _ => size.bytes()
+        };
+        Ok(Argument::Literal(self.value(len)?))
+    }
+
+    // Target of a relative jump (relative to the end of the instruction)
+    fn relative(&mut self, size: Size) -> Result<u64, InterpError> {
+        let rel = self.value(size.bytes())?;
+        let target = (self.addr + self.pos as u64).wrapping_add(rel as u64);
+        match self.mode {
+            Mode::Bits32 => Ok(Size::Long.truncate(target as i64)),
+            Mode::Bits64 => Ok(target)
+        }
+    }
+
+
+    // Raw bytes
+    // The opcode extension in the upcoming ModR/M byte
+    fn extension(&self) -> Result<u8, InterpError> {
+        match self.bytes.get(self.pos) {
+            Some(&byt) => Ok((byt >> 3) & 7),
+            None => Err(InterpError::MemoryFault(self.addr.wrapping_add(self.pos as u64) as i64, 1))
+        }
+    }
+
+    fn byte(&mut self) -> Result<u8, InterpError> {
+        match self.bytes.get(self.pos) {
+            Some(&byt) => {
+                self.pos += 1;
+                Ok(byt)
+            },
+            None if self.pos >= MAX_LENGTH => Err(self.invalid()),
+            None => Err(InterpError::MemoryFault(self.addr.wrapping_add(self.pos as u64) as i64, 1))
+        }
+    }
+
+    // Little-endian value of `len` bytes (sign extended)
+    fn value(&mut self, len: usize) -> Result<i64, InterpError> {
+        let mut val = 0u64;
+        for byt in 0..len {
+            val |= (self.byte()? as u64) << (8 * byt);
+        }
+        Ok(match Size::from_bytes(len) {
+            Some(size) => size.sign_extend(val),
+            None => 0
+        })
+    }
+
+    fn encoding(&self) -> String {
+        self.bytes[..self.pos].iter().map(|byt| format!("{:02x}", byt)).collect::<Vec<_>>().join(" ")
+    }
+
+    fn invalid(&self) -> InterpError {
+        InterpError::Unimplemented(format!("or invalid instruction `{}` at {:#x}", self.encoding(), self.addr))
+    }
+
+    fn unsupported(&self, what: &str) -> InterpError {
+        InterpError::Unimplemented(format!("{} (instruction `{}` at {:#x})", what, self.encoding(), self.addr))
+    }
+}
+
+fn two(mne: &str, size: Size, src: Argument, dest: Argument) -> Command {
+    Command::TwoArg(mne.to_owned(), Some(size), src, dest)
+}
+
+fn jump(mne: &str, target: u64) -> Command {
+    Command::OneArg(mne.to_owned(), None, Argument::Target(target))
+}
+
+// Name of register `num` (0-15) at a width
+//   Without a REX prefix, byte registers 4-7 are `%ah`-`%bh` rather than `%spl`-`%dil`
+fn register_name(num: u8, size: Size, rex: bool) -> String {
+    const LEGACY: [&str; 8] = ["ax", "cx", "dx", "bx", "sp", "bp", "si", "di"];
+    let num = num as usize;
+
+    if num >= 8 {
+        let suffix = match size {
+            Size::Byte => "b",
+            Size::Word => "w",
+            Size::Long => "d",
+            Size::Quad => ""
+        };
+        return format!("r{}{}", num, suffix);
+    }
+
+    match size {
+        Size::Byte if num < 4 => format!("{}l", &LEGACY[num][..1]),
+        Size::Byte if !rex => format!("{}h", &LEGACY[num - 4][..1]),
+        Size::Byte => format!("{}l", LEGACY[num]),
+        Size::Word => LEGACY[num].to_owned(),
+        Size::Long => format!("e{}", LEGACY[num]),
+        Size::Quad => format!("r{}", LEGACY[num])
+    }
+}
@@ -0,0 +1,425 @@
+use emu::Emulator;
+use error::InterpError;
+use memory::{AddressSpace, Layout, PAGE_SIZE, round_to_page};
+use ximpl::Mode;
+
+// Load statically linked ELF executables (ie. `gcc -static -m32 -nostdlib`)
+//   The `PT_LOAD` segments are mapped at their addresses (as the `text`, `rodata` and `data`
+//   regions), followed by the heap, with the stack below the usual stack top of the layout
+
+// System V ABI: "Object Files" and "Program Loading", along with the i386/x86-64 supplements
+// https://refspecs.linuxfoundation.org/elf/gabi4+/contents.html
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+
+// `e_machine`
+const EM_386: u64 = 3;
+const EM_X86_64: u64 = 62;
+
+// `e_type`
+const ET_EXEC: u64 = 2;
+const ET_DYN: u64 = 3;
+
+// `p_type`
+const PT_LOAD: u64 = 1;
+const PT_DYNAMIC: u64 = 2;
+const PT_INTERP: u64 = 3;
+const PT_PHDR: u64 = 6;
+const PT_TLS: u64 = 7;
+
+// `p_flags`
+const PF_X: u64 = 1;
+const PF_W: u64 = 2;
+
+// Auxiliary vector entries
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+const AT_PLATFORM: u64 = 15;
+const AT_CLKTCK: u64 = 17;
+const AT_SECURE: u64 = 23;
+const AT_RANDOM: u64 = 25;
+const AT_EXECFN: u64 = 31;
+
+pub struct Executable {
+    pub layout: Layout,
+    pub entry: u64,
+    segments: Vec<Segment>,
+    // Where the program headers end up in memory (for `AT_PHDR`)
+    phdr: u64,
+    phent: u64,
+    phnum: u64
+}
+
+struct Segment {
+    addr: u64,
+    data: Vec<u8>,      // the file contents, the rest of the segment (up to `size`) is zeroed
+    size: u64,
+    flags: u64
+}
+
+// Read the headers and segments of an executable
+pub fn parse(image: &[u8]) -> Result<Executable, InterpError> {
+    if !image.starts_with(ELF_MAGIC) {
+        return Err(InterpError::InvalidOperand("Not an ELF file (missing the `\\x7fELF` magic)".to_owned()));
+    }
+
+    let file = File{ image, wide: field(image, 4, 1)? == 2 };
+    match field(image, 4, 1)? {
+        1 | 2 => (),
+        class => return Err(InterpError::InvalidOperand(format!("Unknown ELF class: {}", class)))
+    }
+    if field(image, 5, 1)? != 1 {
+        return Err(InterpError::InvalidOperand("Big-endian ELF files can't hold x86 code".to_owned()));
+    }
+
+    let (layout, machine) = if file.wide { (Layout::X86_64, EM_X86_64) } else { (Layout::I386, EM_386) };
+    match file.half(18)? {
+        m if m == machine => (),
+        EM_386 | EM_X86_64 => return Err(InterpError::InvalidOperand(
+            format!("The ELF{} file holds {} code", if file.wide { 64 } else { 32 }, if file.wide { "32bit" } else { "64bit" }))),
+        m => return Err(InterpError::InvalidOperand(format!("Not an x86 executable (machine {})", m)))
+    }
+    match file.half(16)? {
+        ET_EXEC => (),
+        ET_DYN => return Err(InterpError::Unimplemented(
+            "position independent executables (link with `-static -no-pie`)".to_owned())),
+        kind => return Err(InterpError::InvalidOperand(format!("Not an executable (ELF type {})", kind)))
+    }
+
+    let entry = file.addr(24)?;
+    let (phoff, phent, phnum) = if file.wide {
+        (file.addr(32)?, file.half(54)?, file.half(56)?)
+    } else {
+        (file.addr(28)?, file.half(42)?, file.half(44)?)
+    };
+
+    // The whole program header table has to be in the file
+    let hdr_size = if file.wide { 56 } else { 32 };
+    if phnum > 0 && phent < hdr_size {
+        return Err(InterpError::InvalidOperand(format!("Program headers of {} bytes are too small (at least {} bytes)", phent, hdr_size)));
+    }
+    match phnum.checked_mul(phent).and_then(|size| size.checked_add(phoff)) {
+        Some(end) if end <= image.len() as u64 => (),
+        _ => return Err(truncated())
+    }
+
+    let mut exe = Executable{ layout, entry, segments: Vec::new(), phdr: 0, phent, phnum };
+    for idx in 0..phnum {
+        let hdr = (phoff + idx * phent) as usize;
+        let (kind, flags, offset, addr, filesz, memsz) = if file.wide {
+            (file.word(hdr)?, file.word(hdr + 4)?, file.addr(hdr + 8)?, file.addr(hdr + 16)?, file.addr(hdr + 32)?, file.addr(hdr + 40)?)
+        } else {
+            (file.word(hdr)?, file.word(hdr + 24)?, file.addr(hdr + 4)?, file.addr(hdr + 8)?, file.addr(hdr + 16)?, file.addr(hdr + 20)?)
+        };
+
+        match kind {
+            PT_LOAD => {
+                if filesz > memsz {
+                    return Err(InterpError::InvalidOperand(format!("Segment at {:#x} is larger in the file than in memory", addr)));
+                }
+                let data = segment_data(image, offset, filesz)?;
+
+                // The program headers are usually loaded along with the first segment
+                if exe.phdr == 0 && offset <= phoff && phoff - offset < filesz {
+                    exe.phdr = addr.checked_add(phoff - offset).ok_or_else(|| InterpError::InvalidOperand(
+                        format!("Segment at {:#x} wraps around the address space", addr)))?;
+                }
+                exe.segments.push(Segment{ addr, data: data.to_vec(), size: memsz, flags });
+            },
+            PT_PHDR => exe.phdr = addr,
+            PT_INTERP => {
+                let interp = segment_data(image, offset, filesz)?;
+                let interp = String::from_utf8_lossy(interp).trim_end_matches('\0').to_string();
+                return Err(InterpError::Unimplemented(
+                    format!("dynamically linked executables (the program asks for `{}`, link with `-static`)", interp)));
+            },
+            PT_DYNAMIC => return Err(InterpError::Unimplemented(
+                "dynamically linked executables (the program has a `PT_DYNAMIC` segment, link with `-static`)".to_owned())),
+            PT_TLS => return Err(InterpError::Unimplemented(
+                "thread local storage (the program has a `PT_TLS` segment)".to_owned())),
+            // Notes, `PT_GNU_STACK`, `PT_GNU_RELRO`, ... don't need to be loaded
+            _ => ()
+        }
+    }
+
+    if exe.segments.is_empty() {
+        return Err(InterpError::InvalidOperand("The executable has no loadable segments".to_owned()));
+    }
+    Ok(exe)
+}
+
+// Lay out the address space of an executable (the segments still have to be copied in by `load`)
+//   Segments sharing a page are merged into a single region
+pub fn address_space(exe: &Executable, stack_size: u64) -> Result<AddressSpace, InterpError> {
+    let mut space = AddressSpace::empty();
+    let mut segments = exe.segments.iter().collect::<Vec<_>>();
+    segments.sort_by_key(|seg| seg.addr);
+
+    let mut mapped = 0;
+    for seg in segments {
+        let start = (seg.addr / PAGE_SIZE * PAGE_SIZE).max(mapped);
+        let end = seg.addr.checked_add(seg.size).map(round_to_page)
+            .ok_or_else(|| InterpError::InvalidOperand(format!("Segment at {:#x} wraps around the address space", seg.addr)))?;
+        if end <= start {
+            continue;
+        }
+
+        space.map(&region_name(&space, seg.flags), start, end - start)?;
+        mapped = end;
+    }
+
+    space.map_heap()?;
+    space.map_stack(exe.layout, stack_size)?;
+    Ok(space)
+}
+
+// Copy the segments into memory and build the initial stack
+//   Returns the initial stack pointer
+pub fn load(exe: &Executable, args: &[String], env: &[String], emu: &mut Emulator) -> Result<u64, InterpError> {
+    for seg in exe.segments.iter() {
+        emu.writeBytes(seg.addr, &seg.data)?;
+    }
+    initial_stack(exe, args, env, emu)
+}
+
+// The process stack at the entry point, from the top:
+//   the argument/environment strings (and the `AT_RANDOM` bytes), then 16 byte aligned
+//   argc, argv[], NULL, envp[], NULL and the auxiliary vector (`%esp`/`%rsp` points at argc)
+fn initial_stack(exe: &Executable, args: &[String], env: &[String], emu: &mut Emulator) -> Result<u64, InterpError> {
+    let top = emu.getRegion("stack").map(|stack| stack.end).unwrap_or(0);
+    let mut sp = top;
+    let mut push_bytes = |emu: &mut Emulator, bytes: &[u8]| {
+        sp -= bytes.len() as u64;
+        emu.writeBytes(sp, bytes).map(|_| sp)
+    };
+
+    // No real randomness (runs are reproducible)
+    let random = push_bytes(emu, &[0x5a; 16])?;
+    let platform = match exe.layout.mode() {
+        Mode::Bits32 => push_bytes(emu, b"i686\0")?,
+        Mode::Bits64 => push_bytes(emu, b"x86_64\0")?
+    };
+    let mut strings = |emu: &mut Emulator, strs: &[String]| -> Result<Vec<u64>, InterpError> {
+        strs.iter().map(|s| push_bytes(emu, format!("{}\0", s).as_bytes())).collect()
+    };
+    let env_ptrs = strings(emu, env)?;
+    let arg_ptrs = strings(emu, args)?;
+
+    let mut words = vec![args.len() as u64];
+    words.extend(arg_ptrs.iter().cloned());
+    words.push(0);
+    words.extend(env_ptrs.iter().cloned());
+    words.push(0);
+
+    let aux = [(AT_PHDR, exe.phdr), (AT_PHENT, exe.phent), (AT_PHNUM, exe.phnum), (AT_PAGESZ, PAGE_SIZE),
+               (AT_ENTRY, exe.entry), (AT_CLKTCK, 100), (AT_SECURE, 0), (AT_RANDOM, random),
+               (AT_PLATFORM, platform), (AT_EXECFN, arg_ptrs.first().cloned().unwrap_or(0)), (AT_NULL, 0)];
+    for &(key, val) in aux.iter() {
+        words.push(key);
+        words.push(val);
+    }
+
+    let size = exe.layout.mode().stack_size();
+    let start = (sp - (words.len() * size.bytes()) as u64) & !15;
+    for (idx, &word) in words.iter().enumerate() {
+        emu.getMemory((start + (idx * size.bytes()) as u64) as i64, size)?.set(word as i64)?;
+    }
+    Ok(start)
+}
+
+// Regions are named after what they hold (repeats get a number, ie. `rodata.1`)
+fn region_name(space: &AddressSpace, flags: u64) -> String {
+    let base = if flags & PF_X != 0 {
+        "text"
+    } else if flags & PF_W != 0 {
+        "data"
+    } else {
+        "rodata"
+    };
+
+    let count = space.regions().iter().filter(|r| r.name == base || r.name.starts_with(&format!("{}.", base))).count();
+    match count {
+        0 => base.to_string(),
+        n => format!("{}.{}", base, n)
+    }
+}
+
+// Little-endian fields of the file
+struct File<'a> {
+    image: &'a [u8],
+    wide: bool          // ELF64 (addresses and offsets are 8 bytes)
+}
+
+impl<'a> File<'a> {
+    fn half(&self, off: usize) -> Result<u64, InterpError> {
+        field(self.image, off, 2)
+    }
+
+    fn word(&self, off: usize) -> Result<u64, InterpError> {
+        field(self.image, off, 4)
+    }
+
+    fn addr(&self, off: usize) -> Result<u64, InterpError> {
+        field(self.image, off, if self.wide { 8 } else { 4 })
+    }
+}
+
+fn field(image: &[u8], off: usize, len: usize) -> Result<u64, InterpError> {
+    let bytes = off.checked_add(len).and_then(|end| image.get(off..end)).ok_or_else(truncated)?;
+    Ok(bytes.iter().rev().fold(0, |acc, &byt| (acc << 8) | byt as u64))
+}
+
+// The contents of a segment in the file
+fn segment_data(image: &[u8], offset: u64, filesz: u64) -> Result<&[u8], InterpError> {
+    offset.checked_add(filesz).and_then(|end| image.get(offset as usize..end as usize)).ok_or_else(truncated)
+}
+
+fn truncated() -> InterpError {
+    InterpError::InvalidOperand("The ELF file is truncated".to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use emu::Emulator;
+    use host::NullIo;
+    use super::*;
+
+    // `mov $1, %eax; mov $42, %ebx; int $0x80` (exit with status 42)
+    const EXIT_42: &[u8] = &[0xb8, 0x01, 0, 0, 0, 0xbb, 0x2a, 0, 0, 0, 0xcd, 0x80];
+
+    fn put(image: &mut [u8], off: usize, len: usize, val: u64) {
+        for idx in 0..len {
+            image[off + idx] = (val >> (8 * idx)) as u8;
+        }
+    }
+
+    // An ELF32 executable with a single segment holding the headers and the code
+    fn image32() -> Vec<u8> {
+        let mut image = vec![0; 84];
+        image[..7].copy_from_slice(b"\x7fELF\x01\x01\x01");
+        put(&mut image, 16, 2, ET_EXEC);
+        put(&mut image, 18, 2, EM_386);
+        put(&mut image, 24, 4, 0x0804_8000 + 84);
+        put(&mut image, 28, 4, 52);
+        put(&mut image, 42, 2, 32);
+        put(&mut image, 44, 2, 1);
+
+        let size = 84 + EXIT_42.len() as u64;
+        put(&mut image, 52, 4, PT_LOAD);
+        put(&mut image, 60, 4, 0x0804_8000);
+        put(&mut image, 68, 4, size);
+        put(&mut image, 72, 4, size);
+        put(&mut image, 76, 4, PF_X | 4);
+        image.extend_from_slice(EXIT_42);
+        image
+    }
+
+    // An ELF64 header without any program headers
+    fn header64() -> Vec<u8> {
+        let mut image = vec![0; 64];
+        image[..7].copy_from_slice(b"\x7fELF\x02\x01\x01");
+        put(&mut image, 16, 2, ET_EXEC);
+        put(&mut image, 18, 2, EM_X86_64);
+        put(&mut image, 24, 8, 0x40_0000);
+        put(&mut image, 32, 8, 64);
+        put(&mut image, 54, 2, 56);
+        image
+    }
+
+    fn error(image: &[u8]) -> String {
+        match parse(image) {
+            Ok(_) => panic!("The image was accepted"),
+            Err(e) => e.to_string()
+        }
+    }
+
+    #[test]
+    fn loads_a_valid_image() {
+        let mut emu = Emulator::new();
+        emu.setIo(Box::new(NullIo));
+        emu.loadElf(&image32(), &["prog".to_owned()], &[]).unwrap();
+        assert_eq!(emu.getPC(), 0x0804_8000 + 84);
+        emu.execute().unwrap();
+        assert_eq!(emu.getExitStatus(), 42);
+    }
+
+    #[test]
+    fn rejects_bad_identification() {
+        assert!(error(b"#!/bin/sh\n").contains("Not an ELF file"));
+        assert!(error(b"\x7fELF").contains("truncated"));
+
+        let mut image = image32();
+        image[4] = 3;
+        assert!(error(&image).contains("Unknown ELF class"));
+
+        let mut image = image32();
+        image[5] = 2;
+        assert!(error(&image).contains("Big-endian"));
+    }
+
+    #[test]
+    fn rejects_other_machines_and_types() {
+        let mut image = image32();
+        put(&mut image, 18, 2, EM_X86_64);
+        assert!(error(&image).contains("holds 64bit code"));
+
+        let mut image = image32();
+        put(&mut image, 18, 2, 40);
+        assert!(error(&image).contains("Not an x86 executable"));
+
+        let mut image = image32();
+        put(&mut image, 16, 2, ET_DYN);
+        assert!(error(&image).contains("position independent"));
+
+        let mut image = image32();
+        put(&mut image, 16, 2, 1);
+        assert!(error(&image).contains("Not an executable"));
+    }
+
+    #[test]
+    fn rejects_bad_program_headers() {
+        let mut image = image32();
+        put(&mut image, 42, 2, 16);
+        assert!(error(&image).contains("too small"));
+
+        // The program header table is past the end of the file (or of the address space)
+        let mut image = image32();
+        put(&mut image, 28, 4, 0xffff_fff0);
+        assert!(error(&image).contains("truncated"));
+
+        let mut image = header64();
+        put(&mut image, 32, 8, u64::MAX - 8);
+        put(&mut image, 56, 2, 2);
+        assert!(error(&image).contains("truncated"));
+
+        assert!(error(&header64()).contains("no loadable segments"));
+    }
+
+    #[test]
+    fn rejects_bad_segments() {
+        let mut image = image32();
+        put(&mut image, 72, 4, 8);
+        assert!(error(&image).contains("larger in the file than in memory"));
+
+        let mut image = image32();
+        put(&mut image, 56, 4, 0xffff_fff0);
+        assert!(error(&image).contains("truncated"));
+
+        let mut image = header64();
+        image.resize(64 + 56, 0);
+        put(&mut image, 56, 2, 1);
+        put(&mut image, 64, 4, PT_LOAD);
+        put(&mut image, 72, 8, u64::MAX);
+        put(&mut image, 96, 8, 2);
+        put(&mut image, 104, 8, 2);
+        assert!(error(&image).contains("truncated"));
+
+        let mut image = image32();
+        put(&mut image, 52, 4, PT_INTERP);
+        assert!(error(&image).contains("dynamically linked"));
+    }
+}
@@ -1,110 +1,370 @@
 
-use std::mem;
-use std::collections::HashMap;
-use bit_vec::BitVec;
+use std::collections::{BTreeSet, HashMap};
 use ximpl;
+use ximpl::{Code, Command, Mode, Size, Syntax};
 use view::Memory;
+use memory::{AddressSpace, Layout, Region, DEFAULT_STACK_SIZE};
+use flags;
+use error::InterpError;
+use inter;
+use data;
+use encode;
+use decode;
+use decode::Disassembly;
+use elf;
+use host::{GuestIo, HostIo, Stream};
+use x86;
 
 // #[disable(non_snake_case)]
 
-// 1024 * 1024 overflows the stack
-// const MEM_SIZE: usize = 1024 * 1024;
-const MEM_SIZE: usize = 1024;
+impl Default for Emulator {
+    fn default() -> Emulator {
+        Emulator::new()
+    }
+}
 
 // TODO: Look at abstracting this organization to accomodate different architectures
 #[allow(non_snake_case)]
 impl Emulator {
     pub fn new() -> Emulator {
-        unsafe {
-            Emulator{
-                eax: mem::transmute(1 as u32),
-                ecx: mem::transmute(4200656 as u32),
-                edx: [0;4],
-                ebx: mem::transmute(2138112 as u32),
-                esp: mem::transmute(MEM_SIZE as u32 - 4),
-                ebp: mem::transmute(MEM_SIZE as u32 - 4),
-                esi: mem::transmute(4199136 as u32),
-                edi: mem::transmute(4199136 as u32),
-                eflags: BitVec::with_capacity(32),
-
-                pc: 0,
-                mem: [0 as u8; MEM_SIZE],
-                jumps: HashMap::new(),
-                exit_flag: false,
+        Emulator::with_stack_size(DEFAULT_STACK_SIZE)
+    }
+
+    pub fn with_stack_size(stack_size: u64) -> Emulator {
+        Emulator::with_layout(Layout::I386, stack_size)
+    }
+
+    // Create an emulator whose memory is laid out like a Linux process (see `memory.rs`)
+    pub fn with_layout(layout: Layout, stack_size: u64) -> Emulator {
+        let mut emu = Emulator{
+            regs: [[0;8]; 17],
+            // Bit 1 is reserved (always set) and interrupts start out enabled
+            eflags: 0x202,
+            lazy_flags: None,
+
+            layout,
+            mem: AddressSpace::new(layout, stack_size),
+            jumps: HashMap::new(),
+            symbols: HashMap::new(),
+            io: Box::new(HostIo),
+            exit_flag: false,
+            exit_status: 0,
+            brk: None,
+            breakpoints: BTreeSet::new(),
+
+            code: Vec::new(),
+            addrs: Vec::new(),
+            source: Vec::new(),
+        };
+
+        // Initialize the registers with some "garbage" values
+        let top = emu.mem.region("stack").unwrap().end as i64 - 4;
+        let init: [(&str, i64); 8] = [
+            ("eax", 1), ("ecx", 4200656), ("edx", 0), ("ebx", 2138112),
+            ("rsp", top), ("rbp", top),
+            ("esi", 4199136), ("edi", 4199136)
+        ];
+        for &(reg, val) in init.iter() {
+            emu.getReg(reg).and_then(|mut r| r.set(val)).unwrap();
+        }
+
+        emu
+    }
+
+    // Load a new program (registers are left as they are, the data sections are rewritten)
+    //   The program is assembled into the text region, and any error stops it from running
+    pub fn load<I: Iterator<Item=String>>(&mut self, code_iter: I, syntax: Syntax) -> Result<(), InterpError> {
+        self.unload();
+        self.source = code_iter.collect();
+
+        let res = self.assemble(syntax);
+        if res.is_err() {
+            self.exit();
+        }
+        res
+    }
+
+    // Load raw machine code at the start of the text region (registers are left as they are)
+    //   The program stops once it runs off the end of the code
+    pub fn loadBytes(&mut self, bytes: &[u8]) -> Result<(), InterpError> {
+        self.unload();
+
+        let text = self.getRegion("text").cloned().unwrap();
+        self.setPC(text.start);
+        if bytes.len() as u64 > text.size() {
+            self.exit();
+            return Err(InterpError::InvalidOperand(
+                format!("The program is larger than its {} KiB text region", text.size() / 1024)));
+        }
+
+        self.writeBytes(text.start, bytes)?;
+        self.code = vec![Code::EndProgram];
+        self.addrs = vec![text.start + bytes.len() as u64];
+        Ok(())
+    }
+
+    // Load a statically linked ELF executable (`args` includes the program name as `argv[0]`)
+    //   The executable replaces the memory layout (its class picks 32 or 64bit mode) and
+    //   starts at its entry point with the registers cleared, as on Linux
+    pub fn loadElf(&mut self, image: &[u8], args: &[String], env: &[String]) -> Result<(), InterpError> {
+        self.unload();
+
+        let res = self.startElf(image, args, env);
+        if res.is_err() {
+            self.exit();
+        }
+        res
+    }
+
+    fn startElf(&mut self, image: &[u8], args: &[String], env: &[String]) -> Result<(), InterpError> {
+        let exe = elf::parse(image)?;
+        let stack_size = self.getRegion("stack").map(|stack| stack.size()).unwrap_or(DEFAULT_STACK_SIZE);
+        self.mem = elf::address_space(&exe, stack_size)?;
+        self.layout = exe.layout;
+
+        self.regs = [[0;8]; 17];
+        self.eflags = 0x202;
+        self.lazy_flags = None;
+
+        let sp = elf::load(&exe, args, env, self)?;
+        self.getReg("rsp")?.set(sp as i64)?;
+        self.setPC(exe.entry);
+        Ok(())
+    }
+
+    // Forget the current program
+    fn unload(&mut self) {
+        self.source.clear();
+        self.code.clear();
+        self.addrs.clear();
+        self.jumps.clear();
+        self.symbols.clear();
+        self.exit_flag = false;
+        self.exit_status = 0;
+        self.brk = None;
+        self.breakpoints.clear();
+    }
+
+    fn assemble(&mut self, syntax: Syntax) -> Result<(), InterpError> {
+        let text = self.getRegion("text").cloned().unwrap();
+        self.setPC(text.start);
+
+        let mut code = x86::first_parse(&self.source, syntax);
+        let unresolved = data::assemble(&mut code, self)?;
+        inter::collect_labels(&mut code, self);
+        x86::parse_code(&mut code)?;
+
+        // Instructions are placed from the start of the text region
+        self.addrs = x86::code_addresses(&code, text.start, self.getMode(), &self.source)?;
+        if self.addrs.last().is_some_and(|&end| end > text.end) {
+            return Err(InterpError::InvalidOperand(
+                format!("The program is larger than its {} KiB text region", text.size() / 1024)));
+        }
+        self.code = code;
+
+        // Every label has an address now, so the instructions can be encoded
+        data::link(unresolved, self)?;
+        let image = self.encodeCode()?;
+        self.writeBytes(text.start, &image)?;
+
+        // Like a linked executable, the program starts at `_start` if there is one
+        if let Some(start) = self.getLabel("_start") {
+            self.setPC(start);
+        }
+        Ok(())
+    }
+
+    fn encodeCode(&self) -> Result<Vec<u8>, InterpError> {
+        let resolve = |lbl: &str| self.getSymbol(lbl).or_else(|| self.getLabel(lbl));
+        let mut image = Vec::new();
+
+        for (entry, &addr) in self.code.iter().zip(self.addrs.iter()) {
+            if let Code::Parsed(ref inst, line) = *entry {
+                let bytes = encode::encode(inst, addr, self.getMode(), &resolve).map_err(|e| e.at(line + 1, &self.source[line]))?;
+                image.extend(bytes);
             }
         }
+        Ok(image)
+    }
+
+    // Execute the next instruction of the program (decoded from the memory at `%rip`)
+    //   Returns whether the program is still running (faults also stop the program)
+    pub fn step(&mut self) -> Result<bool, InterpError> {
+        if !self.run() {
+            return Ok(false);
+        }
+
+        // Running off the end of the program stops it
+        let pc = self.getPC();
+        if self.addrs.last() == Some(&pc) {
+            self.exit();
+            return Ok(false);
+        }
+
+        let bytes = self.fetchBytes(pc);
+        let res = decode::decode(&bytes, pc, self.getMode()).and_then(|(inst, len)| {
+            // `%rip` holds the address of the next instruction while this one executes
+            self.setPC(pc.wrapping_add(len as u64));
+            inter::dispatch(&inst, self)
+        });
+
+        if let Err(e) = res {
+            self.exit();
+            return Err(self.locate(e, pc));
+        }
+        Ok(self.run())
+    }
+
+    // Run the program until it exits or faults
+    pub fn execute(&mut self) -> Result<(), InterpError> {
+        while self.step()? {}
+        Ok(())
+    }
+
+    // Debugger style execution: every run stops early at a breakpoint (or when the program stops),
+    //   the instruction at `%rip` when a run starts never counts as a breakpoint
+    //
+    // Execute a single instruction
+    pub fn stepInstruction(&mut self) -> Result<StopReason, InterpError> {
+        self.runUntil(|_| true)
+    }
+    // Execute a single instruction, running a `call` through to its return
+    pub fn stepOver(&mut self) -> Result<StopReason, InterpError> {
+        match self.nextInstruction() {
+            Some((Command::OneArg(ref mne, ..), len)) if mne == "call" => {
+                // Recursive calls come back to the same address deeper in the stack
+                let (ret, sp) = (self.getPC().wrapping_add(len as u64), self.getSP());
+                self.runUntil(|emu| emu.getPC() == ret && emu.getSP() >= sp)
+            },
+            _ => self.stepInstruction()
+        }
+    }
+    // Run until the current function returns (a `ret` with the stack no deeper than it is now)
+    pub fn stepOut(&mut self) -> Result<StopReason, InterpError> {
+        let sp = self.getSP();
+        loop {
+            let leaving = match self.nextInstruction() {
+                Some((Command::NoArg(ref mne, _), _)) | Some((Command::OneArg(ref mne, ..), _)) => mne == "ret" && self.getSP() >= sp,
+                _ => false
+            };
+
+            match self.stepInstruction()? {
+                StopReason::Stepped if !leaving => (),
+                stop => return Ok(stop)
+            }
+        }
+    }
+    // Run until a breakpoint is reached (or the program stops)
+    pub fn resume(&mut self) -> Result<StopReason, InterpError> {
+        self.runUntil(|_| false)
+    }
+
+    fn runUntil<F: FnMut(&Emulator) -> bool>(&mut self, mut done: F) -> Result<StopReason, InterpError> {
+        loop {
+            if !self.step()? {
+                return Ok(StopReason::Exited);
+            }
+
+            let pc = self.getPC();
+            if self.breakpoints.contains(&pc) {
+                return Ok(StopReason::Breakpoint(pc));
+            }
+            if done(self) {
+                return Ok(StopReason::Stepped);
+            }
+        }
+    }
+
+    // The instruction at `%rip` (and its length), if there is one
+    fn nextInstruction(&mut self) -> Option<(Command, usize)> {
+        let pc = self.getPC();
+        if !self.run() || self.addrs.last() == Some(&pc) {
+            return None;
+        }
+        decode::decode(&self.fetchBytes(pc), pc, self.getMode()).ok()
+    }
+
+    // Breakpoints are kept by address (they're cleared when a new program is loaded)
+    pub fn addBreakpoint(&mut self, addr: u64) -> bool {
+        self.breakpoints.insert(addr)
+    }
+    pub fn removeBreakpoint(&mut self, addr: u64) -> bool {
+        self.breakpoints.remove(&addr)
+    }
+    pub fn getBreakpoints(&self) -> Vec<u64> {
+        self.breakpoints.iter().cloned().collect()
     }
 
     // TODO: Look into changing the interface (switch String with Argument)
-    pub fn getReg(&mut self, reg: &str) -> Result<Memory, String> {
-        let reg = match reg {
-            // Access the 32bit Memorys
-            "eax" => Ok(&mut self.eax[0..4]),
-            "ecx" => Ok(&mut self.ecx[0..4]),
-            "edx" => Ok(&mut self.edx[0..4]),
-            "ebx" => Ok(&mut self.ebx[0..4]),
-            "esp" => Ok(&mut self.esp[0..4]),
-            "ebp" => Ok(&mut self.ebp[0..4]),
-            "esi" => Ok(&mut self.esi[0..4]),
-            "edi" => Ok(&mut self.edi[0..4]),
-            // There's apparently more than this
-            // rXXd, rXXw, rXXb
-
-            // Access the 16bit Memorys
-            "ax" => Ok(&mut self.eax[0..2]),
-            "cx" => Ok(&mut self.ecx[0..2]),
-            "dx" => Ok(&mut self.edx[0..2]),
-            "bx" => Ok(&mut self.ebx[0..2]),
-            "si" => Ok(&mut self.esi[0..2]),
-            "di" => Ok(&mut self.edi[0..2]),
-            "sp" => Ok(&mut self.esp[0..2]),
-            "bp" => Ok(&mut self.ebp[0..2]),
-
-            // Access the 8bit Memorys
-            "ah" => Ok(&mut self.eax[1..2]),
-            "al" => Ok(&mut self.eax[0..1]),
-            "ch" => Ok(&mut self.ecx[1..2]),
-            "cl" => Ok(&mut self.ecx[0..1]),
-            "dh" => Ok(&mut self.edx[1..2]),
-            "dl" => Ok(&mut self.edx[0..1]),
-            "bh" => Ok(&mut self.ebx[1..2]),
-            "bl" => Ok(&mut self.ebx[0..1]),
-            "sih" => Ok(&mut self.esi[1..2]),
-            "sil" => Ok(&mut self.esi[0..1]),
-            "dih" => Ok(&mut self.edi[1..2]),
-            "dil" => Ok(&mut self.edi[0..1]),
-            "sph" => Ok(&mut self.esp[1..2]),
-            "spl" => Ok(&mut self.esp[0..1]),
-            "bph" => Ok(&mut self.ebp[1..2]),
-            "bpl" => Ok(&mut self.ebp[0..1]),
-
-            _ => Err("Attempt to use unsupported registers".to_owned())
-        };
+    pub fn getReg(&mut self, reg: &str) -> Result<Memory<'_>, InterpError> {
+        match ximpl::reg_location(reg) {
+            // Writing to a 32bit register clears the upper half of the 64bit register
+            Some((idx, 0, 4)) => {
+                let (reg, ext) = self.regs[idx].split_at_mut(4);
+                Ok(Memory::zero_extended(reg, ext))
+            },
+            Some((idx, off, len)) => Ok(Memory::new(&mut self.regs[idx][off..off+len])),
+            None => Err(InterpError::UnknownRegister(reg.to_string()))
+        }
+    }
 
-        match reg {
-            Ok(reg) => Ok(Memory::new(&mut self.eflags, reg)),
-            Err(e) => Err(e)
+    // 'getReg' type functions that work on the address space
+    pub fn getMemory(&mut self, loc: i64, size: Size) -> Result<Memory<'_>, InterpError> {
+        self.getMemorySized(loc, size.bytes())
+    }
+    pub fn getMemorySized(&mut self, loc: i64, len: usize) -> Result<Memory<'_>, InterpError> {
+        if Size::from_bytes(len).is_none() {
+            return Err(InterpError::InvalidOperand(format!("Memory can't be accessed {} bytes at a time", len)));
         }
+
+        // Accesses have to fall entirely within mapped regions
+        self.mem.access(loc, len)
     }
 
-    // 'getReg' type functions that work on the memory tape
-    pub fn getMemory(&mut self, loc: i32) -> Memory {
-        self.getMemorySized(loc, 4)
+    // Query the memory layout
+    pub fn getRegions(&self) -> &[Region] {
+        self.mem.regions()
     }
-    pub fn getMemorySized(&mut self, loc: i32, len: i32) -> Memory {
-        let (loc, end) = min_max(loc as usize, (loc + len) as usize);
-        Memory::new(&mut self.eflags, &mut self.mem[loc..end])
+    pub fn getRegion(&self, name: &str) -> Option<&Region> {
+        self.mem.region(name)
     }
 
     // Look at and modify cpu flags
     pub fn getFlag(&self, flag: ximpl::Flag) -> bool {
-        self.eflags.get(ximpl::mask_shift(flag)).unwrap_or(false)
+        match self.lazy_flags.and_then(|lazy| lazy.get(flag)) {
+            Some(val) => val,
+            None => self.eflags & (1 << ximpl::mask_shift(flag)) != 0
+        }
     }
     pub fn setFlag(&mut self, flag: ximpl::Flag, val: bool) {
-        self.eflags.set(ximpl::mask_shift(flag), val)
+        self.syncFlags();
+
+        let mask = 1 << ximpl::mask_shift(flag);
+        if val {
+            self.eflags |= mask;
+        } else {
+            self.eflags &= !mask;
+        }
+    }
+    pub fn getEflags(&self) -> u32 {
+        flags::ARITHMETIC_FLAGS.iter().fold(self.eflags, |eflags, &flag| {
+            let mask = 1 << ximpl::mask_shift(flag);
+            if self.getFlag(flag) { eflags | mask } else { eflags & !mask }
+        })
     }
-    pub fn clearFlags(&mut self) {
-        self.eflags.clear()
+
+    // Remember the last flag-setting operation (the flags are computed when read)
+    pub fn recordFlags(&mut self, lazy: flags::Lazy) {
+        // Operations that leave some flags unchanged need the old values written out
+        if flags::ARITHMETIC_FLAGS.iter().any(|&flag| lazy.get(flag).is_none()) {
+            self.syncFlags();
+        }
+
+        self.lazy_flags = Some(lazy);
+    }
+    fn syncFlags(&mut self) {
+        self.eflags = self.getEflags();
+        self.lazy_flags = None;
     }
 
     
@@ -115,87 +375,356 @@ impl Emulator {
     pub fn run(&self) -> bool {
         !self.exit_flag
     }
+    // Exit with a status for the host (ie. the `exit` system call)
+    pub fn exitWith(&mut self, status: i32) {
+        self.exit_status = status;
+        self.exit();
+    }
+    pub fn getExitStatus(&self) -> i32 {
+        self.exit_status
+    }
+
+    // The program break (the end of the heap in use, see the `brk` system call)
+    //   It starts out at the start of the heap region
+    pub fn getBreak(&self) -> u64 {
+        self.brk.or_else(|| self.getRegion("heap").map(|heap| heap.start)).unwrap_or(0)
+    }
+    pub fn setBreak(&mut self, brk: u64) {
+        self.brk = Some(brk);
+    }
+
+
+    // Copy bytes into memory
+    pub fn writeBytes(&mut self, addr: u64, bytes: &[u8]) -> Result<(), InterpError> {
+        for (off, &byt) in bytes.iter().enumerate() {
+            self.getMemory(addr.wrapping_add(off as u64) as i64, Size::Byte)?.set(byt)?;
+        }
+        Ok(())
+    }
+
+    // Copy bytes out of memory (the whole range has to be mapped)
+    pub fn readBytes(&mut self, addr: u64, len: u64) -> Result<Vec<u8>, InterpError> {
+        let mut bytes = Vec::new();
+        for off in 0..len {
+            bytes.push(self.getMemory(addr.wrapping_add(off) as i64, Size::Byte)?.get() as u8);
+        }
+        Ok(bytes)
+    }
+
+    // The bytes an instruction at `addr` could be made of (stopping at the end of mapped memory)
+    fn fetchBytes(&mut self, addr: u64) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(decode::MAX_LENGTH);
+        for off in 0..decode::MAX_LENGTH as u64 {
+            match self.getMemory(addr.wrapping_add(off) as i64, Size::Byte) {
+                Ok(byt) => bytes.push(byt.get() as u8),
+                Err(_) => break
+            }
+        }
+        bytes
+    }
+
+    // Decode the instructions starting within `[addr, addr + len)`
+    //   Bytes that aren't a (supported) instruction are listed one at a time as `(bad)`
+    pub fn disassemble(&mut self, addr: u64, len: u64) -> Result<Vec<Disassembly>, InterpError> {
+        let mut listing = Vec::new();
+        let mut cur = addr;
+
+        while cur < addr.saturating_add(len) {
+            let bytes = self.fetchBytes(cur);
+            let (text, size) = match decode::decode(&bytes, cur, self.getMode()) {
+                Ok((inst, size)) => (inst.to_string(), size),
+                Err(e @ InterpError::MemoryFault(..)) => return Err(e),
+                Err(_) => ("(bad)".to_owned(), 1)
+            };
+
+            listing.push(Disassembly{ addr: cur, bytes: bytes[..size].to_vec(), text });
+            cur += size as u64;
+        }
+        Ok(listing)
+    }
+
+    // Processor mode of the memory layout
+    pub fn getMode(&self) -> Mode {
+        self.layout.mode()
+    }
 
 
-    // Look at and modify the program counter
-    pub fn updatePC(&mut self) {
-        self.pc += 1;
+    // Look at and modify the program counter (`%rip`)
+    pub fn setPC(&mut self, pc: u64) {
+        self.regs[ximpl::RIP] = pc.to_le_bytes();
     }
-    pub fn setPC(&mut self, pc: usize) {
-        self.pc = pc;
+    pub fn getPC(&self) -> u64 {
+        u64::from_le_bytes(self.regs[ximpl::RIP])
     }
-    pub fn getPC(&self) -> usize {
-        self.pc
+    pub fn getSP(&self) -> u64 {
+        u64::from_le_bytes(self.regs[ximpl::RSP])
+    }
+
+    // Find the instruction at an address
+    //   Lines without code (ie. blank lines) share the address of the next instruction,
+    //   so they're skipped over
+    fn codeIndex(&self, addr: u64) -> Option<usize> {
+        let idx = self.addrs.partition_point(|&a| a <= addr);
+        match idx.checked_sub(1).map(|idx| (idx, self.addrs[idx])) {
+            Some((idx, a)) if a == addr => Some(idx),
+            _ => None
+        }
+    }
+    // Attach the source line of the instruction at an address to an error
+    fn locate(&self, err: InterpError, addr: u64) -> InterpError {
+        match self.getAddressLine(addr) {
+            Some(line) => err.at(line + 1, &self.source[line]),
+            None => err
+        }
+    }
+    // (0-based) source line of the instruction at an address
+    pub fn getAddressLine(&self, addr: u64) -> Option<usize> {
+        match self.codeIndex(addr).and_then(|idx| self.code.get(idx)) {
+            Some(&Code::Parsed(_, line)) => Some(line),
+            _ => None
+        }
+    }
+    // Bounds of the loaded code (it always starts at the start of the text region)
+    pub fn getCodeRange(&self) -> Option<(u64, u64)> {
+        let start = self.getRegion("text")?.start;
+        self.addrs.last().map(|&end| (start, end))
+    }
+    pub fn getAddress(&self, idx: usize) -> Option<u64> {
+        self.addrs.get(idx).cloned()
+    }
+    // Address of the first instruction at or after a (0-based) source line
+    pub fn getLineAddress(&self, line: usize) -> Option<u64> {
+        let idx = self.code.iter().position(|entry| match *entry {
+            Code::Parsed(_, l) | Code::Unread(_, _, l) => l >= line,
+            Code::EndProgram => true
+        });
+        idx.and_then(|idx| self.getAddress(idx))
+    }
+
+
+    // Original text of a (0-based) source line
+    pub fn getSource(&self, line: usize) -> Option<&str> {
+        self.source.get(line).map(|s| s.as_str())
     }
 
 
     // Work with assembly labels
+    //   Labels in the code are recorded by instruction index, those in data sections (symbols) by address
     pub fn addLabel(&mut self, lbl: &str, idx: usize) {
         self.jumps.entry(lbl.to_string()).or_insert(idx);
     }
-    pub fn gotoLabel(&mut self, lbl: &str) {
-        match self.jumps.get(lbl) {
-            Some(val) => self.pc = *val,
-            None => {
-                println!("{:?} isn't a valid label!", lbl);
-                // Ignore this instruction for now
-                self.pc += 1
-            }
+    pub fn getLabel(&self, lbl: &str) -> Option<u64> {
+        self.jumps.get(lbl).and_then(|&idx| self.getAddress(idx))
+    }
+    pub fn addSymbol(&mut self, lbl: &str, addr: u64) {
+        self.symbols.entry(lbl.to_string()).or_insert(addr);
+    }
+    pub fn getSymbol(&self, lbl: &str) -> Option<u64> {
+        self.symbols.get(lbl).cloned()
+    }
+    pub fn gotoLabel(&mut self, lbl: &str) -> Result<(), InterpError> {
+        match self.getLabel(lbl) {
+            Some(addr) => {
+                self.setPC(addr);
+                Ok(())
+            },
+            None => Err(InterpError::UndefinedLabel(lbl.to_string()))
         }
     }
 
 
+    // Swap out where the program's standard streams and the emulator's output go
+    //   (the host's streams by default)
+    pub fn setIo(&mut self, io: Box<dyn GuestIo>) {
+        self.io = io;
+    }
+    pub fn getIo(&mut self) -> &mut dyn GuestIo {
+        &mut *self.io
+    }
+    // Print the emulator's own output (ie. the dumps)
+    pub fn log(&mut self, text: &str) {
+        // There's nowhere to report a failing log, the program keeps running either way
+        let _ = self.io.write(Stream::Log, text.as_bytes());
+    }
+
+
     // Dump the internals of the Emulator
-    pub fn dumpRegisters(&self) {
-        println!("\n   ::: x86 Emulator Memory Dump :::");
-        unsafe {
-            // TODO: Look into switching bits to outputting binary instead
-            println!("  %eax: {1:>8}   byts: {0:?}", self.eax, mem::transmute::<[u8;4], u32>(self.eax));
-            println!("  %ecx: {1:>8}   byts: {0:?}", self.ecx, mem::transmute::<[u8;4], u32>(self.ecx));
-            println!("  %edx: {1:>8}   byts: {0:?}", self.edx, mem::transmute::<[u8;4], u32>(self.edx));
-            println!("  %ebx: {1:>8}   byts: {0:?}", self.ebx, mem::transmute::<[u8;4], u32>(self.ebx));
-            println!("  %esp: {1:>8}   byts: {0:?}", self.esp, mem::transmute::<[u8;4], u32>(self.esp));
-            println!("  %ebp: {1:>8}   byts: {0:?}", self.ebp, mem::transmute::<[u8;4], u32>(self.ebp));
-            println!("  %esi: {1:>8}   byts: {0:?}", self.esi, mem::transmute::<[u8;4], u32>(self.esi));
-            println!("  %edi: {1:>8}   byts: {0:?}", self.edi, mem::transmute::<[u8;4], u32>(self.edi));
+    pub fn dumpRegisters(&mut self) {
+        let mut out = String::from("\n   ::: x86 Emulator Memory Dump :::\n");
+        // TODO: Look into switching bits to outputting binary instead
+        for (name, reg) in ximpl::REGISTERS.iter().zip(self.regs.iter()) {
+            let val = reg.iter().rev().fold(0, |acc, &byt| (acc << 8) | byt as u64);
+            out += &format!("  %{0:<3}: {2:>20}   byts: {1:?}\n", name, reg, val);
         }
 
-        // println!("eflags: {0:>8}   bits: {0:b}", self.eflags);
-        println!("    pc: {0:>8}   bits: 0b{0:b}", self.pc);
+        out += &format!("eflags: {0:>20}   bits: 0b{0:b}\n", self.getEflags());
+        out += &format!("  %rip: {0:>20}   addr: {0:#x}\n", self.getPC());
+        self.log(&out);
     }
 
-    pub fn dumpLabels(&self) {
-        println!("\n   ::: x86 Emulator Label Dump :::");
+    pub fn dumpLabels(&mut self) {
+        let mut out = String::from("\n   ::: x86 Emulator Label Dump :::\n");
 
-        for (ref label, &val) in self.jumps.iter() {
-            println!("  {:<12} -> {}", label, val);
+        for label in self.jumps.keys() {
+            out += &format!("  {:<12} -> {:#x}\n", label, self.getLabel(label).unwrap_or(0));
+        }
+        for (ref label, &addr) in self.symbols.iter() {
+            out += &format!("  {:<12} -> {:#x}\n", label, addr);
         }
+        self.log(&out);
     }
 
-    pub fn dump_all(&self) {
+    pub fn dumpLayout(&mut self) {
+        let mut out = String::from("\n   ::: x86 Emulator Memory Layout :::\n");
+
+        for region in self.getRegions() {
+            out += &format!("  {:<8} {:#014x} - {:#014x}   {:>8} KiB   ({} KiB in use)\n", region.name,
+                            region.start, region.end, region.size() / 1024, self.mem.resident(region) / 1024);
+        }
+        self.log(&out);
+    }
+
+    pub fn dump_all(&mut self) {
         self.dumpRegisters();
         self.dumpLabels();
+        self.dumpLayout();
     }
 
     // TODO: Add in function to dump contents of used tape
 }
 
-fn min_max(loc: usize, end: usize) -> (usize, usize) {
-    if loc > end {
-        (end, loc)
-    } else {
-        (loc, end)
-    }
+// Why a debugger style run (ie. `Emulator::stepOver`) stopped
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StopReason {
+    Stepped,            // the step finished
+    Breakpoint(u64),    // a breakpoint at the address is next
+    Exited              // the program stopped (it exited or ran off the end of its code)
 }
 
 pub struct Emulator {
-    eax: [u8;4], ecx: [u8;4], esp: [u8;4], ebp: [u8;4],
-    edx: [u8;4], ebx: [u8;4], esi: [u8;4], edi: [u8;4],
-    eflags: BitVec,
+    // rax, rcx, rdx, rbx, rsp, rbp, rsi, rdi, r8-r15, rip (little-endian bytes)
+    regs: [[u8;8]; 17],
+    eflags: u32,
+    lazy_flags: Option<flags::Lazy>,
 
-    mem: [u8; MEM_SIZE],
+    layout: Layout,
+    mem: AddressSpace,
 
-    pc: usize,
+    io: Box<dyn GuestIo>,
     exit_flag: bool,
-    jumps: HashMap<String, usize>
-}
\ No newline at end of file
+    exit_status: i32,
+    brk: Option<u64>,
+    breakpoints: BTreeSet<u64>,
+    jumps: HashMap<String, usize>,
+    symbols: HashMap<String, u64>,
+
+    // The loaded program (`source` holds the original lines for error messages)
+    code: Vec<Code>,
+    addrs: Vec<u64>,            // address of each entry in `code`
+    source: Vec<String>
+}
+#[cfg(test)]
+mod tests {
+    use super::{Emulator, StopReason};
+    use host::NullIo;
+    use memory::{Layout, DEFAULT_STACK_SIZE};
+    use ximpl::Syntax;
+
+    // Loaded but not started, as the debugger sees a program
+    fn load(code: &str) -> Emulator {
+        let mut emu = Emulator::with_layout(Layout::I386, DEFAULT_STACK_SIZE);
+        emu.setIo(Box::new(NullIo));
+        emu.load(code.lines().map(|l| l.to_owned()), Syntax::ATT).unwrap();
+        emu
+    }
+
+    fn reg(emu: &mut Emulator, reg: &str) -> u64 {
+        emu.getReg(reg).unwrap().get_unsigned()
+    }
+
+    const FRAME: &str = "movl $0, %eax\njmp main\nf:\npushl %ebp\nmovl %esp, %ebp\nmovl $1, %eax\nmovl $2, %ecx\npopl %ebp\nret\n\
+                         main:\ncall f\nafter:\nmovl $7, %ebx";
+
+    #[test]
+    fn step_instruction() {
+        let mut emu = load(FRAME);
+        emu.stepInstruction().unwrap();
+        assert_eq!(emu.stepInstruction().unwrap(), StopReason::Stepped);
+        assert_eq!(emu.getPC(), emu.getLabel("main").unwrap());
+
+        // Steps into the call
+        let sp = emu.getSP();
+        assert_eq!(emu.stepInstruction().unwrap(), StopReason::Stepped);
+        assert_eq!(emu.getPC(), emu.getLabel("f").unwrap());
+        assert_eq!(emu.getSP(), sp - 4);
+    }
+
+    #[test]
+    fn breakpoint_in_a_loop() {
+        let mut emu = load("movl $0, %eax\nmovl $3, %ecx\ntop:\naddl $1, %eax\ndecl %ecx\njnz top\nmovl %eax, %ebx");
+        let top = emu.getLabel("top").unwrap();
+        assert!(emu.addBreakpoint(top));
+
+        // Stops before every iteration
+        for count in 0..3 {
+            assert_eq!(emu.resume().unwrap(), StopReason::Breakpoint(top));
+            assert_eq!(reg(&mut emu, "eax"), count);
+        }
+        assert_eq!(emu.resume().unwrap(), StopReason::Exited);
+        assert_eq!(reg(&mut emu, "ebx"), 3);
+
+        assert!(emu.removeBreakpoint(top));
+        assert!(emu.getBreakpoints().is_empty());
+    }
+
+    #[test]
+    fn step_over_a_recursive_call() {
+        // `count` calls itself until %eax is 0: every nested call returns to `back`
+        let mut emu = load("movl $3, %eax\ncall count\nafter:\nmovl %eax, %ebx\njmp done\n\
+                            count:\ndecl %eax\njz back\ncall count\nback:\nret\ndone:\nmovl $0, %ecx");
+        for _ in 0..4 {
+            emu.stepInstruction().unwrap();
+        }
+        assert_eq!(emu.getPC(), emu.getLabel("back").unwrap() - 5);
+        let sp = emu.getSP();
+
+        // The innermost frames come back to `back` first, with the stack deeper than it is now
+        assert_eq!(emu.stepOver().unwrap(), StopReason::Stepped);
+        assert_eq!(emu.getPC(), emu.getLabel("back").unwrap());
+        assert_eq!(emu.getSP(), sp);
+        assert_eq!(reg(&mut emu, "eax"), 0);
+
+        assert_eq!(emu.stepInstruction().unwrap(), StopReason::Stepped);
+        assert_eq!(emu.getPC(), emu.getLabel("after").unwrap());
+    }
+
+    #[test]
+    fn step_over_stops_at_breakpoints() {
+        let mut emu = load(FRAME);
+        emu.stepInstruction().unwrap();
+        emu.stepInstruction().unwrap();
+        let inside = emu.getLabel("f").unwrap() + 3;
+        emu.addBreakpoint(inside);
+
+        assert_eq!(emu.stepOver().unwrap(), StopReason::Breakpoint(inside));
+        assert_eq!(reg(&mut emu, "eax"), 0);
+    }
+
+    #[test]
+    fn step_out_of_the_middle_of_a_frame() {
+        let mut emu = load(FRAME);
+        for _ in 0..5 {
+            emu.stepInstruction().unwrap();
+        }
+        let sp = emu.getSP();
+        assert_eq!(reg(&mut emu, "ebp"), sp);
+
+        assert_eq!(emu.stepOut().unwrap(), StopReason::Stepped);
+        assert_eq!(emu.getPC(), emu.getLabel("after").unwrap());
+        assert_eq!(emu.getSP(), sp + 8);
+        assert_eq!((reg(&mut emu, "eax"), reg(&mut emu, "ecx")), (1, 2));
+
+        assert_eq!(emu.resume().unwrap(), StopReason::Exited);
+        assert_eq!(reg(&mut emu, "ebx"), 7);
+    }
+}
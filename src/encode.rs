@@ -0,0 +1,1249 @@
+use ximpl;
+use ximpl::{Argument, Command, Mode, Size};
+use error::InterpError;
+
+// Assemble instructions into x86 machine code
+//   The form of an instruction never depends on the value of a label (label immediates,
+//   displacements and jump targets always take 4 bytes), so the length of every
+//   instruction is known before the labels have been given addresses
+
+// Intel Manual: Volume 2, Chapter 2 (Instruction Format) and Appendix A (Opcode Map)
+// http://ref.x86asm.net/coder32.html
+
+// The `exit` and `dump` pseudo-instructions are encoded as `hlt` and `int3`
+pub const EXIT_OPCODE: u8 = 0xf4;
+pub const DUMP_OPCODE: u8 = 0xcc;
+
+// Assemble an instruction placed at `addr` (`resolve` looks up the address of a label)
+pub fn encode(inst: &Command, addr: u64, mode: Mode, resolve: &dyn Fn(&str) -> Option<u64>) -> Result<Vec<u8>, InterpError> {
+    let mut enc = Encoder::new(mode, resolve);
+
+    match *inst {
+        Command::NoArg(ref mne, size) => enc.no_arg(mne, size)?,
+        Command::OneArg(ref mne, size, ref arg) => enc.one_arg(mne, size, arg)?,
+        Command::TwoArg(ref mne, size, ref src, ref dest) => enc.two_arg(mne, size, src, dest)?,
+        Command::ThreeArg(ref mne, size, ref a1, ref a2, ref a3) => enc.three_arg(mne, size, a1, a2, a3)?,
+        Command::Directive(_) | Command::Label(_) | Command::Nop => return Ok(Vec::new()),
+        Command::Align(align, fill, max) => return Ok(vec![fill; ximpl::align_padding(align, max, addr) as usize])
+    }
+
+    enc.finish(addr)
+}
+
+// Number of bytes an instruction assembles to (labels are given a placeholder address)
+pub fn length(inst: &Command, mode: Mode) -> Result<usize, InterpError> {
+    encode(inst, 0, mode, &|_| Some(0)).map(|bytes| bytes.len())
+}
+
+// REX prefix bits
+const REX_W: u8 = 0x8;
+const REX_R: u8 = 0x4;
+const REX_X: u8 = 0x2;
+const REX_B: u8 = 0x1;
+
+// Fields that can hold a value relative to the end of the instruction
+#[derive(Clone, Copy)]
+enum Field {
+    Disp,
+    Imm
+}
+
+// The parts of an instruction, in encoding order
+struct Encoder<'a> {
+    mode: Mode,
+    resolve: &'a dyn Fn(&str) -> Option<u64>,
+
+    prefixes: Vec<u8>,
+    rex: u8,
+    needs_rex: bool,            // `%spl`-`%dil` need a REX prefix even without any bits set
+    high_byte: bool,            // `%ah`-`%bh` can't be used along with a REX prefix
+    opcode: Vec<u8>,
+    reg: u8,                    // ModR/M reg field (a register or an opcode extension)
+    modrm: Option<(u8, u8)>,    // ModR/M mod and r/m fields
+    sib: Option<u8>,
+    disp: Vec<u8>,
+    imm: Vec<u8>,
+
+    // Jump targets and `%rip` relative operands hold `target - next instruction`
+    relative: Option<(Field, u64)>
+}
+
+impl<'a> Encoder<'a> {
+    fn new(mode: Mode, resolve: &'a dyn Fn(&str) -> Option<u64>) -> Encoder<'a> {
+        Encoder{
+            mode,
+            resolve,
+
+            prefixes: Vec::new(),
+            rex: 0,
+            needs_rex: false,
+            high_byte: false,
+            opcode: Vec::new(),
+            reg: 0,
+            modrm: None,
+            sib: None,
+            disp: Vec::new(),
+            imm: Vec::new(),
+
+            relative: None
+        }
+    }
+
+    fn finish(self, addr: u64) -> Result<Vec<u8>, InterpError> {
+        let rex = self.rex != 0 || self.needs_rex;
+        if rex && self.high_byte {
+            return Err(InterpError::InvalidOperand(
+                "`%ah`, `%ch`, `%dh` and `%bh` can't be used along with 64bit operands, `%r8`-`%r15` or `%spl`-`%dil`".to_owned()));
+        }
+
+        let mut bytes = self.prefixes;
+        if rex {
+            bytes.push(0x40 | self.rex);
+        }
+        bytes.extend(self.opcode);
+        if let Some((md, rm)) = self.modrm {
+            bytes.push((md << 6) | (self.reg << 3) | rm);
+        }
+        bytes.extend(self.sib);
+
+        let disp_at = bytes.len();
+        bytes.extend(self.disp);
+        let imm_at = bytes.len();
+        bytes.extend(self.imm);
+
+        if let Some((field, target)) = self.relative {
+            let next = addr.wrapping_add(bytes.len() as u64);
+            let rel = target.wrapping_sub(next) as i64;
+            if self.mode == Mode::Bits64 && !fits_i32(rel) {
+                return Err(InterpError::InvalidOperand(format!("Target {:#x} is too far away from {:#x}", target, next)));
+            }
+
+            let at = match field {
+                Field::Disp => disp_at,
+                Field::Imm => imm_at
+            };
+            bytes[at..at + 4].copy_from_slice(&(rel as i32).to_le_bytes());
+        }
+
+        Ok(bytes)
+    }
+
+
+    // Instruction forms
+    fn no_arg(&mut self, mne: &str, size: Option<Size>) -> Result<(), InterpError> {
+        let size = size.unwrap_or_else(|| ximpl::default_size(mne, self.mode));
+
+        match mne {
+            "cbtw" | "cbw" => self.fixed_size(Size::Word, &[0x98]),
+            "cwtl" | "cwde" => self.fixed_size(Size::Long, &[0x98]),
+            "cltq" | "cdqe" => self.fixed_size(Size::Quad, &[0x98]),
+            "cwtd" | "cwd" => self.fixed_size(Size::Word, &[0x99]),
+            "cltd" | "cdq" => self.fixed_size(Size::Long, &[0x99]),
+            "cqto" | "cqo" => self.fixed_size(Size::Quad, &[0x99]),
+            "leave" => {
+                self.stack_operand(mne, size)?;
+                self.opcode.push(0xc9);
+                Ok(())
+            },
+            "ret" => {
+                self.stack_operand(mne, size)?;
+                self.opcode.push(0xc3);
+                Ok(())
+            },
+            "nop" => {
+                self.opcode.push(0x90);
+                Ok(())
+            },
+            "syscall" => {
+                if self.mode == Mode::Bits32 {
+                    return Err(mode_error("`syscall`"));
+                }
+                self.opcode.extend(&[0x0f, 0x05]);
+                Ok(())
+            },
+            "endbr32" | "endbr64" => {
+                self.prefixes.push(0xf3);
+                self.opcode.extend(&[0x0f, 0x1e, if mne == "endbr32" { 0xfb } else { 0xfa }]);
+                Ok(())
+            },
+            "exit" => {
+                self.opcode.push(EXIT_OPCODE);
+                Ok(())
+            },
+            "dump" => {
+                self.opcode.push(DUMP_OPCODE);
+                Ok(())
+            },
+            _ => Err(InterpError::UnknownMnemonic(mne.to_string()))
+        }
+    }
+
+    fn one_arg(&mut self, mne: &str, size: Option<Size>, arg: &Argument) -> Result<(), InterpError> {
+        use self::Argument::*;
+        let size = size.unwrap_or_else(|| ximpl::default_size(mne, self.mode));
+
+        match mne {
+            "push" => {
+                self.stack_operand(mne, size)?;
+                match *arg {
+                    Reg(ref r) => self.register_opcode(0x50, r),
+                    Literal(_) | Address(..) => {
+                        let (val, label) = self.immediate(arg, size)?;
+                        if !label && fits_i8(val) {
+                            self.opcode.push(0x6a);
+                            self.immediate_bytes(val, false, Size::Byte)
+                        } else {
+                            self.opcode.push(0x68);
+                            self.immediate_bytes(val, label, size)
+                        }
+                    },
+                    _ => self.extended(mne, &[0xff], 6, arg)
+                }
+            },
+            "pop" => {
+                self.stack_operand(mne, size)?;
+                match *arg {
+                    Reg(ref r) => self.register_opcode(0x58, r),
+                    Mem(..) | Label(_) => self.extended(mne, &[0x8f], 0, arg),
+                    _ => Err(operand_error(mne, "a register or memory operand"))
+                }
+            },
+            "inc" | "dec" | "not" | "neg" | "mul" | "imul" | "div" | "idiv" => {
+                let (opcode, digit) = match mne {
+                    "inc" => (0xfe, 0),
+                    "dec" => (0xfe, 1),
+                    "not" => (0xf6, 2),
+                    "neg" => (0xf6, 3),
+                    "mul" => (0xf6, 4),
+                    "imul" => (0xf6, 5),
+                    "div" => (0xf6, 6),
+                    _ => (0xf6, 7)
+                };
+                // 32bit mode has one byte forms of `inc`/`dec` (they're REX prefixes in 64bit mode)
+                match *arg {
+                    Reg(ref r) if opcode == 0xfe && size != Size::Byte && self.mode == Mode::Bits32 => {
+                        self.operand_size(size)?;
+                        self.register_opcode(0x40 + (digit << 3), r)
+                    },
+                    _ => {
+                        self.sized_opcode(size, opcode)?;
+                        self.reg = digit;
+                        self.rm_operand(mne, arg)
+                    }
+                }
+            },
+            "sal" | "shl" | "sar" | "shr" |
+            "rol" | "ror" | "rcl" | "rcr" => self.shift(mne, size, &Literal(1), arg),
+            "jmp" => match *arg {
+                Label(_) | Target(_) => self.jump(&[0xe9], arg),
+                _ => {
+                    self.stack_operand(mne, size)?;
+                    self.extended(mne, &[0xff], 4, arg)
+                }
+            },
+            "call" => match *arg {
+                Label(_) | Target(_) => self.jump(&[0xe8], arg),
+                _ => {
+                    self.stack_operand(mne, size)?;
+                    self.extended(mne, &[0xff], 2, arg)
+                }
+            },
+            "ret" => match *arg {
+                Literal(val) => {
+                    self.stack_operand(mne, size)?;
+                    self.opcode.push(0xc2);
+                    self.immediate_bytes(val, false, Size::Word)
+                },
+                _ => Err(operand_error(mne, "an immediate"))
+            },
+            // Software interrupts (`int $3` stays two bytes, `0xcc` is taken by `dump`)
+            "int" => match *arg {
+                Literal(val) if (0..=0xff).contains(&val) => {
+                    self.opcode.push(0xcd);
+                    self.immediate_bytes(val, false, Size::Byte)
+                },
+                _ => Err(operand_error(mne, "an interrupt number (0-255)"))
+            },
+            _ if mne.starts_with("set") => {
+                let cc = condition_code(&mne[3..])?;
+                if let Reg(ref r) = *arg {
+                    if ximpl::reg_location(r).map(|(_, _, len)| len) != Some(1) {
+                        return Err(operand_error(mne, "a byte register or memory operand"));
+                    }
+                }
+                self.extended(mne, &[0x0f, 0x90 + cc], 0, arg)
+            },
+            _ if mne.starts_with("j") => {
+                let cc = condition_code(&mne[1..])?;
+                match *arg {
+                    Label(_) | Target(_) => self.jump(&[0x0f, 0x80 + cc], arg),
+                    _ => Err(operand_error(mne, "a label"))
+                }
+            },
+            _ => Err(InterpError::UnknownMnemonic(mne.to_string()))
+        }
+    }
+
+    fn two_arg(&mut self, mne: &str, size: Option<Size>, src: &Argument, dest: &Argument) -> Result<(), InterpError> {
+        let size = size.unwrap_or_else(|| ximpl::default_size(mne, self.mode));
+
+        match mne {
+            // The operand size is the destination's, the source is sized by the mnemonic
+            "movzb" | "movzw" | "movsb" | "movsw" | "movsl" => {
+                let opcode: &[u8] = match mne {
+                    "movzb" => &[0x0f, 0xb6],
+                    "movzw" => &[0x0f, 0xb7],
+                    "movsb" => &[0x0f, 0xbe],
+                    "movsw" => &[0x0f, 0xbf],
+                    _ => &[0x63]
+                };
+
+                if mne == "movsl" && size != Size::Quad {
+                    return Err(operand_error(mne, "a 64bit destination"));
+                }
+                self.operand_size(size)?;
+                self.opcode.extend(opcode);
+                self.reg_operand(mne, dest)?;
+                self.rm_operand(mne, src)
+            },
+            "mov" => self.mov(size, src, dest),
+            "xchg" => self.xchg(size, src, dest),
+            "movabs" => self.movabs(size, src, dest),
+            "lea" => {
+                if size == Size::Byte {
+                    return Err(operand_error(mne, "a 16, 32 or 64bit destination"));
+                }
+                match *src {
+                    Argument::Mem(..) | Argument::Label(_) => (),
+                    _ => return Err(operand_error(mne, "a memory source"))
+                }
+                self.operand_size(size)?;
+                self.opcode.push(0x8d);
+                self.reg_operand(mne, dest)?;
+                self.rm_operand(mne, src)
+            },
+            "add" => self.arithmetic(mne, 0, size, src, dest),
+            "or" => self.arithmetic(mne, 1, size, src, dest),
+            "adc" => self.arithmetic(mne, 2, size, src, dest),
+            "sbb" => self.arithmetic(mne, 3, size, src, dest),
+            "and" => self.arithmetic(mne, 4, size, src, dest),
+            "sub" => self.arithmetic(mne, 5, size, src, dest),
+            "xor" => self.arithmetic(mne, 6, size, src, dest),
+            "cmp" => self.arithmetic(mne, 7, size, src, dest),
+            "test" => self.test(size, src, dest),
+            "imul" => match *src {
+                Argument::Literal(_) | Argument::Address(..) => self.multiply_immediate(size, src, dest, dest),
+                _ => {
+                    self.multiply_size(size)?;
+                    self.opcode.extend(&[0x0f, 0xaf]);
+                    self.reg_operand(mne, dest)?;
+                    self.rm_operand(mne, src)
+                }
+            },
+            "sal" | "shl" | "sar" | "shr" |
+            "rol" | "ror" | "rcl" | "rcr" => self.shift(mne, size, src, dest),
+            // Operands are `enter $alloc, $level`
+            "enter" => match (src, dest) {
+                (&Argument::Literal(alloc), &Argument::Literal(level)) => {
+                    self.stack_operand(mne, size)?;
+                    self.opcode.push(0xc8);
+                    self.immediate_bytes(alloc, false, Size::Word)?;
+                    self.immediate_bytes(level, false, Size::Byte)
+                },
+                _ => Err(operand_error(mne, "two immediates"))
+            },
+            _ if mne.starts_with("cmov") => {
+                let cc = condition_code(&mne[4..])?;
+                self.multiply_size(size)?;
+                self.opcode.extend(&[0x0f, 0x40 + cc]);
+                self.reg_operand(mne, dest)?;
+                self.rm_operand(mne, src)
+            },
+            _ => Err(InterpError::UnknownMnemonic(mne.to_string()))
+        }
+    }
+
+    fn three_arg(&mut self, mne: &str, size: Option<Size>, a1: &Argument, a2: &Argument, a3: &Argument) -> Result<(), InterpError> {
+        let size = size.unwrap_or_else(|| ximpl::default_size(mne, self.mode));
+
+        match mne {
+            // `imul $imm, src, dest`
+            "imul" => self.multiply_immediate(size, a1, a2, a3),
+            // `shld count, src, dest`
+            "shld" | "shrd" => {
+                let opcode = if mne == "shld" { 0xa4 } else { 0xac };
+                self.multiply_size(size)?;
+
+                match *a1 {
+                    Argument::Literal(count) => {
+                        self.opcode.extend(&[0x0f, opcode]);
+                        self.immediate_bytes(count, false, Size::Byte)?;
+                    },
+                    Argument::Reg(ref r) if r == "cl" => self.opcode.extend(&[0x0f, opcode + 1]),
+                    _ => return Err(InterpError::InvalidOperand("Shift counts must be an immediate or `%cl`".to_owned()))
+                }
+                self.reg_operand(mne, a2)?;
+                self.rm_operand(mne, a3)
+            },
+            _ => Err(InterpError::UnknownMnemonic(mne.to_string()))
+        }
+    }
+
+    fn mov(&mut self, size: Size, src: &Argument, dest: &Argument) -> Result<(), InterpError> {
+        use self::Argument::*;
+
+        match (src, dest) {
+            // The accumulator has forms taking just an absolute address in 32bit mode
+            (&Mem(None, None, _, _, _), &Reg(_)) | (&Label(_), &Reg(_)) if self.mode == Mode::Bits32 && is_accumulator(dest) => {
+                self.sized_opcode(size, 0xa0)?;
+                let addr = self.absolute_address(src)?;
+                self.displacement(addr, 4)
+            },
+            (&Reg(_), &Mem(None, None, _, _, _)) | (&Reg(_), &Label(_)) if self.mode == Mode::Bits32 && is_accumulator(src) => {
+                self.sized_opcode(size, 0xa2)?;
+                let addr = self.absolute_address(dest)?;
+                self.displacement(addr, 4)
+            },
+            (&Reg(_), &Reg(_)) | (&Reg(_), &Mem(..)) | (&Reg(_), &Label(_)) => {
+                self.sized_opcode(size, 0x88)?;
+                self.reg_operand("mov", src)?;
+                self.rm_operand("mov", dest)
+            },
+            (&Mem(..), &Reg(_)) | (&Label(_), &Reg(_)) => {
+                self.sized_opcode(size, 0x8a)?;
+                self.reg_operand("mov", dest)?;
+                self.rm_operand("mov", src)
+            },
+            // 64bit immediates are sign extended from 32bits, anything larger needs `movabs`
+            (&Literal(val), &Reg(_)) if size == Size::Quad && !fits_i32(val) => self.movabs(size, src, dest),
+            (&Literal(_), &Reg(ref r)) | (&Address(..), &Reg(ref r)) if size != Size::Quad => {
+                let (val, label) = self.immediate(src, size)?;
+                let opcode = if size == Size::Byte { 0xb0 } else { 0xb8 };
+                self.operand_size(size)?;
+                self.register_opcode(opcode, r)?;
+                self.immediate_bytes(val, label, size)
+            },
+            (&Literal(_), _) | (&Address(..), _) => {
+                let (val, label) = self.immediate(src, size)?;
+                self.sized_opcode(size, 0xc6)?;
+                self.reg = 0;
+                self.rm_operand("mov", dest)?;
+                self.immediate_bytes(val, label, size)
+            },
+            _ => Err(InterpError::InvalidOperand("`mov` can't have two memory operands".to_owned()))
+        }
+    }
+
+    // `movabs $imm64, %reg` (REX.W B8+r) loads a full 8 byte immediate
+    fn movabs(&mut self, size: Size, src: &Argument, dest: &Argument) -> Result<(), InterpError> {
+        match (src, dest) {
+            (&Argument::Literal(_), &Argument::Reg(ref r)) | (&Argument::Address(..), &Argument::Reg(ref r)) if size == Size::Quad => {
+                let (val, _) = self.immediate_value(src)?;
+                self.operand_size(size)?;
+                self.register_opcode(0xb8, r)?;
+                self.imm.extend(&val.to_le_bytes());
+                Ok(())
+            },
+            _ => Err(operand_error("movabs", "an immediate and a 64bit register"))
+        }
+    }
+
+    // `add`, `or`, `and`, `sub`, `xor` and `cmp` (`digit` is their row in the opcode map)
+    fn arithmetic(&mut self, mne: &str, digit: u8, size: Size, src: &Argument, dest: &Argument) -> Result<(), InterpError> {
+        use self::Argument::*;
+
+        match *src {
+            Literal(_) | Address(..) => {
+                let (val, label) = self.immediate(src, size)?;
+
+                // Small immediates are sign extended from a byte, the accumulator has a shorter form
+                if !label && size != Size::Byte && fits_i8(val) {
+                    self.operand_size(size)?;
+                    self.opcode.push(0x83);
+                    self.reg = digit;
+                    self.rm_operand(mne, dest)?;
+                    self.immediate_bytes(val, false, Size::Byte)
+                } else if is_accumulator(dest) {
+                    self.sized_opcode(size, (digit << 3) + 4)?;
+                    self.immediate_bytes(val, label, size)
+                } else {
+                    self.sized_opcode(size, 0x80)?;
+                    self.reg = digit;
+                    self.rm_operand(mne, dest)?;
+                    self.immediate_bytes(val, label, size)
+                }
+            },
+            Reg(_) => {
+                self.sized_opcode(size, digit << 3)?;
+                self.reg_operand(mne, src)?;
+                self.rm_operand(mne, dest)
+            },
+            _ => match *dest {
+                Reg(_) => {
+                    self.sized_opcode(size, (digit << 3) + 2)?;
+                    self.reg_operand(mne, dest)?;
+                    self.rm_operand(mne, src)
+                },
+                _ => Err(InterpError::InvalidOperand(format!("`{}` can't have two memory operands", mne)))
+            }
+        }
+    }
+
+    // The register goes in the reg field whichever side it's on, the accumulator has a short form
+    fn xchg(&mut self, size: Size, src: &Argument, dest: &Argument) -> Result<(), InterpError> {
+        let (reg, rm) = match (src, dest) {
+            (&Argument::Reg(_), _) => (src, dest),
+            (_, &Argument::Reg(_)) => (dest, src),
+            _ => return Err(InterpError::InvalidOperand("`xchg` needs a register operand".to_owned()))
+        };
+
+        // `0x90` is `nop` (ie. `xchg %rax, %rax`), but `xchg %eax, %eax` zero extends `%rax` in 64bit mode
+        let pair = is_accumulator(src) && is_accumulator(dest);
+        let short = size != Size::Byte && !(pair && size == Size::Long && self.mode == Mode::Bits64);
+        match (rm, reg) {
+            _ if pair && size == Size::Quad => {
+                self.opcode.push(0x90);
+                Ok(())
+            },
+            (Argument::Reg(r), _) if short && is_accumulator(reg) => {
+                self.operand_size(size)?;
+                self.register_opcode(0x90, r)
+            },
+            (_, Argument::Reg(r)) if short && is_accumulator(rm) => {
+                self.operand_size(size)?;
+                self.register_opcode(0x90, r)
+            },
+            _ => {
+                self.sized_opcode(size, 0x86)?;
+                self.reg_operand("xchg", reg)?;
+                self.rm_operand("xchg", rm)
+            }
+        }
+    }
+
+    fn test(&mut self, size: Size, src: &Argument, dest: &Argument) -> Result<(), InterpError> {
+        use self::Argument::*;
+
+        match (src, dest) {
+            (&Literal(_), _) | (&Address(..), _) => {
+                let (val, label) = self.immediate(src, size)?;
+                if is_accumulator(dest) {
+                    self.sized_opcode(size, 0xa8)?;
+                } else {
+                    self.sized_opcode(size, 0xf6)?;
+                    self.reg = 0;
+                    self.rm_operand("test", dest)?;
+                }
+                self.immediate_bytes(val, label, size)
+            },
+            // The register goes in the reg field whichever side it's on
+            (&Reg(_), _) | (_, &Reg(_)) => {
+                let (reg, rm) = match *src {
+                    Reg(_) => (src, dest),
+                    _ => (dest, src)
+                };
+                self.sized_opcode(size, 0x84)?;
+                self.reg_operand("test", reg)?;
+                self.rm_operand("test", rm)
+            },
+            _ => Err(InterpError::InvalidOperand("`test` can't have two memory operands".to_owned()))
+        }
+    }
+
+    // `imul $imm, src, dest` (the two operand form has `src` = `dest`)
+    fn multiply_immediate(&mut self, size: Size, imm: &Argument, src: &Argument, dest: &Argument) -> Result<(), InterpError> {
+        let (val, label) = self.immediate(imm, size)?;
+        self.multiply_size(size)?;
+
+        if !label && fits_i8(val) {
+            self.opcode.push(0x6b);
+            self.reg_operand("imul", dest)?;
+            self.rm_operand("imul", src)?;
+            self.immediate_bytes(val, false, Size::Byte)
+        } else {
+            self.opcode.push(0x69);
+            self.reg_operand("imul", dest)?;
+            self.rm_operand("imul", src)?;
+            self.immediate_bytes(val, label, size)
+        }
+    }
+
+    fn shift(&mut self, mne: &str, size: Size, count: &Argument, dest: &Argument) -> Result<(), InterpError> {
+        let digit = match mne {
+            "rol" => 0,
+            "ror" => 1,
+            "rcl" => 2,
+            "rcr" => 3,
+            "sal" | "shl" => 4,
+            "shr" => 5,
+            _ => 7
+        };
+
+        match *count {
+            Argument::Literal(1) => self.sized_opcode(size, 0xd0)?,
+            Argument::Literal(_) => self.sized_opcode(size, 0xc0)?,
+            Argument::Reg(ref r) if r == "cl" => self.sized_opcode(size, 0xd2)?,
+            _ => return Err(InterpError::InvalidOperand("Shift counts must be an immediate or `%cl`".to_owned()))
+        }
+        self.reg = digit;
+        self.rm_operand(mne, dest)?;
+
+        match *count {
+            Argument::Literal(n) if n != 1 => self.immediate_bytes(n, false, Size::Byte),
+            _ => Ok(())
+        }
+    }
+
+    // Relative jumps always take a 4 byte displacement (so their length doesn't depend on the target)
+    fn jump(&mut self, opcode: &[u8], target: &Argument) -> Result<(), InterpError> {
+        let target = match *target {
+            Argument::Target(addr) => addr,
+            Argument::Label(ref lbl) => self.label(lbl)?,
+            _ => return Err(InterpError::InvalidOperand("Jumps take a label".to_owned()))
+        };
+        self.opcode.extend(opcode);
+        self.imm.extend(&[0; 4]);
+        self.relative = Some((Field::Imm, target));
+        Ok(())
+    }
+
+
+    // Operand sizes
+    // 16bit operands take a `0x66` prefix and 64bit ones REX.W
+    fn operand_size(&mut self, size: Size) -> Result<(), InterpError> {
+        match size {
+            Size::Word => self.prefixes.push(0x66),
+            Size::Quad if self.mode == Mode::Bits32 => return Err(mode_error("64bit operand size")),
+            Size::Quad => self.rex |= REX_W,
+            _ => ()
+        }
+        Ok(())
+    }
+
+    // Byte sized forms are one opcode below the 16/32/64bit forms
+    fn sized_opcode(&mut self, size: Size, byte_opcode: u8) -> Result<(), InterpError> {
+        match size {
+            Size::Byte => self.opcode.push(byte_opcode),
+            _ => {
+                self.operand_size(size)?;
+                self.opcode.push(byte_opcode + 1);
+            }
+        }
+        Ok(())
+    }
+
+    fn fixed_size(&mut self, size: Size, opcode: &[u8]) -> Result<(), InterpError> {
+        self.operand_size(size)?;
+        self.opcode.extend(opcode);
+        Ok(())
+    }
+
+    // Instructions without byte forms (`imul`, `cmov`, `shld`/`shrd`)
+    fn multiply_size(&mut self, size: Size) -> Result<(), InterpError> {
+        match size {
+            Size::Byte => Err(InterpError::InvalidOperand("Byte operands can't be encoded for this instruction".to_owned())),
+            _ => self.operand_size(size)
+        }
+    }
+
+    // Stack operations (and indirect jumps) are the width of the mode, or 16bits
+    fn stack_operand(&mut self, mne: &str, size: Size) -> Result<(), InterpError> {
+        match size {
+            Size::Word => self.prefixes.push(0x66),
+            _ if size == self.mode.stack_size() => (),
+            _ => return Err(InterpError::InvalidOperand(format!("`{}` can't take {} byte operands in {}bit mode",
+                                                                mne, size.bytes(), mode_bits(self.mode))))
+        }
+        Ok(())
+    }
+
+
+    // Operands
+    // Register number (0-15, `%ah`-`%bh` are 4-7) checked against the mode
+    fn register(&mut self, name: &str) -> Result<u8, InterpError> {
+        let (idx, off, len) = match ximpl::reg_location(name) {
+            Some((ximpl::RIP, _, _)) =>
+                return Err(InterpError::InvalidOperand(format!("`%{}` can only be used as a base register", name))),
+            Some(loc) => loc,
+            None => return Err(InterpError::UnknownRegister(name.to_string()))
+        };
+
+        let byte_rex = len == 1 && off == 0 && (4..8).contains(&idx);
+        if self.mode == Mode::Bits32 && (idx >= 8 || len == 8 || byte_rex) {
+            return Err(mode_error(&format!("`%{}`", name)));
+        }
+
+        if off == 1 {
+            self.high_byte = true;
+            Ok(idx as u8 + 4)
+        } else {
+            self.needs_rex |= byte_rex;
+            Ok(idx as u8)
+        }
+    }
+
+    // A register encoded in the low bits of the opcode (ie. `push`/`pop`)
+    fn register_opcode(&mut self, opcode: u8, name: &str) -> Result<(), InterpError> {
+        let num = self.register(name)?;
+        if num & 8 != 0 {
+            self.rex |= REX_B;
+        }
+        self.opcode.push(opcode + (num & 7));
+        Ok(())
+    }
+
+    // A register in the ModR/M reg field
+    fn reg_operand(&mut self, mne: &str, arg: &Argument) -> Result<(), InterpError> {
+        let num = match *arg {
+            Argument::Reg(ref r) => self.register(r)?,
+            _ => return Err(operand_error(mne, "a register"))
+        };
+
+        if num & 8 != 0 {
+            self.rex |= REX_R;
+        }
+        self.reg = num & 7;
+        Ok(())
+    }
+
+    // A register or memory operand in the ModR/M r/m field
+    fn rm_operand(&mut self, mne: &str, arg: &Argument) -> Result<(), InterpError> {
+        match *arg {
+            Argument::Reg(ref r) => {
+                let num = self.register(r)?;
+                if num & 8 != 0 {
+                    self.rex |= REX_B;
+                }
+                self.modrm = Some((3, num & 7));
+                Ok(())
+            },
+            Argument::Mem(ref base, ref idx, scale, disp, ref lbl) =>
+                self.memory(base.as_ref().map(|b| &**b), idx.as_ref().map(|i| &**i), scale, disp, lbl.as_ref()),
+            Argument::Label(ref lbl) => self.memory(None, None, 1, 0, Some(lbl)),
+            _ => Err(operand_error(mne, "a register or memory operand"))
+        }
+    }
+
+    // An opcode with an extension in the reg field (ie. `0xff /6`)
+    fn extended(&mut self, mne: &str, opcode: &[u8], digit: u8, arg: &Argument) -> Result<(), InterpError> {
+        self.opcode.extend(opcode);
+        self.reg = digit;
+        self.rm_operand(mne, arg)
+    }
+
+    // Encode `disp(base, index, scale)` into the ModR/M, SIB and displacement
+    fn memory(&mut self, base: Option<&Argument>, idx: Option<&Argument>, scale: i32, disp: i32, lbl: Option<&String>) -> Result<(), InterpError> {
+        let mut disp = disp as i64;
+        if let Some(lbl) = lbl {
+            disp = disp.wrapping_add(self.label(lbl)? as i64);
+        }
+
+        let base = match base {
+            Some(base) => Some(self.address_register(base)?),
+            None => None
+        };
+        let idx = match idx {
+            Some(idx) => Some(self.address_register(idx)?),
+            None => None
+        };
+
+        // Both registers (and so the address) have to be the same width
+        let width = match (base, idx) {
+            (Some((_, b)), Some((_, i))) if b != i =>
+                return Err(InterpError::InvalidOperand("Base and index registers have to be the same size".to_owned())),
+            (Some((_, len)), _) | (_, Some((_, len))) => len,
+            (None, None) => self.mode.stack_size().bytes()
+        };
+        if self.mode == Mode::Bits64 && width == 4 {
+            self.prefixes.push(0x67);
+        }
+
+        // `disp(%rip)` is relative to the next instruction (labels are made relative to it)
+        if base.is_some_and(|(num, _)| num as usize == ximpl::RIP) {
+            if self.mode == Mode::Bits32 {
+                return Err(mode_error("`%rip` relative addressing"));
+            } else if idx.is_some() {
+                return Err(InterpError::InvalidOperand("`%rip` can't be used along with an index register".to_owned()));
+            }
+
+            self.modrm = Some((0, 5));
+            if lbl.is_some() {
+                self.relative = Some((Field::Disp, disp as u64));
+            }
+            return self.displacement(disp, 4);
+        }
+
+        let ss = match (idx, scale) {
+            (None, _) | (_, 1) => 0,
+            (_, 2) => 1,
+            (_, 4) => 2,
+            (_, 8) => 3,
+            _ => return Err(InterpError::InvalidOperand(format!("Invalid scale factor: {} (expected 1, 2, 4 or 8)", scale)))
+        };
+        if let Some((num, _)) = idx {
+            if num == 4 {
+                return Err(InterpError::InvalidOperand("The stack pointer can't be used as an index register".to_owned()));
+            } else if num & 8 != 0 {
+                self.rex |= REX_X;
+            }
+        }
+
+        match base {
+            // Absolute addresses (64bit mode needs a SIB byte, as `mod 00 r/m 101` is `%rip` relative)
+            None => {
+                let index = idx.map(|(num, _)| num & 7).unwrap_or(4);
+                if idx.is_none() && self.mode == Mode::Bits32 {
+                    self.modrm = Some((0, 5));
+                } else {
+                    self.modrm = Some((0, 4));
+                    self.sib = Some((ss << 6) | (index << 3) | 5);
+                }
+                self.displacement(disp, 4)
+            },
+            Some((num, _)) => {
+                if num & 8 != 0 {
+                    self.rex |= REX_B;
+                }
+
+                // Labels always get a full displacement, `%ebp`/`%r13` can't go without one
+                let len = if lbl.is_some() || !fits_i8(disp) {
+                    4
+                } else if disp == 0 && num & 7 != 5 {
+                    0
+                } else {
+                    1
+                };
+                let md = match len {
+                    0 => 0,
+                    1 => 1,
+                    _ => 2
+                };
+
+                // `%esp`/`%r12` as a base need a SIB byte
+                if idx.is_some() || num & 7 == 4 {
+                    let index = idx.map(|(num, _)| num & 7).unwrap_or(4);
+                    self.modrm = Some((md, 4));
+                    self.sib = Some((ss << 6) | (index << 3) | (num & 7));
+                } else {
+                    self.modrm = Some((md, num & 7));
+                }
+                self.displacement(disp, len)
+            }
+        }
+    }
+
+    // Address of a memory operand without any registers
+    fn absolute_address(&self, arg: &Argument) -> Result<i64, InterpError> {
+        match *arg {
+            Argument::Mem(None, None, _, disp, Some(ref lbl)) => Ok((self.label(lbl)? as i64).wrapping_add(disp as i64)),
+            Argument::Mem(None, None, _, disp, None) => Ok(disp as i64),
+            Argument::Label(ref lbl) => Ok(self.label(lbl)? as i64),
+            _ => Err(InterpError::InvalidOperand("Expected an absolute address".to_owned()))
+        }
+    }
+
+    // Register number and width of a base/index register
+    fn address_register(&mut self, arg: &Argument) -> Result<(u8, usize), InterpError> {
+        let name = match *arg {
+            Argument::Reg(ref r) => r,
+            _ => return Err(InterpError::InvalidOperand("Memory operands can only hold registers".to_owned()))
+        };
+
+        match ximpl::reg_location(name) {
+            Some((_, _, 2)) | Some((_, _, 1)) =>
+                Err(InterpError::Unimplemented(format!("addressing through `%{}` (only 32 and 64bit registers can address memory)", name))),
+            Some((idx, _, len)) if self.mode == Mode::Bits32 && (len == 8 || (idx >= 8 && idx != ximpl::RIP)) =>
+                Err(mode_error(&format!("`%{}`", name))),
+            Some((idx, _, len)) => Ok((idx as u8, len)),
+            None => Err(InterpError::UnknownRegister(name.to_string()))
+        }
+    }
+
+    fn displacement(&mut self, disp: i64, len: usize) -> Result<(), InterpError> {
+        // Displacements are sign extended in 64bit mode (32bit addresses just wrap)
+        let fits = match self.mode {
+            Mode::Bits32 => disp >= i32::MIN as i64 && disp <= u32::MAX as i64,
+            Mode::Bits64 => fits_i32(disp)
+        };
+        if !fits {
+            return Err(InterpError::InvalidOperand(format!("Displacement {:#x} doesn't fit in 32bits", disp)));
+        }
+
+        self.disp.extend(&disp.to_le_bytes()[..len]);
+        Ok(())
+    }
+
+    // Value of an immediate operand (and whether it's the address of a label)
+    fn immediate_value(&self, arg: &Argument) -> Result<(i64, bool), InterpError> {
+        match *arg {
+            Argument::Literal(val) => Ok((val, false)),
+            Argument::Address(ref lbl, off) => Ok((self.label(lbl)?.wrapping_add(off as i64 as u64) as i64, true)),
+            _ => Err(InterpError::InvalidOperand("Expected an immediate".to_owned()))
+        }
+    }
+
+    // Value of an immediate for an operation of the given size, sign extended from that size
+    //   (`$0xffffffff` and `$-1` are the same `long`, and can both take the short forms)
+    fn immediate(&self, arg: &Argument, size: Size) -> Result<(i64, bool), InterpError> {
+        let (val, label) = self.immediate_value(arg)?;
+        Ok((fit_immediate(val, label, size)?, label))
+    }
+
+    // Immediates are at most 4 bytes (64bit operations sign extend them, except for `movabs`)
+    fn immediate_bytes(&mut self, val: i64, label: bool, size: Size) -> Result<(), InterpError> {
+        let val = fit_immediate(val, label, size)?;
+        let len = size.bytes().min(4);
+        self.imm.extend(&val.to_le_bytes()[..len]);
+        Ok(())
+    }
+
+    fn label(&self, lbl: &str) -> Result<u64, InterpError> {
+        (self.resolve)(lbl).ok_or_else(|| InterpError::UndefinedLabel(lbl.to_string()))
+    }
+}
+
+// Condition codes in encoding order (the low nibble of `jcc`/`setcc`/`cmovcc`)
+fn condition_code(cc: &str) -> Result<u8, InterpError> {
+    let code = match cc {
+        "o" => 0x0,
+        "no" => 0x1,
+        "b" | "c" | "nae" => 0x2,
+        "ae" | "nb" | "nc" => 0x3,
+        "e" | "z" => 0x4,
+        "ne" | "nz" => 0x5,
+        "be" | "na" => 0x6,
+        "a" | "nbe" => 0x7,
+        "s" => 0x8,
+        "ns" => 0x9,
+        "p" | "pe" => 0xa,
+        "np" | "po" => 0xb,
+        "l" | "nge" => 0xc,
+        "ge" | "nl" => 0xd,
+        "le" | "ng" => 0xe,
+        "g" | "nle" => 0xf,
+        _ => return Err(InterpError::InvalidOperand(format!("Unknown condition code: `{}`", cc)))
+    };
+
+    Ok(code)
+}
+
+// `%al`, `%ax`, `%eax` or `%rax` (which have shorter immediate forms)
+fn is_accumulator(arg: &Argument) -> bool {
+    match *arg {
+        Argument::Reg(ref r) => ximpl::reg_location(r).map(|(idx, off, _)| (idx, off)) == Some((0, 0)),
+        _ => false
+    }
+}
+
+fn fits_i8(val: i64) -> bool {
+    val as i8 as i64 == val
+}
+
+fn fits_i32(val: i64) -> bool {
+    val as i32 as i64 == val
+}
+
+fn mode_bits(mode: Mode) -> usize {
+    8 * mode.stack_size().bytes()
+}
+
+// Immediates have to fit the operand size (either signed or unsigned), 64bit ones are sign extended from 32bits
+fn fit_immediate(val: i64, label: bool, size: Size) -> Result<i64, InterpError> {
+    let what = if label { "Address" } else { "Immediate" };
+    match size {
+        Size::Quad if fits_i32(val) => Ok(val),
+        Size::Quad => Err(InterpError::InvalidOperand(
+            format!("{} {:#x} doesn't fit in a sign extended 32bit immediate", what, val))),
+        _ if val == size.sign_extend(val as u64) || val as u64 == size.truncate(val) => Ok(size.sign_extend(val as u64)),
+        _ => Err(InterpError::InvalidOperand(format!("{} {:#x} doesn't fit in {}bits", what, val, 8 * size.bytes())))
+    }
+}
+
+fn mode_error(what: &str) -> InterpError {
+    InterpError::InvalidOperand(format!("{} is only available in 64bit mode (the x86_64 layout)", what))
+}
+
+fn operand_error(mne: &str, expected: &str) -> InterpError {
+    InterpError::InvalidOperand(format!("Invalid operand to `{}`: expected {}", mne, expected))
+}
+
+#[cfg(test)]
+mod tests {
+    use nom::IResult;
+    use decode::decode;
+    use parse;
+    use super::*;
+
+    fn parse(line: &str) -> Command {
+        match parse::x86_instruction(line) {
+            IResult::Done(_, cmd) => parse::resolve_size(cmd).unwrap(),
+            res => panic!("`{}` doesn't parse: {:?}", line, res)
+        }
+    }
+
+    fn assemble(line: &str, mode: Mode) -> Result<Vec<u8>, InterpError> {
+        encode(&parse(line), 0, mode, &|_| Some(0x1000))
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byt| format!("{:02x}", byt)).collect::<Vec<_>>().join(" ")
+    }
+
+    // Assembles to the given bytes, which decode back to the given text (and the same bytes)
+    fn round_trip(mode: Mode, line: &str, bytes: &str, text: &str) {
+        let enc = assemble(line, mode).unwrap_or_else(|e| panic!("`{}`: {}", line, e));
+        assert_eq!(hex(&enc), bytes, "`{}`", line);
+
+        let (inst, len) = decode(&enc, 0, mode).unwrap_or_else(|e| panic!("`{}`: {}", bytes, e));
+        assert_eq!(len, enc.len(), "`{}`", bytes);
+        assert_eq!(inst.to_string(), text, "`{}`", bytes);
+
+        let again = encode(&inst, 0, mode, &|_| None).unwrap_or_else(|e| panic!("`{}`: {}", text, e));
+        assert_eq!(hex(&again), bytes, "`{}`", text);
+    }
+
+    fn rejected(mode: Mode, line: &str) -> String {
+        match assemble(line, mode) {
+            Ok(bytes) => panic!("`{}` assembled to {}", line, hex(&bytes)),
+            Err(e) => e.to_string()
+        }
+    }
+
+    // (source, bytes as assembled by gas, disassembly)
+    const FORMS_32: &[(&str, &str, &str)] = &[
+        ("movl $5, %eax", "b8 05 00 00 00", "mov $0x5,%eax"),
+        ("movl %eax, %ebx", "89 c3", "mov %eax,%ebx"),
+        ("movl (%eax), %ebx", "8b 18", "mov (%eax),%ebx"),
+        ("movl %ebx, 4(%esp)", "89 5c 24 04", "mov %ebx,0x4(%esp)"),
+        ("movl 8(%ebp), %ecx", "8b 4d 08", "mov 0x8(%ebp),%ecx"),
+        ("movl (%ebp), %ecx", "8b 4d 00", "mov (%ebp),%ecx"),
+        ("movl -300(%ebp,%esi,4), %edx", "8b 94 b5 d4 fe ff ff", "mov -0x12c(%ebp,%esi,4),%edx"),
+        ("movl (,%ecx,4), %eax", "8b 04 8d 00 00 00 00", "mov (,%ecx,4),%eax"),
+        ("movl 0x1000, %eax", "a1 00 10 00 00", "mov 0x1000,%eax"),
+        ("movb $1, %al", "b0 01", "mov $0x1,%al"),
+        ("movb %ah, %bl", "88 e3", "mov %ah,%bl"),
+        ("movw $300, %cx", "66 b9 2c 01", "mov $0x12c,%cx"),
+        ("movw %ax, (%ebx)", "66 89 03", "mov %ax,(%ebx)"),
+        ("movl $-1, (%eax)", "c7 00 ff ff ff ff", "movl $-0x1,(%eax)"),
+        ("movb $7, 3(%eax)", "c6 40 03 07", "movb $0x7,0x3(%eax)"),
+        ("addl $1, %eax", "83 c0 01", "add $0x1,%eax"),
+        ("addl $1000, %eax", "05 e8 03 00 00", "add $0x3e8,%eax"),
+        ("addl $1000, %ebx", "81 c3 e8 03 00 00", "add $0x3e8,%ebx"),
+        ("addb $5, %al", "04 05", "add $0x5,%al"),
+        ("addb $5, %bl", "80 c3 05", "add $0x5,%bl"),
+        ("subl %eax, %ebx", "29 c3", "sub %eax,%ebx"),
+        ("subl (%ecx), %edx", "2b 11", "sub (%ecx),%edx"),
+        ("andl $0xff, %eax", "25 ff 00 00 00", "and $0xff,%eax"),
+        ("orl %ecx, (%edx)", "09 0a", "or %ecx,(%edx)"),
+        ("xorl %eax, %eax", "31 c0", "xor %eax,%eax"),
+        ("cmpl $0, %eax", "83 f8 00", "cmp $0x0,%eax"),
+        ("cmpb $10, (%esi)", "80 3e 0a", "cmpb $0xa,(%esi)"),
+        ("testl %eax, %eax", "85 c0", "test %eax,%eax"),
+        ("testl $1, %ebx", "f7 c3 01 00 00 00", "test $0x1,%ebx"),
+        ("testb $1, %al", "a8 01", "test $0x1,%al"),
+        ("testl %eax, (%ebx)", "85 03", "test %eax,(%ebx)"),
+        ("lea 4(%eax,%ebx,2), %ecx", "8d 4c 58 04", "lea 0x4(%eax,%ebx,2),%ecx"),
+        ("leaw (%eax), %cx", "66 8d 08", "lea (%eax),%cx"),
+        ("push %ebp", "55", "push %ebp"),
+        ("pushl $4", "6a 04", "pushl $0x4"),
+        ("pushl $1000", "68 e8 03 00 00", "pushl $0x3e8"),
+        ("pushl (%eax)", "ff 30", "pushl (%eax)"),
+        ("pop %ebx", "5b", "pop %ebx"),
+        ("popl (%ecx)", "8f 01", "popl (%ecx)"),
+        ("incl %eax", "40", "inc %eax"),
+        ("incb (%eax)", "fe 00", "incb (%eax)"),
+        ("decw %dx", "66 4a", "dec %dx"),
+        ("negl %ecx", "f7 d9", "neg %ecx"),
+        ("notl %eax", "f7 d0", "not %eax"),
+        ("mull %ebx", "f7 e3", "mul %ebx"),
+        ("imull %ecx", "f7 e9", "imul %ecx"),
+        ("divl (%eax)", "f7 30", "divl (%eax)"),
+        ("idivb %bl", "f6 fb", "idiv %bl"),
+        ("imull %ebx, %ecx", "0f af cb", "imul %ebx,%ecx"),
+        ("imull $3, %ecx", "6b c9 03", "imul $0x3,%ecx,%ecx"),
+        ("imull $300, %ecx, %edx", "69 d1 2c 01 00 00", "imul $0x12c,%ecx,%edx"),
+        ("imull $3, (%eax), %edx", "6b 10 03", "imul $0x3,(%eax),%edx"),
+        ("shll %eax", "d1 e0", "shl %eax"),
+        ("shll $3, %eax", "c1 e0 03", "shl $0x3,%eax"),
+        ("sarl %cl, %edx", "d3 fa", "sar %cl,%edx"),
+        ("rolb $1, %al", "d0 c0", "rol %al"),
+        ("rcrw $2, %bx", "66 c1 db 02", "rcr $0x2,%bx"),
+        ("shrl $31, (%eax)", "c1 28 1f", "shrl $0x1f,(%eax)"),
+        ("shldl $4, %ebx, %eax", "0f a4 d8 04", "shld $0x4,%ebx,%eax"),
+        ("shrdl %cl, %ebx, %eax", "0f ad d8", "shrd %cl,%ebx,%eax"),
+        ("sete %al", "0f 94 c0", "sete %al"),
+        ("setne (%ebx)", "0f 95 03", "setne (%ebx)"),
+        ("setg %bh", "0f 9f c7", "setg %bh"),
+        ("cmovel %ebx, %eax", "0f 44 c3", "cmove %ebx,%eax"),
+        ("cmovgw %ax, %bx", "66 0f 4f d8", "cmovg %ax,%bx"),
+        ("cmovll (%eax), %edx", "0f 4c 10", "cmovl (%eax),%edx"),
+        ("movzbl %al, %eax", "0f b6 c0", "movzbl %al,%eax"),
+        ("movzbw (%eax), %bx", "66 0f b6 18", "movzbw (%eax),%bx"),
+        ("movzwl %ax, %ecx", "0f b7 c8", "movzwl %ax,%ecx"),
+        ("movsbl %bl, %edx", "0f be d3", "movsbl %bl,%edx"),
+        ("movswl (%eax), %eax", "0f bf 00", "movswl (%eax),%eax"),
+        ("cbtw", "66 98", "cbtw"),
+        ("cwtl", "98", "cwtl"),
+        ("cwtd", "66 99", "cwtd"),
+        ("cltd", "99", "cltd"),
+        ("leave", "c9", "leave"),
+        ("ret", "c3", "ret"),
+        ("ret $8", "c2 08 00", "ret $0x8"),
+        ("enter $16, $0", "c8 10 00 00", "enter $0x10,$0x0"),
+        ("jmp *%eax", "ff e0", "jmp *%eax"),
+        ("jmp *(%eax)", "ff 20", "jmpl *(%eax)"),
+        ("call *%ebx", "ff d3", "call *%ebx"),
+        ("call *8(%ebx,%ecx,4)", "ff 54 8b 08", "calll *0x8(%ebx,%ecx,4)"),
+        ("movl %esp, %ebp", "89 e5", "mov %esp,%ebp"),
+        ("movl (%esp), %eax", "8b 04 24", "mov (%esp),%eax"),
+        ("movl %eax, (%esp,%ebx,1)", "89 04 1c", "mov %eax,(%esp,%ebx,1)"),
+        ("movl %eax, 0x2000", "a3 00 20 00 00", "mov %eax,0x2000"),
+        ("movb 0x10, %al", "a0 10 00 00 00", "mov 0x10,%al"),
+        ("movw %ax, 0x10", "66 a3 10 00 00 00", "mov %ax,0x10"),
+        ("movl 0x10(%ebx), %eax", "8b 43 10", "mov 0x10(%ebx),%eax"),
+        ("incw %ax", "66 40", "inc %ax"),
+        ("decl %edi", "4f", "dec %edi"),
+        ("movl $1, %eax", "b8 01 00 00 00", "mov $0x1,%eax"),
+        ("pushl %eax", "50", "push %eax"),
+        ("adcl %ebx, %eax", "11 d8", "adc %ebx,%eax"),
+        ("adcl $5, %eax", "83 d0 05", "adc $0x5,%eax"),
+        ("adcl $0x1000, (%ebx)", "81 13 00 10 00 00", "adcl $0x1000,(%ebx)"),
+        ("sbbl (%esi), %edi", "1b 3e", "sbb (%esi),%edi"),
+        ("sbbw $-2, %ax", "66 83 d8 fe", "sbb $-0x2,%ax"),
+        ("xchg %ebx, %eax", "93", "xchg %ebx,%eax"),
+        ("xchgb %al, %bl", "86 c3", "xchg %al,%bl"),
+        ("xchg %edx, 4(%esi)", "87 56 04", "xchg %edx,0x4(%esi)"),
+        ("notb (%ebx)", "f6 13", "notb (%ebx)"),
+        ("int $0x80", "cd 80", "int $0x80"),
+        ("nop", "90", "nop"),
+        ("endbr32", "f3 0f 1e fb", "endbr32")
+    ];
+
+    const FORMS_64: &[(&str, &str, &str)] = &[
+        ("movq $1, %rax", "48 c7 c0 01 00 00 00", "mov $0x1,%rax"),
+        ("movq $-1, %r8", "49 c7 c0 ff ff ff ff", "mov $-0x1,%r8"),
+        ("movl $5, %eax", "b8 05 00 00 00", "mov $0x5,%eax"),
+        ("movl $5, %r9d", "41 b9 05 00 00 00", "mov $0x5,%r9d"),
+        ("movq %rax, %rbx", "48 89 c3", "mov %rax,%rbx"),
+        ("movq %r8, %r15", "4d 89 c7", "mov %r8,%r15"),
+        ("movq (%rax), %rbx", "48 8b 18", "mov (%rax),%rbx"),
+        ("movq 8(%rsp), %rax", "48 8b 44 24 08", "mov 0x8(%rsp),%rax"),
+        ("movq (%r12), %rax", "49 8b 04 24", "mov (%r12),%rax"),
+        ("movq (%r13), %rax", "49 8b 45 00", "mov (%r13),%rax"),
+        ("movq 16(%rbp,%r9,8), %r10", "4e 8b 54 cd 10", "mov 0x10(%rbp,%r9,8),%r10"),
+        ("movl (%eax), %ebx", "67 8b 18", "mov (%eax),%ebx"),
+        ("movq 0x1000, %rax", "48 8b 04 25 00 10 00 00", "mov 0x1000,%rax"),
+        ("movl %eax, 0x1000", "89 04 25 00 10 00 00", "mov %eax,0x1000"),
+        ("movb %sil, %dil", "40 88 f7", "mov %sil,%dil"),
+        ("movb %ah, %al", "88 e0", "mov %ah,%al"),
+        ("movb $1, %r8b", "41 b0 01", "mov $0x1,%r8b"),
+        ("movw %ax, %r9w", "66 41 89 c1", "mov %ax,%r9w"),
+        ("addq $1, %rax", "48 83 c0 01", "add $0x1,%rax"),
+        ("addq $1000, %rax", "48 05 e8 03 00 00", "add $0x3e8,%rax"),
+        ("addq $1000, %rbx", "48 81 c3 e8 03 00 00", "add $0x3e8,%rbx"),
+        ("addl %r8d, %r9d", "45 01 c1", "add %r8d,%r9d"),
+        ("subq (%rdi), %rsi", "48 2b 37", "sub (%rdi),%rsi"),
+        ("xorl %eax, %eax", "31 c0", "xor %eax,%eax"),
+        ("cmpq $0, (%rsp)", "48 83 3c 24 00", "cmpq $0x0,(%rsp)"),
+        ("testq %rax, %rax", "48 85 c0", "test %rax,%rax"),
+        ("testb $1, %r10b", "41 f6 c2 01", "test $0x1,%r10b"),
+        ("lea 8(%rip), %rax", "48 8d 05 08 00 00 00", "lea 0x8(%rip),%rax"),
+        ("lea (%rdi,%rsi,4), %rax", "48 8d 04 b7", "lea (%rdi,%rsi,4),%rax"),
+        ("leal (%edi,%esi), %eax", "67 8d 04 37", "lea (%edi,%esi,1),%eax"),
+        ("push %rbp", "55", "push %rbp"),
+        ("push %r12", "41 54", "push %r12"),
+        ("pushq $1", "6a 01", "pushq $0x1"),
+        ("pushq (%rax)", "ff 30", "pushq (%rax)"),
+        ("pop %rbp", "5d", "pop %rbp"),
+        ("pop %r15", "41 5f", "pop %r15"),
+        ("popq 8(%rsp)", "8f 44 24 08", "popq 0x8(%rsp)"),
+        ("incq %rax", "48 ff c0", "inc %rax"),
+        ("incl %eax", "ff c0", "inc %eax"),
+        ("decq (%rax)", "48 ff 08", "decq (%rax)"),
+        ("negq %r11", "49 f7 db", "neg %r11"),
+        ("mulq %rbx", "48 f7 e3", "mul %rbx"),
+        ("imulq %r8, %r9", "4d 0f af c8", "imul %r8,%r9"),
+        ("imulq $7, %rax, %rbx", "48 6b d8 07", "imul $0x7,%rax,%rbx"),
+        ("idivq (%rcx)", "48 f7 39", "idivq (%rcx)"),
+        ("shlq $4, %rax", "48 c1 e0 04", "shl $0x4,%rax"),
+        ("sarq %cl, %r8", "49 d3 f8", "sar %cl,%r8"),
+        ("rolq %rax", "48 d1 c0", "rol %rax"),
+        ("shldq $3, %rbx, %rax", "48 0f a4 d8 03", "shld $0x3,%rbx,%rax"),
+        ("setl %r9b", "41 0f 9c c1", "setl %r9b"),
+        ("setb %sil", "40 0f 92 c6", "setb %sil"),
+        ("cmovneq %rax, %rbx", "48 0f 45 d8", "cmovne %rax,%rbx"),
+        ("movzbl %sil, %eax", "40 0f b6 c6", "movzbl %sil,%eax"),
+        ("movzbq %al, %rax", "48 0f b6 c0", "movzbq %al,%rax"),
+        ("movzwq %ax, %r8", "4c 0f b7 c0", "movzwq %ax,%r8"),
+        ("movsbq (%rax), %rdx", "48 0f be 10", "movsbq (%rax),%rdx"),
+        ("movslq %eax, %rdx", "48 63 d0", "movslq %eax,%rdx"),
+        ("movslq (%rdi), %r8", "4c 63 07", "movslq (%rdi),%r8"),
+        ("cltq", "48 98", "cltq"),
+        ("cqto", "48 99", "cqto"),
+        ("cwtl", "98", "cwtl"),
+        ("leave", "c9", "leave"),
+        ("ret", "c3", "ret"),
+        ("ret $16", "c2 10 00", "ret $0x10"),
+        ("enter $32, $0", "c8 20 00 00", "enter $0x20,$0x0"),
+        ("jmp *%rax", "ff e0", "jmp *%rax"),
+        ("jmp *(%rax)", "ff 20", "jmpq *(%rax)"),
+        ("call *%r11", "41 ff d3", "call *%r11"),
+        ("call *8(%rax,%rbx,8)", "ff 54 d8 08", "callq *0x8(%rax,%rbx,8)"),
+        ("movq %rsp, %rbp", "48 89 e5", "mov %rsp,%rbp"),
+        ("adcq %rbx, %rax", "48 11 d8", "adc %rbx,%rax"),
+        ("sbbq $1, %r9", "49 83 d9 01", "sbb $0x1,%r9"),
+        ("xchg %rax, %r8", "49 90", "xchg %r8,%rax"),
+        ("xchgl %eax, %eax", "87 c0", "xchg %eax,%eax"),
+        ("xchg %r10, %r11", "4d 87 d3", "xchg %r10,%r11"),
+        ("notq %r12", "49 f7 d4", "not %r12"),
+        ("syscall", "0f 05", "syscall"),
+        ("endbr64", "f3 0f 1e fa", "endbr64"),
+        ("movq $0xffffffff, %rax", "48 b8 ff ff ff ff 00 00 00 00", "movabs $0xffffffff,%rax"),
+        ("movabsq $0x123456789abcdef0, %r9", "49 b9 f0 de bc 9a 78 56 34 12", "movabs $0x123456789abcdef0,%r9")
+    ];
+
+    #[test]
+    fn round_trips_32() {
+        for &(line, bytes, text) in FORMS_32.iter() {
+            round_trip(Mode::Bits32, line, bytes, text);
+        }
+    }
+
+    #[test]
+    fn round_trips_64() {
+        for &(line, bytes, text) in FORMS_64.iter() {
+            round_trip(Mode::Bits64, line, bytes, text);
+        }
+    }
+
+    // Labels resolve to 0x1000 (jumps are relative to the end of the instruction at 0)
+    #[test]
+    fn jumps_and_pseudo_instructions() {
+        round_trip(Mode::Bits32, "jmp target", "e9 fb 0f 00 00", "jmp 0x1000");
+        round_trip(Mode::Bits32, "call target", "e8 fb 0f 00 00", "call 0x1000");
+        round_trip(Mode::Bits64, "jne target", "0f 85 fa 0f 00 00", "jne 0x1000");
+        round_trip(Mode::Bits32, "movl $target, %eax", "b8 00 10 00 00", "mov $0x1000,%eax");
+        round_trip(Mode::Bits32, "exit", "f4", "exit");
+        round_trip(Mode::Bits64, "dump", "cc", "dump");
+    }
+
+    #[test]
+    fn wide_immediates() {
+        let mode = Mode::Bits64;
+        round_trip(mode, "movabsq $1, %rcx", "48 b9 01 00 00 00 00 00 00 00", "movabs $0x1,%rcx");
+        round_trip(mode, "movq $-1, %rdx", "48 c7 c2 ff ff ff ff", "mov $-0x1,%rdx");
+        round_trip(mode, "movl $0xffffffff, %esi", "be ff ff ff ff", "mov $-0x1,%esi");
+        round_trip(mode, "addl $0xffffffff, %esi", "83 c6 ff", "add $-0x1,%esi");
+        round_trip(mode, "andw $0xff00, %bx", "66 81 e3 00 ff", "and $-0x100,%bx");
+    }
+
+    #[test]
+    fn immediates_must_fit() {
+        assert!(rejected(Mode::Bits64, "addq $0xffffffff, %rax").contains("sign extended 32bit"));
+        assert!(rejected(Mode::Bits64, "movq $0x100000000, (%rax)").contains("sign extended 32bit"));
+        assert!(rejected(Mode::Bits32, "movb $300, %al").contains("8bits"));
+        assert!(rejected(Mode::Bits32, "movw $-40000, %ax").contains("16bits"));
+        assert!(rejected(Mode::Bits64, "movabs $1, %eax").contains("64bit register"));
+        assert!(rejected(Mode::Bits32, "int $256").contains("interrupt number"));
+    }
+}
@@ -0,0 +1,46 @@
+use std::error;
+use std::fmt;
+
+// Everything that can stop the interpretation of a program
+#[derive(Debug, Clone, PartialEq)]
+pub enum InterpError {
+    Parse(String),                  // source text that isn't a valid instruction
+    UnknownMnemonic(String),        // mnemonic
+    InvalidOperand(String),         // description of the bad operand combination
+    UnknownRegister(String),        // register name
+    MemoryFault(i64, usize),        // address, access length
+    DivideError(String),            // cause of the #DE fault
+    UndefinedLabel(String),         // label
+    Unimplemented(String),          // instruction/feature
+    // Any of the above, raised by the instruction on the given (1-based) source line
+    Located(usize, String, Box<InterpError>)
+}
+
+impl InterpError {
+    // Attach the source position of the failing instruction
+    pub fn at(self, line: usize, inst: &str) -> InterpError {
+        match self {
+            err @ InterpError::Located(..) => err,
+            err => InterpError::Located(line, inst.trim().to_string(), Box::new(err))
+        }
+    }
+}
+
+impl fmt::Display for InterpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            InterpError::Parse(ref inst) => write!(f, "Invalid x86 instruction string: {}", inst),
+            InterpError::UnknownMnemonic(ref mne) => write!(f, "Unknown instruction: `{}`", mne),
+            InterpError::InvalidOperand(ref msg) => write!(f, "{}", msg),
+            InterpError::UnknownRegister(ref reg) => write!(f, "Unsupported register: `%{}`", reg),
+            InterpError::MemoryFault(addr, len) =>
+                write!(f, "Memory fault: {} byte access at address {:#x} is outside of mapped memory", len, addr),
+            InterpError::DivideError(ref cause) => write!(f, "Divide error (#DE): {}", cause),
+            InterpError::UndefinedLabel(ref lbl) => write!(f, "Undefined label: `{}`", lbl),
+            InterpError::Unimplemented(ref inst) => write!(f, "Unimplemented {}", inst),
+            InterpError::Located(line, ref inst, ref err) => write!(f, "Line {} (`{}`): {}", line, inst, err)
+        }
+    }
+}
+
+impl error::Error for InterpError {}
@@ -0,0 +1,523 @@
+use libc::{c_char, c_int, size_t};
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::fs::File;
+use std::io::BufReader;
+use std::io::prelude::*;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+use emu::Emulator;
+use host::BufferIo;
+use memory::{Layout, DEFAULT_STACK_SIZE};
+use error::InterpError;
+use x86::{interpret_code, interpret_iter};
+use ximpl::{Size, Syntax};
+
+// C interface to the interpreter (see `include/x86_interpreter.h`)
+//   Every function returns one of the status codes below and never unwinds into C
+
+// `///` comments are copied into the generated header
+
+/// Status codes returned by every function (errors are negative)
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum X86Status {
+    /// The call succeeded
+    Ok = 0,
+    /// `x86_emulator_step`: the program has finished
+    Halted = 1,
+    /// A null pointer, invalid utf-8 string or unknown syntax was passed in
+    InvalidArgument = -1,
+    Parse = -2,
+    UnknownMnemonic = -3,
+    InvalidOperand = -4,
+    UnknownRegister = -5,
+    MemoryFault = -6,
+    /// Division by zero or a quotient that doesn't fit (#DE)
+    Divide = -7,
+    UndefinedLabel = -8,
+    Unimplemented = -9,
+    /// The file given to `interpret_file` couldn't be read
+    Io = -10,
+    /// The interpreter hit an internal error (the handle should be freed)
+    Panic = -11
+}
+
+/// AT&T syntax (`movl $4, %eax`)
+pub const X86_SYNTAX_ATT: c_int = 0;
+/// Intel syntax (`mov eax, 4`)
+pub const X86_SYNTAX_INTEL: c_int = 1;
+
+/// 32bit Linux memory layout (text at 0x08048000, stack below 0xc0000000)
+pub const X86_LAYOUT_I386: c_int = 0;
+/// 64bit Linux memory layout (text at 0x400000, stack below 0x7ffffffff000)
+pub const X86_LAYOUT_X86_64: c_int = 1;
+
+/// The program's standard output (see `x86_emulator_output`)
+pub const X86_STREAM_STDOUT: c_int = 1;
+/// The program's standard error
+pub const X86_STREAM_STDERR: c_int = 2;
+/// The emulator's own output (the instruction trace and register dumps)
+pub const X86_STREAM_LOG: c_int = 3;
+
+/// Opaque emulator handle (created by `x86_emulator_new`, released by `x86_emulator_free`)
+pub struct X86Emulator {
+    emu: Emulator,
+    // Message of the most recent failed call
+    last_error: Option<CString>,
+    // The buffers the emulator writes to after `x86_emulator_capture_io`
+    captured: Option<BufferIo>
+}
+
+type FfiResult = Result<X86Status, (X86Status, String)>;
+
+thread_local! {
+    // Message of the most recent failed one-shot call (`interpret_file`/`interpret_string`) on this thread
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn error_code(err: &InterpError) -> X86Status {
+    match *err {
+        InterpError::Parse(_) => X86Status::Parse,
+        InterpError::UnknownMnemonic(_) => X86Status::UnknownMnemonic,
+        InterpError::InvalidOperand(_) => X86Status::InvalidOperand,
+        InterpError::UnknownRegister(_) => X86Status::UnknownRegister,
+        InterpError::MemoryFault(..) => X86Status::MemoryFault,
+        InterpError::DivideError(_) => X86Status::Divide,
+        InterpError::UndefinedLabel(_) => X86Status::UndefinedLabel,
+        InterpError::Unimplemented(_) => X86Status::Unimplemented,
+        InterpError::Located(_, _, ref err) => error_code(err)
+    }
+}
+
+fn interp_error(err: InterpError) -> (X86Status, String) {
+    (error_code(&err), err.to_string())
+}
+
+fn invalid_argument(msg: &str) -> (X86Status, String) {
+    (X86Status::InvalidArgument, msg.to_string())
+}
+
+// Run `f` on the handle's emulator, remembering any error (or panic) it raises
+unsafe fn with_handle<F>(handle: *mut X86Emulator, f: F) -> X86Status
+    where F: FnOnce(&mut Emulator) -> FfiResult
+{
+    let handle = match handle.as_mut() {
+        Some(handle) => handle,
+        None => return X86Status::InvalidArgument
+    };
+
+    let emu = &mut handle.emu;
+    let (code, msg) = match panic::catch_unwind(AssertUnwindSafe(|| f(emu))) {
+        Ok(Ok(code)) => return code,
+        Ok(Err(err)) => err,
+        Err(_) => (X86Status::Panic, "Internal interpreter error (panic)".to_string())
+    };
+
+    handle.last_error = CString::new(msg).ok();
+    code
+}
+
+unsafe fn c_str<'a>(c_str: *const c_char) -> Result<&'a str, (X86Status, String)> {
+    if c_str.is_null() {
+        return Err(invalid_argument("Unexpected null string"));
+    }
+
+    CStr::from_ptr(c_str).to_str().map_err(|_| invalid_argument("Strings must be valid utf-8"))
+}
+
+// Null terminated array of strings (null is an empty array)
+unsafe fn c_str_array(arr: *const *const c_char) -> Result<Vec<String>, (X86Status, String)> {
+    let mut strs = Vec::new();
+    if arr.is_null() {
+        return Ok(strs);
+    }
+
+    let mut idx = 0;
+    while !(*arr.add(idx)).is_null() {
+        strs.push(c_str(*arr.add(idx))?.to_string());
+        idx += 1;
+    }
+    Ok(strs)
+}
+
+
+// Handle management
+/// Create an emulator with the 32bit layout and default stack size (see `x86_emulator_new_with_layout`)
+#[no_mangle]
+pub extern "C" fn x86_emulator_new() -> *mut X86Emulator {
+    new_handle(Emulator::new)
+}
+
+/// Create an emulator with one of the `X86_LAYOUT_*` memory layouts and a `stack_size` byte stack
+/// Returns null if the emulator couldn't be created (or the layout is unknown)
+#[no_mangle]
+pub extern "C" fn x86_emulator_new_with_layout(layout: c_int, stack_size: u64) -> *mut X86Emulator {
+    let layout = match layout {
+        X86_LAYOUT_I386 => Layout::I386,
+        X86_LAYOUT_X86_64 => Layout::X86_64,
+        _ => return ptr::null_mut()
+    };
+
+    new_handle(|| Emulator::with_layout(layout, stack_size))
+}
+
+fn new_handle<F: FnOnce() -> Emulator + panic::UnwindSafe>(new: F) -> *mut X86Emulator {
+    let handle = panic::catch_unwind(|| Box::new(X86Emulator{ emu: new(), last_error: None, captured: None }));
+
+    match handle {
+        Ok(handle) => Box::into_raw(handle),
+        Err(_) => ptr::null_mut()
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn x86_emulator_free(handle: *mut X86Emulator) {
+    if !handle.is_null() {
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| drop(Box::from_raw(handle))));
+    }
+}
+
+/// Message of the most recent failed call on the handle (or null if no call has failed)
+/// The string lives until the next failure or `x86_emulator_free`
+#[no_mangle]
+pub unsafe extern "C" fn x86_emulator_last_error(handle: *const X86Emulator) -> *const c_char {
+    match handle.as_ref().and_then(|handle| handle.last_error.as_ref()) {
+        Some(msg) => msg.as_ptr(),
+        None => ptr::null()
+    }
+}
+
+
+// Program execution
+/// Load a program (`source` holds newline separated lines), registers are kept but the data sections are rewritten
+#[no_mangle]
+pub unsafe extern "C" fn x86_emulator_load(handle: *mut X86Emulator, source: *const c_char, syntax: c_int) -> X86Status {
+    with_handle(handle, |emu| {
+        let source = c_str(source)?;
+        let syntax = match syntax {
+            X86_SYNTAX_ATT => Syntax::ATT,
+            X86_SYNTAX_INTEL => Syntax::Intel,
+            _ => return Err(invalid_argument("Unknown syntax"))
+        };
+
+        emu.load(source.split('\n').map(|s| s.to_owned()), syntax).map_err(interp_error)?;
+        Ok(X86Status::Ok)
+    })
+}
+
+/// Load raw machine code at the start of the text region, the program ends when it runs off the end of the code
+#[no_mangle]
+pub unsafe extern "C" fn x86_emulator_load_bytes(handle: *mut X86Emulator, code: *const u8, len: size_t) -> X86Status {
+    with_handle(handle, |emu| {
+        let code = match len {
+            0 => &[][..],
+            _ if code.is_null() => return Err(invalid_argument("Unexpected null code pointer")),
+            _ => slice::from_raw_parts(code, len)
+        };
+
+        emu.loadBytes(code).map_err(interp_error)?;
+        Ok(X86Status::Ok)
+    })
+}
+
+/// Load a statically linked ELF executable, `argv` (including the program name) and `envp` are null terminated
+/// arrays (or null). The memory layout is replaced to match the executable and the registers are cleared
+#[no_mangle]
+pub unsafe extern "C" fn x86_emulator_load_elf(handle: *mut X86Emulator, image: *const u8, len: size_t,
+                                               argv: *const *const c_char, envp: *const *const c_char) -> X86Status {
+    with_handle(handle, |emu| {
+        if image.is_null() {
+            return Err(invalid_argument("Unexpected null image"));
+        }
+
+        let (args, env) = (c_str_array(argv)?, c_str_array(envp)?);
+        emu.loadElf(slice::from_raw_parts(image, len), &args, &env).map_err(interp_error)?;
+        Ok(X86Status::Ok)
+    })
+}
+
+/// Execute the next instruction, returns `X86_STATUS_HALTED` once the program has finished
+#[no_mangle]
+pub unsafe extern "C" fn x86_emulator_step(handle: *mut X86Emulator) -> X86Status {
+    with_handle(handle, |emu| {
+        match emu.step() {
+            Ok(true) => Ok(X86Status::Ok),
+            Ok(false) => Ok(X86Status::Halted),
+            Err(e) => Err(interp_error(e))
+        }
+    })
+}
+
+/// Run the program until it exits or faults
+#[no_mangle]
+pub unsafe extern "C" fn x86_emulator_run(handle: *mut X86Emulator) -> X86Status {
+    with_handle(handle, |emu| {
+        emu.execute().map_err(interp_error)?;
+        Ok(X86Status::Ok)
+    })
+}
+
+/// Exit status the program passed to the `exit`/`exit_group` system call (0 if it didn't make one)
+#[no_mangle]
+pub unsafe extern "C" fn x86_emulator_exit_status(handle: *mut X86Emulator, status: *mut c_int) -> X86Status {
+    with_handle(handle, |emu| {
+        if status.is_null() {
+            return Err(invalid_argument("Unexpected null status pointer"));
+        }
+
+        *status = emu.getExitStatus();
+        Ok(X86Status::Ok)
+    })
+}
+
+
+// Register and memory access
+/// Registers are named without the `%` (ie. "eax" or "r8w")
+#[no_mangle]
+pub unsafe extern "C" fn x86_emulator_read_register(handle: *mut X86Emulator, name: *const c_char, value: *mut u64) -> X86Status {
+    with_handle(handle, |emu| {
+        let name = c_str(name)?;
+        if value.is_null() {
+            return Err(invalid_argument("Unexpected null value pointer"));
+        }
+
+        *value = emu.getReg(name).map_err(interp_error)?.get_unsigned();
+        Ok(X86Status::Ok)
+    })
+}
+
+/// The value has to fit in the register (as either a signed or unsigned value)
+#[no_mangle]
+pub unsafe extern "C" fn x86_emulator_write_register(handle: *mut X86Emulator, name: *const c_char, value: u64) -> X86Status {
+    with_handle(handle, |emu| {
+        let name = c_str(name)?;
+        let mut reg = emu.getReg(name).map_err(interp_error)?;
+        reg.set(value as i64).map_err(interp_error)?;
+        Ok(X86Status::Ok)
+    })
+}
+
+/// Memory accesses stop at the first byte that is out of bounds
+#[no_mangle]
+pub unsafe extern "C" fn x86_emulator_read_memory(handle: *mut X86Emulator, addr: u64, buf: *mut u8, len: size_t) -> X86Status {
+    with_handle(handle, |emu| {
+        if len == 0 {
+            return Ok(X86Status::Ok);
+        } else if buf.is_null() {
+            return Err(invalid_argument("Unexpected null buffer"));
+        }
+
+        for (i, byt) in slice::from_raw_parts_mut(buf, len).iter_mut().enumerate() {
+            let loc = (addr as i64).wrapping_add(i as i64);
+            *byt = emu.getMemory(loc, Size::Byte).map_err(interp_error)?.get() as u8;
+        }
+        Ok(X86Status::Ok)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn x86_emulator_write_memory(handle: *mut X86Emulator, addr: u64, buf: *const u8, len: size_t) -> X86Status {
+    with_handle(handle, |emu| {
+        if len == 0 {
+            return Ok(X86Status::Ok);
+        } else if buf.is_null() {
+            return Err(invalid_argument("Unexpected null buffer"));
+        }
+
+        for (i, &byt) in slice::from_raw_parts(buf, len).iter().enumerate() {
+            let loc = (addr as i64).wrapping_add(i as i64);
+            emu.getMemory(loc, Size::Byte).map_err(interp_error)?.set(byt).map_err(interp_error)?;
+        }
+        Ok(X86Status::Ok)
+    })
+}
+
+/// Disassemble the instructions starting within `[addr, addr + len)` into `buf` (one per line, nul terminated)
+/// Lines look like `0x08048000:  b8 04 00 00 00                mov $0x4,%eax`, fails if `buf_len` is too small
+#[no_mangle]
+pub unsafe extern "C" fn x86_emulator_disassemble(handle: *mut X86Emulator, addr: u64, len: u64, buf: *mut c_char, buf_len: size_t) -> X86Status {
+    with_handle(handle, |emu| {
+        if buf.is_null() {
+            return Err(invalid_argument("Unexpected null buffer"));
+        }
+
+        let listing = emu.disassemble(addr, len).map_err(interp_error)?;
+        let text = listing.iter().map(|inst| format!("{}\n", inst)).collect::<String>();
+        copy_text(&text, buf, buf_len, "listing")
+    })
+}
+
+/// Look up the bounds of a memory region ("text", "rodata", "data", "bss", "heap" or "stack")
+#[no_mangle]
+pub unsafe extern "C" fn x86_emulator_region(handle: *mut X86Emulator, name: *const c_char, start: *mut u64, size: *mut u64) -> X86Status {
+    with_handle(handle, |emu| {
+        let name = c_str(name)?;
+        if start.is_null() || size.is_null() {
+            return Err(invalid_argument("Unexpected null start/size pointer"));
+        }
+
+        match emu.getRegion(name) {
+            Some(region) => {
+                *start = region.start;
+                *size = region.size();
+                Ok(X86Status::Ok)
+            },
+            None => Err(invalid_argument(&format!("Unknown memory region: `{}`", name)))
+        }
+    })
+}
+
+
+// Program input/output
+/// Capture the program's output (and the emulator's own output) instead of printing it, with the `len` bytes
+/// at `input` as its standard input. The captured text is read with `x86_emulator_output`
+#[no_mangle]
+pub unsafe extern "C" fn x86_emulator_capture_io(handle: *mut X86Emulator, input: *const u8, len: size_t) -> X86Status {
+    let mut captured = None;
+    let status = with_handle(handle, |emu| {
+        let input = match len {
+            0 => &[][..],
+            _ if input.is_null() => return Err(invalid_argument("Unexpected null input pointer")),
+            _ => slice::from_raw_parts(input, len)
+        };
+
+        let io = BufferIo::new(input);
+        captured = Some(io.clone());
+        emu.setIo(Box::new(io));
+        Ok(X86Status::Ok)
+    });
+
+    if let (Some(handle), Some(io)) = (handle.as_mut(), captured) {
+        handle.captured = Some(io);
+    }
+    status
+}
+
+/// Copy everything captured on one of the `X86_STREAM_*` streams into `buf` (nul terminated),
+/// fails if `buf_len` is too small
+#[no_mangle]
+pub unsafe extern "C" fn x86_emulator_output(handle: *mut X86Emulator, stream: c_int, buf: *mut c_char, buf_len: size_t) -> X86Status {
+    let captured = handle.as_ref().and_then(|handle| handle.captured.clone());
+    with_handle(handle, |_| {
+        let io = captured.ok_or_else(|| invalid_argument("The output isn't captured (see `x86_emulator_capture_io`)"))?;
+        if buf.is_null() {
+            return Err(invalid_argument("Unexpected null buffer"));
+        }
+
+        let text = match stream {
+            X86_STREAM_STDOUT => io.stdout(),
+            X86_STREAM_STDERR => io.stderr(),
+            X86_STREAM_LOG => io.log(),
+            _ => return Err(invalid_argument("Unknown stream"))
+        };
+        copy_text(&text, buf, buf_len, "output")
+    })
+}
+
+// Copy `text` into a C buffer of `buf_len` bytes (with the nul terminator)
+unsafe fn copy_text(text: &str, buf: *mut c_char, buf_len: size_t, what: &str) -> FfiResult {
+    if text.len() >= buf_len {
+        return Err(invalid_argument(&format!("The {} needs a {} byte buffer", what, text.len() + 1)));
+    }
+
+    ptr::copy_nonoverlapping(text.as_ptr() as *const c_char, buf, text.len());
+    *buf.add(text.len()) = 0;
+    Ok(X86Status::Ok)
+}
+
+
+// One-shot interpretation
+/// Interpret an AT&T syntax file, the register dump is written to stderr
+/// On failure the message is available through `x86_last_error`
+#[no_mangle]
+pub unsafe extern "C" fn interpret_file(file: *const c_char) -> X86Status {
+    one_shot(|| {
+        let file = c_str(file)?;
+        let lines = File::open(file)
+            .and_then(|f| BufReader::new(f).lines().collect::<Result<Vec<_>, _>>())
+            .map_err(|e| (X86Status::Io, format!("Unable to read `{}`: {}", file, e)))?;
+
+        interpret_iter(lines.into_iter(), Syntax::ATT, Layout::I386, DEFAULT_STACK_SIZE).map_err(interp_error)?;
+        Ok(X86Status::Ok)
+    })
+}
+
+/// Interpret AT&T syntax code, the register dump is written to stderr
+/// On failure the message is available through `x86_last_error`
+#[no_mangle]
+pub unsafe extern "C" fn interpret_string(c_str_ptr: *const c_char) -> X86Status {
+    one_shot(|| {
+        interpret_code(c_str(c_str_ptr)?).map_err(interp_error)?;
+        Ok(X86Status::Ok)
+    })
+}
+
+/// Message of the most recent failed `interpret_file`/`interpret_string` call on this thread (or null)
+/// The string lives until the next failure of either function on the same thread
+#[no_mangle]
+pub extern "C" fn x86_last_error() -> *const c_char {
+    LAST_ERROR.with(|msg| msg.borrow().as_ref().map_or(ptr::null(), |msg| msg.as_ptr()))
+}
+
+// Run a call that has no handle, remembering any error (or panic) for `x86_last_error`
+fn one_shot<F: FnOnce() -> FfiResult>(f: F) -> X86Status {
+    let (code, msg) = match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(code)) => return code,
+        Ok(Err(err)) => err,
+        Err(_) => (X86Status::Panic, "Internal interpreter error (panic)".to_string())
+    };
+
+    LAST_ERROR.with(|last| *last.borrow_mut() = CString::new(msg).ok());
+    code
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::{CStr, CString};
+    use std::ptr;
+    use super::*;
+
+    fn last_error() -> String {
+        let msg = x86_last_error();
+        assert!(!msg.is_null());
+        unsafe { CStr::from_ptr(msg) }.to_str().unwrap().to_owned()
+    }
+
+    #[test]
+    fn one_shot_calls() {
+        assert!(x86_last_error().is_null());
+
+        let code = CString::new("movl $1, %eax\nmovl $0, %ebx\nint $0x80").unwrap();
+        assert_eq!(unsafe { interpret_string(code.as_ptr()) }, X86Status::Ok);
+        assert!(x86_last_error().is_null());
+
+        let code = CString::new("movl $1, %foo").unwrap();
+        assert_eq!(unsafe { interpret_string(code.as_ptr()) }, X86Status::Parse);
+        assert!(last_error().contains("foo"), "{}", last_error());
+
+        assert_eq!(unsafe { interpret_string(ptr::null()) }, X86Status::InvalidArgument);
+        assert_eq!(last_error(), "Unexpected null string");
+
+        let file = CString::new("/nonexistent/prog.s").unwrap();
+        assert_eq!(unsafe { interpret_file(file.as_ptr()) }, X86Status::Io);
+        assert!(last_error().starts_with("Unable to read `/nonexistent/prog.s`"), "{}", last_error());
+    }
+
+    #[test]
+    fn handle_errors() {
+        unsafe {
+            let handle = x86_emulator_new();
+            assert!(x86_emulator_last_error(handle).is_null());
+
+            let code = CString::new("frob %eax").unwrap();
+            assert_eq!(x86_emulator_load(handle, code.as_ptr(), X86_SYNTAX_ATT), X86Status::Parse);
+            assert!(!x86_emulator_last_error(handle).is_null());
+
+            // Handle errors don't touch the one-shot message
+            assert!(x86_last_error().is_null());
+            x86_emulator_free(handle);
+        }
+    }
+}
@@ -0,0 +1,161 @@
+use ximpl::{Flag, Size};
+
+// Arithmetic flags are computed lazily from the last flag-setting operation
+//   Most instructions overwrite the flags before anything reads them,
+//   so this avoids computing all six flags for every `add`/`sub`/etc.
+
+// Intel Manual, Appendix A (EFLAGS Cross-Reference)
+// https://www.intel.com/content/dam/www/public/us/en/documents/manuals/64-ia-32-architectures-software-developer-vol-1-manual.pdf
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Add,                // lhs + rhs
+    Sub,                // lhs - rhs (also `cmp` and `neg` as `0 - rhs`)
+    Adc(bool),          // lhs + rhs + CF (the carry in)
+    Sbb(bool),          // lhs - rhs - CF (the borrow in)
+    Inc,                // lhs + 1, CF is left unchanged
+    Dec,                // lhs - 1, CF is left unchanged
+    Logic,              // and/or/xor/test: CF = OF = 0
+}
+
+// Record of the operation that last defined the arithmetic flags
+#[derive(Debug, Clone, Copy)]
+pub struct Lazy {
+    op: Op,
+    lhs: u64,
+    rhs: u64,
+    res: u64,
+    size: Size,
+}
+
+impl Lazy {
+    pub fn new(op: Op, lhs: i64, rhs: i64, res: i64, size: Size) -> Lazy {
+        Lazy{
+            op,
+            lhs: size.truncate(lhs),
+            rhs: size.truncate(rhs),
+            res: size.truncate(res),
+            size
+        }
+    }
+
+    // Returns `None` if the operation doesn't modify the given flag
+    pub fn get(&self, flag: Flag) -> Option<bool> {
+        match flag {
+            Flag::Carry => self.carry(),
+            Flag::Parity => Some(parity(self.res)),
+            Flag::Adjust => Some(self.adjust()),
+            Flag::Zero => Some(self.res == 0),
+            Flag::Sign => Some(self.size.sign_bit(self.res)),
+            Flag::Overflow => Some(self.overflow()),
+            _ => None
+        }
+    }
+
+    fn carry(&self) -> Option<bool> {
+        match self.op {
+            Op::Add => Some(self.res < self.lhs),
+            Op::Sub => Some(self.lhs < self.rhs),
+            // With a carry in, the result wraps around to `lhs` itself
+            Op::Adc(carry) => Some(self.res < self.lhs || (carry && self.res == self.lhs)),
+            Op::Sbb(borrow) => Some(self.lhs < self.rhs || (borrow && self.lhs == self.rhs)),
+            Op::Inc | Op::Dec => None,
+            Op::Logic => Some(false)
+        }
+    }
+
+    fn overflow(&self) -> bool {
+        let (lhs, rhs, res) = (self.lhs, self.rhs, self.res);
+
+        match self.op {
+            // The operands have the same sign, but the result doesn't
+            Op::Add | Op::Adc(_) | Op::Inc => self.size.sign_bit((lhs ^ res) & (rhs ^ res)),
+            // The operands have different signs, and the result doesn't match `lhs`
+            Op::Sub | Op::Sbb(_) | Op::Dec => self.size.sign_bit((lhs ^ rhs) & (lhs ^ res)),
+            Op::Logic => false
+        }
+    }
+
+    // Carry/borrow out of the low nibble
+    fn adjust(&self) -> bool {
+        match self.op {
+            Op::Logic => false,
+            _ => (self.lhs ^ self.rhs ^ self.res) & 0x10 != 0
+        }
+    }
+}
+
+// Set if the low byte of the result has an even number of set bits
+fn parity(res: u64) -> bool {
+    (res as u8).count_ones().is_multiple_of(2)
+}
+
+// The arithmetic flags that `Lazy` can compute
+pub const ARITHMETIC_FLAGS: [Flag; 6] = [
+    Flag::Carry, Flag::Parity, Flag::Adjust, Flag::Zero, Flag::Sign, Flag::Overflow
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The arithmetic flags as `CPAZSO`, `.` for a cleared flag and `-` for one left unchanged
+    fn flags(op: Op, size: Size, lhs: i64, rhs: i64, res: i64) -> String {
+        let lazy = Lazy::new(op, lhs, rhs, res, size);
+        ARITHMETIC_FLAGS.iter().zip("CPAZSO".chars()).map(|(&flag, name)| match lazy.get(flag) {
+            Some(true) => name,
+            Some(false) => '.',
+            None => '-'
+        }).collect()
+    }
+
+    #[test]
+    fn add() {
+        assert_eq!(flags(Op::Add, Size::Byte, 0x7f, 1, 0x80), "..A.SO");
+        assert_eq!(flags(Op::Add, Size::Byte, 0xff, 1, 0x100), "CPAZ..");
+        assert_eq!(flags(Op::Add, Size::Word, 0x8000, 0x8000, 0x10000), "CP.Z.O");
+        assert_eq!(flags(Op::Add, Size::Long, 0x7fff_ffff, 1, 0x8000_0000), ".PA.SO");
+        assert_eq!(flags(Op::Add, Size::Quad, -1, -1, -2), "C.A.S.");
+    }
+
+    #[test]
+    fn sub() {
+        assert_eq!(flags(Op::Sub, Size::Byte, 0, 1, -1), "CPA.S.");
+        assert_eq!(flags(Op::Sub, Size::Byte, 0x80, 1, 0x7f), "..A..O");
+        assert_eq!(flags(Op::Sub, Size::Word, 5, 5, 0), ".P.Z..");
+        assert_eq!(flags(Op::Sub, Size::Long, 1, 0x8000_0000, 0x8000_0001 - 0x1_0000_0000), "C...SO");
+        assert_eq!(flags(Op::Sub, Size::Quad, i64::MIN, 1, i64::MAX), ".PA..O");
+    }
+
+    #[test]
+    fn inc_and_dec_keep_the_carry() {
+        assert_eq!(flags(Op::Inc, Size::Byte, 0xff, 1, 0x100), "-PAZ..");
+        assert_eq!(flags(Op::Inc, Size::Word, 0x7fff, 1, 0x8000), "-PA.SO");
+        assert_eq!(flags(Op::Inc, Size::Long, 0, 1, 1), "-.....");
+        assert_eq!(flags(Op::Inc, Size::Quad, 0xf, 1, 0x10), "-.A...");
+
+        assert_eq!(flags(Op::Dec, Size::Byte, 0, 1, -1), "-PA.S.");
+        assert_eq!(flags(Op::Dec, Size::Word, 0x8000, 1, 0x7fff), "-PA..O");
+        assert_eq!(flags(Op::Dec, Size::Long, 1, 1, 0), "-P.Z..");
+        assert_eq!(flags(Op::Dec, Size::Quad, i64::MIN, 1, i64::MAX), "-PA..O");
+    }
+
+    #[test]
+    fn logic() {
+        assert_eq!(flags(Op::Logic, Size::Byte, 0xf0, 0x0f, 0), ".P.Z..");
+        assert_eq!(flags(Op::Logic, Size::Word, 0x8000, 1, 0x8001), "....S.");
+        assert_eq!(flags(Op::Logic, Size::Long, 0xff, 0x0f, 0xf0), ".P....");
+        assert_eq!(flags(Op::Logic, Size::Quad, -1, -1, -1), ".P..S.");
+    }
+
+    #[test]
+    fn carry_in() {
+        assert_eq!(flags(Op::Adc(true), Size::Byte, 0xff, 0, 0x100), "CPAZ..");
+        assert_eq!(flags(Op::Adc(true), Size::Long, 0x7fff_ffff, 0, 0x8000_0000), ".PA.SO");
+        assert_eq!(flags(Op::Adc(false), Size::Word, 1, 1, 2), "......");
+
+        assert_eq!(flags(Op::Sbb(true), Size::Byte, 0, 0, -1), "CPA.S.");
+        assert_eq!(flags(Op::Sbb(true), Size::Quad, 5, 3, 1), "......");
+        assert_eq!(flags(Op::Sbb(false), Size::Quad, 5, 5, 0), ".P.Z..");
+    }
+}
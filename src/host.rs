@@ -0,0 +1,110 @@
+use std::cell::RefCell;
+use std::io;
+use std::io::{Read, Write};
+use std::rc::Rc;
+
+// Where a program's standard streams (and the emulator's own output) go
+//   Every emulator owns one (see `Emulator::setIo`), the host's streams are the default
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+    // The emulator's own output: the instruction trace and the register/label/layout dumps
+    Log
+}
+
+pub trait GuestIo {
+    // Standard input of the program (`read` on fd 0), returns 0 at the end of the input
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+    fn write(&mut self, stream: Stream, bytes: &[u8]) -> io::Result<()>;
+}
+
+// The host's stdin/stdout/stderr
+//   The emulator's output goes to stderr, so the program's stdout stays exactly what it wrote
+pub struct HostIo;
+
+impl GuestIo for HostIo {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        io::stdin().read(buf)
+    }
+
+    fn write(&mut self, stream: Stream, bytes: &[u8]) -> io::Result<()> {
+        match stream {
+            Stream::Stdout => {
+                let mut out = io::stdout();
+                out.write_all(bytes)?;
+                out.flush()
+            },
+            Stream::Stderr | Stream::Log => io::stderr().write_all(bytes)
+        }
+    }
+}
+
+// In-memory streams: stdin is fed from a byte slice and every stream is captured
+//   Clones share the buffers, so keep one to look at the output after handing a clone to the emulator
+#[derive(Clone, Default)]
+pub struct BufferIo {
+    bufs: Rc<RefCell<Buffers>>
+}
+
+#[derive(Default)]
+struct Buffers {
+    stdin: Vec<u8>,
+    pos: usize,         // how much of stdin has been read
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    log: Vec<u8>
+}
+
+impl BufferIo {
+    pub fn new(stdin: &[u8]) -> BufferIo {
+        let bufs = Buffers{ stdin: stdin.to_vec(), ..Buffers::default() };
+        BufferIo{ bufs: Rc::new(RefCell::new(bufs)) }
+    }
+
+    // The captured output so far (invalid utf-8 is replaced)
+    pub fn stdout(&self) -> String {
+        String::from_utf8_lossy(&self.bufs.borrow().stdout).into_owned()
+    }
+    pub fn stderr(&self) -> String {
+        String::from_utf8_lossy(&self.bufs.borrow().stderr).into_owned()
+    }
+    pub fn log(&self) -> String {
+        String::from_utf8_lossy(&self.bufs.borrow().log).into_owned()
+    }
+}
+
+impl GuestIo for BufferIo {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut bufs = self.bufs.borrow_mut();
+        let len = buf.len().min(bufs.stdin.len() - bufs.pos);
+        buf[..len].copy_from_slice(&bufs.stdin[bufs.pos..bufs.pos + len]);
+        bufs.pos += len;
+        Ok(len)
+    }
+
+    fn write(&mut self, stream: Stream, bytes: &[u8]) -> io::Result<()> {
+        let mut bufs = self.bufs.borrow_mut();
+        let out = match stream {
+            Stream::Stdout => &mut bufs.stdout,
+            Stream::Stderr => &mut bufs.stderr,
+            Stream::Log => &mut bufs.log
+        };
+        out.extend_from_slice(bytes);
+        Ok(())
+    }
+}
+
+// Discards all output, and stdin is always at its end
+pub struct NullIo;
+
+impl GuestIo for NullIo {
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        Ok(0)
+    }
+
+    fn write(&mut self, _stream: Stream, _bytes: &[u8]) -> io::Result<()> {
+        Ok(())
+    }
+}
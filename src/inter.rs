@@ -1,9 +1,13 @@
 
-use ximpl::{Command, Code, Argument, Flag};
+use ximpl;
+use ximpl::{Command, Code, Argument, Flag, Mode, Size};
+use flags::{Lazy, Op};
 use emu::*;
 use parse;
 use nom::IResult;
-use std::{str, mem};
+use std::str;
+use error::InterpError;
+use syscall;
 
 // x86 cheatsheet
 // https://cs.brown.edu/courses/cs033/docs/guides/x64_cheatsheet.pdf
@@ -11,17 +15,20 @@ use std::{str, mem};
 // Fuller Instruction Listing
 // http://www.felixcloutier.com/x86/
 
-pub fn dispatch<'a>(inst: &'a Command, emu: &'a mut Emulator) -> Result<(), String> {
-    println!("{:?}", inst);
+pub fn dispatch<'a>(inst: &'a Command, emu: &'a mut Emulator) -> Result<(), InterpError> {
+    emu.log(&format!("{:?}\n", inst));
 
-    match inst {
-        &Command::NoArg(ref mne) => run_no_arg(mne, emu),
-        &Command::OneArg(ref mne, ref arg) => run_one_arg(mne, arg, emu),
-        &Command::TwoArg(ref mne, ref a1, ref a2) => run_two_arg(mne, a1, a2, emu),
-        _ => {
-            emu.updatePC();
-            Ok(())
-        }
+    // Operands that can't be sized from the mnemonic/registers default to 32bits
+    //   (stack operations default to the width of the mode)
+    let mode = emu.getMode();
+    let sized = |mne: &str, size: Option<Size>| size.unwrap_or_else(|| ximpl::default_size(mne, mode));
+
+    match *inst {
+        Command::NoArg(ref mne, size) => run_no_arg(mne, sized(mne, size), emu),
+        Command::OneArg(ref mne, size, ref arg) => run_one_arg(mne, sized(mne, size), arg, emu),
+        Command::TwoArg(ref mne, size, ref a1, ref a2) => run_two_arg(mne, sized(mne, size), a1, a2, emu),
+        Command::ThreeArg(ref mne, size, ref a1, ref a2, ref a3) => run_three_arg(mne, sized(mne, size), a1, a2, a3, emu),
+        _ => Ok(())
     }
 }
 
@@ -32,29 +39,33 @@ pub fn collect_labels(code: &mut Vec<Code>, emu: &mut Emulator) {
     loop {
         // Collect the label and any extra code (if possible)
         let res = match code[pc] {
-            Code::Unread(ref s) => match parse::label(s) {
+            Code::Unread(ref s, syntax, line) => match parse::label(s) {
                 IResult::Done(left, res) => {
                     let s = unsafe {
                         str::from_utf8_unchecked(left).to_string()
                     };
-                    (s, res)
+                    (s, syntax, line, res)
                 },
-                _ => ("".to_string(), Command::NOP),
+                _ => ("".to_string(), syntax, line, Command::Nop),
             },
             Code::EndProgram => break,
-            _ => continue
+            // Already assembled (ie. alignment padding)
+            Code::Parsed(..) => {
+                pc += 1;
+                continue;
+            }
         };
 
         // Remember the label location and remove it from the code vector
         match res {
-            (ref s, Command::Label(ref lbl)) if s == "" => {
+            (ref s, _, _, Command::Label(ref lbl)) if s.is_empty() => {
                 emu.addLabel(lbl, pc);
                 code.remove(pc);
             },
             // If there's some extra code, insert it at the label's spot
-            (ref s, Command::Label(ref lbl)) => {
+            (ref s, syntax, line, Command::Label(ref lbl)) => {
                 emu.addLabel(lbl, pc);
-                code[pc] = Code::Unread(s.to_string());
+                code[pc] = Code::Unread(s.to_string(), syntax, line);
                 pc += 1;
             },
             _ => pc += 1,
@@ -64,16 +75,33 @@ pub fn collect_labels(code: &mut Vec<Code>, emu: &mut Emulator) {
 
 #[allow(unreachable_code)]
 #[allow(unused_variables)]
-fn run_no_arg(mne: &str, emu: &mut Emulator) -> Result<(), String> {
-    // Note: This may result in some problems in the future (for now it's fine)
-    emu.updatePC();
-
+fn run_no_arg(mne: &str, size: Size, emu: &mut Emulator) -> Result<(), InterpError> {
     match mne {
-        "cwtl" => Err("Unimplemented instruction: `cwtl`".to_owned()),
-        "cltq" => Err("Unimplemented instruction: `cltq`".to_owned()),
-        "cqto" => Err("Unimplemented instruction: `cqto`".to_owned()),
-        "leave" => Err("Unimplemented instruction: `leave`".to_owned()),
-        "ret" => Err("Unimplemented instruction: `ret`".to_owned()),
+        // Sign extend the accumulator in place (ie. `%ax` => `%eax`)
+        "cbtw" | "cbw" => extend_register(emu, "al", "ax"),
+        "cwtl" | "cwde" => extend_register(emu, "ax", "eax"),
+        "cltq" | "cdqe" => extend_register(emu, "eax", "rax"),
+        // Sign extend the accumulator into the data register (ie. `%eax` => `%edx:%eax`)
+        "cwtd" | "cwd" => extend_accumulator(emu, Size::Word),
+        "cltd" | "cdq" => extend_accumulator(emu, Size::Long),
+        "cqto" | "cqo" => extend_accumulator(emu, Size::Quad),
+        "leave" => {
+            // Equivalent to `mov %ebp, %esp; pop %ebp`
+            let (sp, bp) = frame_registers(size)?;
+            let frame = emu.getReg(bp)?.get_unsigned() as i64;
+            emu.getReg(sp)?.set(frame)?;
+
+            let val = pop_value(emu, size)?;
+            emu.getReg(bp)?.set(val)
+        },
+        "ret" => {
+            let addr = pop_value(emu, size)?;
+            emu.setPC(size.truncate(addr));
+            Ok(())
+        },
+        // Indirect branch tracking isn't enforced, so `endbr` marks nothing
+        "nop" | "endbr32" | "endbr64" => Ok(()),
+        "syscall" if emu.getMode() == Mode::Bits64 => syscall::syscall(emu),
         "exit" => {
             emu.exit();
             Ok(())
@@ -82,806 +110,1007 @@ fn run_no_arg(mne: &str, emu: &mut Emulator) -> Result<(), String> {
             emu.dump_all();
             Ok(())
         },
-        _ => Err("Unknown instruction".to_owned())
+        _ => Err(InterpError::UnknownMnemonic(mne.to_string()))
     }
 }
 
-fn set_flags(emu: &mut Emulator, old_val: i32, new_val: i32, perf_add: bool) {
-    if new_val == 0 {
-        emu.setFlag(Flag::Zero, true);
-    }
-
-    if new_val < 0 {
-        emu.setFlag(Flag::Sign, true);
-    }
-
-    match perf_add {
-        true if old_val > new_val => emu.setFlag(Flag::Overflow, true),
-        false if old_val < new_val => emu.setFlag(Flag::Overflow, true),
-        _ => ()
-    }
-
-    let val = emu.getFlag(Flag::Overflow);
-    emu.setFlag(Flag::Carry, val);
-
-    let test: i8 = unsafe { mem::transmute::<i32, [i8;4]>(new_val)[0] };
-    if test.count_zeros() == test.count_ones() {
-        emu.setFlag(Flag::Parity, true);
-    }
-
-    // TODO: Set 'Auxiliary Carry Flag'
-}
-
-fn run_one_arg(mne: &str, arg: &Argument, emu: &mut Emulator) -> Result<(), String> {
+fn run_one_arg(mne: &str, size: Size, arg: &Argument, emu: &mut Emulator) -> Result<(), InterpError> {
     use self::Argument::*;
 
-    let ret = match mne {
+    match mne {
         "push" => {
-            emu.clearFlags();
-
-            let esp = emu.getReg("esp")?.get();
-            let val = get_value(emu, arg)?;
-            emu.getMemorySized(esp, -4).set(val)?;
-
-            let mut esp = emu.getReg("esp")?;
-            esp -= 4;
-            Ok(())
+            let val = get_value(emu, arg, size)?;
+            push_value(emu, val, size)
         },
         "pop" => {
-            emu.clearFlags();
-            
-            let esp = {
-                let mut esp = emu.getReg("esp")?;
-                esp += 4;
-                esp.get()
-            };
-
-            let val = emu.getMemorySized(esp, -4).get();
-
-            match arg {
-                &Reg(ref r) => {
-                    let mut reg = emu.getReg(r)?;
-                    reg.set(val)
-                },
-                &Mem(ref base, off, _, _) => {
-                    let base = get_value(emu, &base)?;
-                    // let mut m = emu.getMemory(base + off);
-                    // m.set(val)
-                    emu.getMemory(base + off).set(val)
-                },
-                _ => Err("Unsupported operation".to_owned())
-            }
+            let val = pop_value(emu, size)?;
+            set_value(emu, arg, size, val)
         },
         "inc" => {
-            emu.clearFlags();
-            
-            match arg {
-                &Reg(ref r) => {
-                    let old_val: i32;
-                    let new_val = {
-                        let mut reg = emu.getReg(r)?;
-                        old_val = reg.get();
-                        reg += 1;
-                        reg.get()
-                    };
-
-                    set_flags(emu, old_val, new_val, true);
-                    Ok(())
-                },
-                &Mem(ref base, off, _, _) => {
-                    let old_val: i32;
-                    let new_val = {
-                        let base = get_value(emu, &base)?;
-                        let mut mem = emu.getMemory(base + off);
-                        old_val = mem.get();
-                        mem += 1;
-                        mem.get()
-                    };
-                    
-                    set_flags(emu, old_val, new_val, true);
-                    Ok(())
-                },
-                _ => Err("Unsupported operation".to_owned())
-            }
+            let val = get_value(emu, arg, size)?;
+            let res = val.wrapping_add(1);
+            set_value(emu, arg, size, res)?;
+            emu.recordFlags(Lazy::new(Op::Inc, val, 1, res, size));
+            Ok(())
         },
         "dec" => {
-            emu.clearFlags();
-            
-            match arg {
-                &Reg(ref r) => {
-                    let mut reg = emu.getReg(r)?;
-                    reg -= 1;
-                    Ok(())
-                },
-                &Mem(ref base, off, _, _) => {
-                    let base = get_value(emu, &base)?;
-                    let mut m = emu.getMemory(base + off);
-                    m -= 1;
-                    Ok(())
-                },
-                _ => Err("Unsupported operation".to_owned())
-            }
-
-            // TODO: Set OF, SF, ZF, AF, PF flags
+            let val = get_value(emu, arg, size)?;
+            let res = val.wrapping_sub(1);
+            set_value(emu, arg, size, res)?;
+            emu.recordFlags(Lazy::new(Op::Dec, val, 1, res, size));
+            Ok(())
         },
         "neg" => {
-            emu.clearFlags();
-            
-            match arg {
-                &Reg(ref r) => {
-                    let mut reg = emu.getReg(r)?;
-                    reg *= -1;
-                    Ok(())
-                },
-                &Mem(ref base, off, _, _) => {
-                    let base = get_value(emu, &base)?;
-                    let mut m = emu.getMemory(base + off);
-                    m *= -1;
-                    Ok(())
-                },
-                _ => Err("Unsupported operation".to_owned())
-            }
-
-            // TODO: Set OF, SF, ZF, AF, CF, PF flags
-        },
-        "not" => Err("Unimplemented instruction: `not`".to_owned()),
-        "imulq" => Err("Unimplemented instruction: `imulq`".to_owned()),
-        "mulq" => Err("Unimplemented instruction: `mulq`".to_owned()),
-        "idivq" => Err("Unimplemented instruction: `idivq`".to_owned()),
-        "divq" => Err("Unimplemented instruction: `divq`".to_owned()),
-        "sete" => {
-            let val = if emu.getFlag(Flag::Zero) { 1 } else { 0 };
-            
-            match arg {
-                &Reg(ref r) => emu.getReg(r)?.set(val),
-                &Mem(ref base, off, _, _) => {
-                    let base = get_value(emu, &base)?;
-                    emu.getMemory(base + off).set(val)
-                },
-                _ => Err("Invalid operand".to_owned())
-            }
-        },
-        "setz" => {
-            let val = if emu.getFlag(Flag::Zero) { 1 } else { 0 };
-            
-            match arg {
-                &Reg(ref r) => emu.getReg(r)?.set(val),
-                &Mem(ref base, off, _, _) => {
-                    let base = get_value(emu, &base)?;
-                    emu.getMemory(base + off).set(val)
-                },
-                _ => Err("Invalid operand".to_owned())
-            }
-        },
-        "setne" => {
-            let val = if emu.getFlag(Flag::Zero) { 0 } else { 1 };
-            
-            match arg {
-                &Reg(ref r) => emu.getReg(r)?.set(val),
-                &Mem(ref base, off, _, _) => {
-                    let base = get_value(emu, &base)?;
-                    emu.getMemory(base + off).set(val)
-                },
-                _ => Err("Invalid operand".to_owned())
-            }
-        },
-        "setnz" => {
-            let val = if emu.getFlag(Flag::Zero) { 0 } else { 1 };
-            
-            match arg {
-                &Reg(ref r) => emu.getReg(r)?.set(val),
-                &Mem(ref base, off, _, _) => {
-                    let base = get_value(emu, &base)?;
-                    emu.getMemory(base + off).set(val)
-                },
-                _ => Err("Invalid operand".to_owned())
-            }
-        },
-        "sets" => {
-            let val = if emu.getFlag(Flag::Sign) { 1 } else { 0 };
-            
-            match arg {
-                &Reg(ref r) => emu.getReg(r)?.set(val),
-                &Mem(ref base, off, _, _) => {
-                    let base = get_value(emu, &base)?;
-                    emu.getMemory(base + off).set(val)
-                },
-                _ => Err("Invalid operand".to_owned())
-            }
-        },
-        "setns" => {
-            let val = if emu.getFlag(Flag::Sign) { 0 } else { 1 };
-            
-            match arg {
-                &Reg(ref r) => emu.getReg(r)?.set(val),
-                &Mem(ref base, off, _, _) => {
-                    let base = get_value(emu, &base)?;
-                    emu.getMemory(base + off).set(val)
-                },
-                _ => Err("Invalid operand".to_owned())
-            }
-        },
-        "setg" => {
-            let val = !(emu.getFlag(Flag::Sign) ^ emu.getFlag(Flag::Overflow)) && !emu.getFlag(Flag::Zero);
-            let val = if val { 1 } else { 0 };
-            
-            match arg {
-                &Reg(ref r) => emu.getReg(r)?.set(val),
-                &Mem(ref base, off, _, _) => {
-                    let base = get_value(emu, &base)?;
-                    emu.getMemory(base + off).set(val)
-                },
-                _ => Err("Invalid operand".to_owned())
-            }
-        },
-        "setnle" => {
-            let val = !(emu.getFlag(Flag::Sign) ^ emu.getFlag(Flag::Overflow)) && !emu.getFlag(Flag::Zero);
-            let val = if val { 1 } else { 0 };
-            
-            match arg {
-                &Reg(ref r) => emu.getReg(r)?.set(val),
-                &Mem(ref base, off, _, _) => {
-                    let base = get_value(emu, &base)?;
-                    emu.getMemory(base + off).set(val)
-                },
-                _ => Err("Invalid operand".to_owned())
-            }
-        },
-        "setge" => {
-            let val = !(emu.getFlag(Flag::Sign) ^ emu.getFlag(Flag::Overflow));
-            let val = if val { 1 } else { 0 };
-            
-            match arg {
-                &Reg(ref r) => emu.getReg(r)?.set(val),
-                &Mem(ref base, off, _, _) => {
-                    let base = get_value(emu, &base)?;
-                    emu.getMemory(base + off).set(val)
-                },
-                _ => Err("Invalid operand".to_owned())
-            }
-        },
-        "setnl" => {
-            let val = !(emu.getFlag(Flag::Sign) ^ emu.getFlag(Flag::Overflow));
-            let val = if val { 1 } else { 0 };
-            
-            match arg {
-                &Reg(ref r) => emu.getReg(r)?.set(val),
-                &Mem(ref base, off, _, _) => {
-                    let base = get_value(emu, &base)?;
-                    emu.getMemory(base + off).set(val)
-                },
-                _ => Err("Invalid operand".to_owned())
-            }
-        },
-        "setl" => {
-            let val = if emu.getFlag(Flag::Sign) ^ emu.getFlag(Flag::Overflow) { 1 } else { 0 };
-            
-            match arg {
-                &Reg(ref r) => emu.getReg(r)?.set(val),
-                &Mem(ref base, off, _, _) => {
-                    let base = get_value(emu, &base)?;
-                    emu.getMemory(base + off).set(val)
-                },
-                _ => Err("Invalid operand".to_owned())
-            }
+            // Flags are set as if computing `0 - val`
+            let val = get_value(emu, arg, size)?;
+            let res = val.wrapping_neg();
+            set_value(emu, arg, size, res)?;
+            emu.recordFlags(Lazy::new(Op::Sub, 0, val, res, size));
+            Ok(())
         },
-        "setnge" => {
-            let val = if emu.getFlag(Flag::Sign) ^ emu.getFlag(Flag::Overflow) { 1 } else { 0 };
-            
-            match arg {
-                &Reg(ref r) => emu.getReg(r)?.set(val),
-                &Mem(ref base, off, _, _) => {
-                    let base = get_value(emu, &base)?;
-                    emu.getMemory(base + off).set(val)
-                },
-                _ => Err("Invalid operand".to_owned())
-            }
+        // The flags are left unchanged
+        "not" => {
+            let val = get_value(emu, arg, size)?;
+            set_value(emu, arg, size, !val)
         },
-        "setle" => {
-            let val = (emu.getFlag(Flag::Sign) ^ emu.getFlag(Flag::Overflow)) || emu.getFlag(Flag::Zero);
-            let val = if val { 1 } else { 0 };
-            
-            match arg {
-                &Reg(ref r) => emu.getReg(r)?.set(val),
-                &Mem(ref base, off, _, _) => {
-                    let base = get_value(emu, &base)?;
-                    emu.getMemory(base + off).set(val)
-                },
-                _ => Err("Invalid operand".to_owned())
-            }
-        },
-        "setng" => {
-            let val = (emu.getFlag(Flag::Sign) ^ emu.getFlag(Flag::Overflow)) || emu.getFlag(Flag::Zero);
-            let val = if val { 1 } else { 0 };
-            
-            match arg {
-                &Reg(ref r) => emu.getReg(r)?.set(val),
-                &Mem(ref base, off, _, _) => {
-                    let base = get_value(emu, &base)?;
-                    emu.getMemory(base + off).set(val)
-                },
-                _ => Err("Invalid operand".to_owned())
-            }
-        },
-        "seta" => {
-            let val = if !emu.getFlag(Flag::Carry) && !emu.getFlag(Flag::Zero) { 1 } else { 0 };
-            
+        "imul" => do_multiply(emu, true, size, arg),
+        "mul" => do_multiply(emu, false, size, arg),
+        "idiv" => do_divide(emu, true, size, arg),
+        "div" => do_divide(emu, false, size, arg),
+        _ if mne.starts_with("set") => {
+            let val = if condition(emu, &mne[3..])? { 1 } else { 0 };
+
             match arg {
-                &Reg(ref r) => emu.getReg(r)?.set(val),
-                &Mem(ref base, off, _, _) => {
-                    let base = get_value(emu, &base)?;
-                    emu.getMemory(base + off).set(val)
-                },
-                _ => Err("Invalid operand".to_owned())
+                &Reg(_) | &Mem(..) | &Label(_) => set_value(emu, arg, Size::Byte, val),
+                _ => Err(InterpError::InvalidOperand("Invalid operand".to_owned()))
             }
         },
-        "setnbe" => {
-            let val = if !emu.getFlag(Flag::Carry) && !emu.getFlag(Flag::Zero) { 1 } else { 0 };
-            
-            match arg {
-                &Reg(ref r) => emu.getReg(r)?.set(val),
-                &Mem(ref base, off, _, _) => {
-                    let base = get_value(emu, &base)?;
-                    emu.getMemory(base + off).set(val)
-                },
-                _ => Err("Invalid operand".to_owned())
-            }
+        // Shifts/rotates by an implicit count of 1
+        "sal" | "shl" | "sar" | "shr" |
+        "rol" | "ror" | "rcl" | "rcr" => do_shift(emu, mne, size, 1, arg),
+        "jmp" => do_jump(emu, size, arg),
+        _ if mne.starts_with("j") => match arg {
+            &Label(_) | &Target(_) if condition(emu, &mne[1..])? => do_jump(emu, size, arg),
+            &Label(_) | &Target(_) => Ok(()),
+            _ => Err(InterpError::InvalidOperand(format!("Invalid operand to `{}`: conditional jumps take a label", mne)))
         },
-        "setae" => {
-            let val = if emu.getFlag(Flag::Carry) { 0 } else { 1 };
-            
-            match arg {
-                &Reg(ref r) => emu.getReg(r)?.set(val),
-                &Mem(ref base, off, _, _) => {
-                    let base = get_value(emu, &base)?;
-                    emu.getMemory(base + off).set(val)
-                },
-                _ => Err("Invalid operand".to_owned())
-            }
+        "call" => {
+            // `%rip` already holds the address of the next instruction
+            let ret = emu.getPC();
+            push_value(emu, ret as i64, size)?;
+            do_jump(emu, size, arg)
         },
-        "setnb" => {
-            let val = if emu.getFlag(Flag::Carry) { 0 } else { 1 };
-            
-            match arg {
-                &Reg(ref r) => emu.getReg(r)?.set(val),
-                &Mem(ref base, off, _, _) => {
-                    let base = get_value(emu, &base)?;
-                    emu.getMemory(base + off).set(val)
-                },
-                _ => Err("Invalid operand".to_owned())
-            }
+        "int" => match *arg {
+            Literal(0x80) => syscall::int80(emu),
+            Literal(vector) => Err(InterpError::Unimplemented(
+                format!("interrupt {:#x} (only `int $0x80` system calls are handled)", vector))),
+            _ => Err(InterpError::InvalidOperand("Invalid operand to `int`: expected an interrupt number".to_owned()))
         },
-        "setb" => {
-            let val = if emu.getFlag(Flag::Carry) { 1 } else { 0 };
-            
-            match arg {
-                &Reg(ref r) => emu.getReg(r)?.set(val),
-                &Mem(ref base, off, _, _) => {
-                    let base = get_value(emu, &base)?;
-                    emu.getMemory(base + off).set(val)
-                },
-                _ => Err("Invalid operand".to_owned())
-            }
+        "ret" => {
+            let addr = pop_value(emu, size)?;
+            let bytes = get_value(emu, arg, Size::Word)? & 0xffff;
+            let mut sp = emu.getReg("rsp")?;
+            sp += bytes;
+
+            emu.setPC(size.truncate(addr));
+            Ok(())
         },
-        "setnae" => {
-            let val = if emu.getFlag(Flag::Carry) { 1 } else { 0 };
-            
-            match arg {
-                &Reg(ref r) => emu.getReg(r)?.set(val),
-                &Mem(ref base, off, _, _) => {
-                    let base = get_value(emu, &base)?;
-                    emu.getMemory(base + off).set(val)
-                },
-                _ => Err("Invalid operand".to_owned())
+        _ => Err(InterpError::UnknownMnemonic(mne.to_string()))
+    }
+}
+
+fn run_two_arg(mne: &str, size: Size, src: &Argument, dest: &Argument, emu: &mut Emulator) -> Result<(), InterpError> {
+    use self::Argument::*;
+
+    match mne {
+        // The source size is the mnemonic suffix (ie. `movsbl` => `movsb` with long operands)
+        "movsb" | "movsw" | "movsl" | "movzb" | "movzw" => {
+            let src_size = Size::from_suffix(&mne[4..]).unwrap();
+            let val = get_value(emu, src, src_size)?;
+
+            match *dest {
+                Reg(_) if mne.starts_with("movz") => set_value(emu, dest, size, src_size.truncate(val) as i64),
+                Reg(_) => set_value(emu, dest, size, val),
+                _ => Err(InterpError::InvalidOperand(format!("Invalid destination for `{}`: expected a register", mne)))
             }
         },
-        "setbe" => {
-            let val = if emu.getFlag(Flag::Carry) || emu.getFlag(Flag::Zero) { 1 } else { 0 };
-            
-            match arg {
-                &Reg(ref r) => emu.getReg(r)?.set(val),
-                &Mem(ref base, off, _, _) => {
-                    let base = get_value(emu, &base)?;
-                    emu.getMemory(base + off).set(val)
+        "mov" | "movabs" => {
+            let val = get_value(emu, src, size)?;
+
+            match *dest {
+                Reg(ref r) if is_instruction_pointer(r) => Err(ip_write_error()),
+                Reg(ref r) => {
+                    let mut r = emu.getReg(r)?;
+                    r.set(val)
                 },
-                _ => Err("Invalid operand".to_owned())
-            }
-        },
-        "setna" => {
-            let val = if emu.getFlag(Flag::Carry) || emu.getFlag(Flag::Zero) { 1 } else { 0 };
-            
-            match arg {
-                &Reg(ref r) => emu.getReg(r)?.set(val),
-                &Mem(ref base, off, _, _) => {
-                    let base = get_value(emu, &base)?;
-                    emu.getMemory(base + off).set(val)
+                Mem(..) | Label(_) => {
+                    let addr = effective_address(emu, dest)?;
+                    let mut m = emu.getMemory(addr, size)?;
+                    m.set(val)
                 },
-                _ => Err("Invalid operand".to_owned())
-            }
-        },
-        "jmp" => {
-            emu.clearFlags();
-            do_jump(emu, arg)
-        },
-        "je" => {
-            if emu.getFlag(Flag::Zero) {
-                do_jump(emu, arg)
-            } else {
-                Ok(())
-            }
-        },
-        "jz" => {
-            if emu.getFlag(Flag::Zero) {
-                do_jump(emu, arg)
-            } else {
-                Ok(())
-            }
-        },
-        "jnz" => {
-            if !emu.getFlag(Flag::Zero) {
-                do_jump(emu, arg)
-            } else {
-                Ok(())
-            }
-        },
-        "jne" => {
-            if !emu.getFlag(Flag::Zero) {
-                do_jump(emu, arg)
-            } else {
-                Ok(())
-            }
-        },
-        "js" => {
-            if emu.getFlag(Flag::Sign) {
-                do_jump(emu, arg)
-            } else {
-                Ok(())
-            }
-        },
-        "jns" => {
-            if !emu.getFlag(Flag::Sign) {
-                do_jump(emu, arg)
-            } else {
-                Ok(())
+                _ => Err(InterpError::InvalidOperand("Invalid operand type to `mov`".to_owned()))
             }
         },
-        "jg" => {
-            if !(emu.getFlag(Flag::Sign) ^ emu.getFlag(Flag::Overflow)) && !emu.getFlag(Flag::Zero) {
-                do_jump(emu, arg)
-            } else {
-                Ok(())
-            }
-        },
-        "jnle" => {
-            if !(emu.getFlag(Flag::Sign) ^ emu.getFlag(Flag::Overflow)) && !emu.getFlag(Flag::Zero) {
-                do_jump(emu, arg)
-            } else {
-                Ok(())
-            }
-        },
-        "jge" => {
-            if !(emu.getFlag(Flag::Sign) ^ emu.getFlag(Flag::Overflow)) {
-                do_jump(emu, arg)
-            } else {
-                Ok(())
+        // Only the address is computed, memory and flags are left untouched
+        "lea" => {
+            let addr = match src {
+                &Mem(..) | &Label(_) => effective_address(emu, src)?,
+                _ => return Err(InterpError::InvalidOperand("Invalid source for `lea`: expected a memory operand".to_owned()))
+            };
+
+            match dest {
+                &Reg(_) => set_value(emu, dest, size, addr),
+                _ => Err(InterpError::InvalidOperand("Invalid destination for `lea`: expected a register".to_owned()))
             }
         },
-        "jnl" => {
-            if !(emu.getFlag(Flag::Sign) ^ emu.getFlag(Flag::Overflow)) {
-                do_jump(emu, arg)
+        "add" => update(emu, Op::Add, size, src, dest, i64::wrapping_add),
+        "sub" => update(emu, Op::Sub, size, src, dest, i64::wrapping_sub),
+        // Multi-word arithmetic, the carry flag is added to/subtracted from the result
+        "adc" | "sbb" => {
+            let carry = emu.getFlag(Flag::Carry);
+            let lhs = get_value(emu, dest, size)?;
+            let rhs = get_value(emu, src, size)?;
+            let (op, res) = if mne == "adc" {
+                (Op::Adc(carry), lhs.wrapping_add(rhs).wrapping_add(carry as i64))
             } else {
-                Ok(())
-            }
+                (Op::Sbb(carry), lhs.wrapping_sub(rhs).wrapping_sub(carry as i64))
+            };
+            set_value(emu, dest, size, res)?;
+            emu.recordFlags(Lazy::new(op, lhs, rhs, res, size));
+            Ok(())
         },
-        "jl" => {
-            if emu.getFlag(Flag::Sign) ^ emu.getFlag(Flag::Overflow) {
-                do_jump(emu, arg)
-            } else {
-                Ok(())
-            }
+        // Swaps the operands, the flags are left unchanged
+        "xchg" => {
+            let lhs = get_value(emu, dest, size)?;
+            let rhs = get_value(emu, src, size)?;
+            set_value(emu, dest, size, rhs)?;
+            set_value(emu, src, size, lhs)
         },
-        "jnge" => {
-            if emu.getFlag(Flag::Sign) ^ emu.getFlag(Flag::Overflow) {
-                do_jump(emu, arg)
-            } else {
-                Ok(())
-            }
+        "imul" => do_truncated_multiply(emu, size, src, dest, dest),
+        "xor" => update(emu, Op::Logic, size, src, dest, |a, b| a ^ b),
+        "or" => update(emu, Op::Logic, size, src, dest, |a, b| a | b),
+        "and" => update(emu, Op::Logic, size, src, dest, |a, b| a & b),
+        "sal" | "shl" | "sar" | "shr" |
+        "rol" | "ror" | "rcl" | "rcr" => {
+            let count = shift_count(emu, src, size)?;
+            do_shift(emu, mne, size, count, dest)
         },
-        "jle" => {
-            if (emu.getFlag(Flag::Sign) ^ emu.getFlag(Flag::Overflow)) || emu.getFlag(Flag::Zero) {
-                do_jump(emu, arg)
-            } else {
-                Ok(())
-            }
+        "cmp" => {
+            // Computes `dest - src` without storing the result
+            let lhs = get_value(emu, dest, size)?;
+            let rhs = get_value(emu, src, size)?;
+            emu.recordFlags(Lazy::new(Op::Sub, lhs, rhs, lhs.wrapping_sub(rhs), size));
+            Ok(())
         },
-        "jng" => {
-            if (emu.getFlag(Flag::Sign) ^ emu.getFlag(Flag::Overflow)) || emu.getFlag(Flag::Zero) {
-                do_jump(emu, arg)
-            } else {
-                Ok(())
-            }
+        "test" => {
+            // Computes `dest & src` without storing the result
+            let lhs = get_value(emu, dest, size)?;
+            let rhs = get_value(emu, src, size)?;
+            emu.recordFlags(Lazy::new(Op::Logic, lhs, rhs, lhs & rhs, size));
+            Ok(())
         },
-        "ja" => {
-            if !emu.getFlag(Flag::Zero) && !emu.getFlag(Flag::Carry) {
-                do_jump(emu, arg)
+        // The destination is always written, so 32bit destinations are zero extended either way
+        _ if mne.starts_with("cmov") => {
+            let val = if condition(emu, &mne[4..])? {
+                get_value(emu, src, size)?
             } else {
-                Ok(())
+                get_value(emu, dest, size)?
+            };
+
+            match dest {
+                &Reg(_) => set_value(emu, dest, size, val),
+                _ => Err(InterpError::InvalidOperand(format!("Invalid destination for `{}`: expected a register", mne)))
             }
         },
-        "jnbe" => {
-            if !emu.getFlag(Flag::Zero) && !emu.getFlag(Flag::Carry) {
-                do_jump(emu, arg)
-            } else {
-                Ok(())
+        "enter" => {
+            // Operands are `enter $alloc, $level`
+            let alloc = get_value(emu, src, Size::Word)? & 0xffff;
+            let level = get_value(emu, dest, Size::Byte)? & 0x1f;
+            let (sp, bp) = frame_registers(size)?;
+
+            // The frame pointers are addresses (a 32bit stack sits above 0x80000000)
+            let frame = emu.getReg(bp)?.get_unsigned() as i64;
+            push_value(emu, frame, size)?;
+            let frame_tmp = emu.getReg(sp)?.get_unsigned() as i64;
+
+            // Copy the enclosing frame pointers for nested procedures
+            if level > 0 {
+                let mut frame = frame;
+                for _ in 1..level {
+                    frame -= size.bytes() as i64;
+                    let val = emu.getMemory(frame, size)?.get();
+                    push_value(emu, val, size)?;
+                }
+
+                push_value(emu, frame_tmp, size)?;
             }
+
+            emu.getReg(bp)?.set(frame_tmp)?;
+            let mut sp = emu.getReg(sp)?;
+            sp -= alloc;
+            Ok(())
         },
-        "jae" => {
-            if !emu.getFlag(Flag::Carry) {
-                do_jump(emu, arg)
-            } else {
-                Ok(())
-            }
+        _ => Err(InterpError::UnknownMnemonic(mne.to_string()))
+    }
+}
+
+fn run_three_arg(mne: &str, size: Size, a1: &Argument, a2: &Argument, a3: &Argument, emu: &mut Emulator) -> Result<(), InterpError> {
+    match mne {
+        // `imul $imm, src, dest` => dest = src * imm
+        "imul" => do_truncated_multiply(emu, size, a1, a2, a3),
+        "shld" | "shrd" => {
+            let count = shift_count(emu, a1, size)?;
+            do_double_shift(emu, mne, size, count, a2, a3)
         },
-        "jnb" => {
-            if !emu.getFlag(Flag::Carry) {
-                do_jump(emu, arg)
-            } else {
-                Ok(())
-            }
+        _ => Err(InterpError::UnknownMnemonic(mne.to_string()))
+    }
+}
+
+fn extend_register(emu: &mut Emulator, src: &str, dest: &str) -> Result<(), InterpError> {
+    let val = emu.getReg(src)?.get();
+    emu.getReg(dest)?.set(val)
+}
+
+// Fill the high register with the sign of the low one (ie. before an `idiv`)
+fn extend_accumulator(emu: &mut Emulator, size: Size) -> Result<(), InterpError> {
+    let (high, low) = accumulator_pair(size);
+    let val = emu.getReg(low)?.get();
+    emu.getReg(high)?.set(if val < 0 { -1 } else { 0 })
+}
+
+// Evaluate the condition code of a `j*`/`set*`/`cmov*` instruction (ie. `nle` for `jnle`)
+fn condition(emu: &Emulator, cc: &str) -> Result<bool, InterpError> {
+    let (carry, zero) = (emu.getFlag(Flag::Carry), emu.getFlag(Flag::Zero));
+    let (sign, overflow) = (emu.getFlag(Flag::Sign), emu.getFlag(Flag::Overflow));
+    let parity = emu.getFlag(Flag::Parity);
+
+    let res = match cc {
+        "o" => overflow,
+        "no" => !overflow,
+        "b" | "c" | "nae" => carry,
+        "ae" | "nb" | "nc" => !carry,
+        "e" | "z" => zero,
+        "ne" | "nz" => !zero,
+        "be" | "na" => carry || zero,
+        "a" | "nbe" => !carry && !zero,
+        "s" => sign,
+        "ns" => !sign,
+        "p" | "pe" => parity,
+        "np" | "po" => !parity,
+        "l" | "nge" => sign != overflow,
+        "ge" | "nl" => sign == overflow,
+        "le" | "ng" => zero || sign != overflow,
+        "g" | "nle" => !zero && sign == overflow,
+        _ => return Err(InterpError::InvalidOperand(format!("Unknown condition code: `{}`", cc)))
+    };
+
+    Ok(res)
+}
+
+// Registers holding the double-width operand of `mul`/`div` (as `high:low`)
+fn accumulator_pair(size: Size) -> (&'static str, &'static str) {
+    match size {
+        Size::Byte => ("ah", "al"),
+        Size::Word => ("dx", "ax"),
+        Size::Long => ("edx", "eax"),
+        Size::Quad => ("rdx", "rax")
+    }
+}
+
+// One operand `mul`/`imul` (ie. EDX:EAX = EAX * src)
+fn do_multiply(emu: &mut Emulator, signed: bool, size: Size, src: &Argument) -> Result<(), InterpError> {
+    let bits = 8 * size.bytes() as u32;
+    let (high, low) = accumulator_pair(size);
+
+    let lhs = emu.getReg(low)?.get();
+    let rhs = get_value(emu, src, size)?;
+
+    let product = if signed {
+        (lhs as i128 * rhs as i128) as u128
+    } else {
+        size.truncate(lhs) as u128 * size.truncate(rhs) as u128
+    };
+    let (res_low, res_high) = (product as u64 & size.truncate(-1), (product >> bits) as u64 & size.truncate(-1));
+
+    // CF/OF are set when the upper half holds significant bits
+    let overflow = if signed {
+        size.sign_extend(res_low) as i128 != product as i128
+    } else {
+        res_high != 0
+    };
+
+    emu.getReg(low)?.set(res_low as i64)?;
+    emu.getReg(high)?.set(res_high as i64)?;
+    emu.recordFlags(Lazy::new(Op::Logic, lhs, rhs, res_low as i64, size));
+    emu.setFlag(Flag::Carry, overflow);
+    emu.setFlag(Flag::Overflow, overflow);
+    Ok(())
+}
+
+// Two and three operand `imul` (the product is truncated to the destination size)
+fn do_truncated_multiply(emu: &mut Emulator, size: Size, src: &Argument, factor: &Argument, dest: &Argument) -> Result<(), InterpError> {
+    let lhs = get_value(emu, factor, size)?;
+    let rhs = get_value(emu, src, size)?;
+    let res = lhs.wrapping_mul(rhs);
+
+    // CF/OF are set when the product doesn't fit within the destination
+    let overflow = size.sign_extend(size.truncate(res)) as i128 != lhs as i128 * rhs as i128;
+
+    set_value(emu, dest, size, res)?;
+    emu.recordFlags(Lazy::new(Op::Logic, lhs, rhs, res, size));
+    emu.setFlag(Flag::Carry, overflow);
+    emu.setFlag(Flag::Overflow, overflow);
+    Ok(())
+}
+
+// One operand `div`/`idiv` (ie. EAX = EDX:EAX / src, EDX = EDX:EAX % src)
+//   Division by 0 and quotients that don't fit raise a divide error (#DE)
+fn do_divide(emu: &mut Emulator, signed: bool, size: Size, src: &Argument) -> Result<(), InterpError> {
+    let bits = 8 * size.bytes() as u32;
+    let (high, low) = accumulator_pair(size);
+
+    let divisor = get_value(emu, src, size)?;
+    if divisor == 0 {
+        return Err(InterpError::DivideError("division by zero".to_owned()));
+    }
+
+    let dividend_high = emu.getReg(high)?.get();
+    let dividend_low = size.truncate(emu.getReg(low)?.get()) as u128;
+
+    let (quot, rem) = if signed {
+        let dividend = ((dividend_high as i128) << bits) | dividend_low as i128;
+        let quot = dividend.checked_div(divisor as i128);
+        let max = (1i128 << (bits - 1)) - 1;
+
+        match quot {
+            Some(quot) if quot >= -max - 1 && quot <= max => (quot as i64, (dividend % divisor as i128) as i64),
+            _ => return Err(InterpError::DivideError("quotient is too large".to_owned()))
+        }
+    } else {
+        let dividend = ((size.truncate(dividend_high) as u128) << bits) | dividend_low;
+        let divisor = size.truncate(divisor) as u128;
+        let quot = dividend / divisor;
+
+        if quot > size.truncate(-1) as u128 {
+            return Err(InterpError::DivideError("quotient is too large".to_owned()));
+        }
+        (quot as i64, (dividend % divisor) as i64)
+    };
+
+    emu.getReg(low)?.set(size.truncate(quot) as i64)?;
+    emu.getReg(high)?.set(size.truncate(rem) as i64)
+}
+
+// Read the count of a shift/rotate, masked to 5 bits (6 bits for 64bit operands)
+fn shift_count(emu: &mut Emulator, arg: &Argument, size: Size) -> Result<u32, InterpError> {
+    let count = match *arg {
+        Argument::Literal(val) => val,
+        Argument::Reg(ref r) if r == "cl" => emu.getReg(r)?.get(),
+        _ => return Err(InterpError::InvalidOperand("Shift counts must be an immediate or `%cl`".to_owned()))
+    };
+
+    let mask = if size == Size::Quad { 0x3f } else { 0x1f };
+    Ok((count & mask) as u32)
+}
+
+// Intel Manual: SAL/SAR/SHL/SHR and RCL/RCR/ROL/ROR
+fn do_shift(emu: &mut Emulator, mne: &str, size: Size, count: u32, dest: &Argument) -> Result<(), InterpError> {
+    // The flags (and destination) are untouched when the count is 0
+    if count == 0 {
+        return Ok(());
+    }
+
+    let bits = 8 * size.bytes() as u32;
+    let mask = (1u128 << bits) - 1;
+    let msb = |val: u128| (val >> (bits - 1)) & 1 == 1;
+
+    let val = get_value(emu, dest, size)?;
+    let uval = size.truncate(val) as u128;
+    let carry = emu.getFlag(Flag::Carry);
+
+    // Compute the result along with the new CF and OF values
+    let (res, cf, of) = match mne {
+        "sal" | "shl" => {
+            let full = uval << count;
+            let res = full & mask;
+            let cf = (full >> bits) & 1 == 1;
+            (res, cf, msb(res) ^ cf)
         },
-        "jb" => {
-            if emu.getFlag(Flag::Carry) {
-                do_jump(emu, arg)
-            } else {
-                Ok(())
-            }
+        "shr" => {
+            let res = uval >> count;
+            (res, (uval >> (count - 1)) & 1 == 1, msb(uval))
         },
-        "jnae" => {
-            if emu.getFlag(Flag::Carry) {
-                do_jump(emu, arg)
-            } else {
-                Ok(())
-            }
+        "sar" => {
+            let sval = val as i128;
+            let res = ((sval >> count) as u128) & mask;
+            (res, (sval >> (count - 1)) & 1 == 1, false)
         },
-        "jbe" => {
-            if emu.getFlag(Flag::Zero) || emu.getFlag(Flag::Carry) {
-                do_jump(emu, arg)
-            } else {
-                Ok(())
-            }
+        "rol" | "ror" => {
+            let n = count % bits;
+            let res = match mne {
+                "rol" => ((uval << n) | (uval >> (bits - n))) & mask,
+                _ => ((uval >> n) | (uval << (bits - n))) & mask
+            };
+
+            // Rotates only affect CF and OF
+            let (cf, of) = match mne {
+                "rol" => (res & 1 == 1, msb(res) ^ (res & 1 == 1)),
+                _ => (msb(res), msb(res) ^ ((res >> (bits - 2)) & 1 == 1))
+            };
+            set_value(emu, dest, size, res as i64)?;
+            emu.setFlag(Flag::Carry, cf);
+            emu.setFlag(Flag::Overflow, of);
+            return Ok(());
         },
-        "jna" => {
-            if emu.getFlag(Flag::Zero) || emu.getFlag(Flag::Carry) {
-                do_jump(emu, arg)
-            } else {
-                Ok(())
-            }
+        "rcl" | "rcr" => {
+            // Rotate the (bits + 1)-wide value formed by CF:dest
+            let n = count % (bits + 1);
+            let wide = ((carry as u128) << bits) | uval;
+            let wide_mask = (1u128 << (bits + 1)) - 1;
+            let rotated = match mne {
+                "rcl" => ((wide << n) | (wide >> (bits + 1 - n))) & wide_mask,
+                _ => ((wide >> n) | (wide << (bits + 1 - n))) & wide_mask
+            };
+
+            let res = rotated & mask;
+            let cf = (rotated >> bits) & 1 == 1;
+            let of = match mne {
+                "rcl" => msb(res) ^ cf,
+                _ => msb(uval) ^ carry
+            };
+            set_value(emu, dest, size, res as i64)?;
+            emu.setFlag(Flag::Carry, cf);
+            emu.setFlag(Flag::Overflow, of);
+            return Ok(());
         },
-        "call" => Err("Unimplemented instruction: `call`".to_owned()),
-        _ => Err("Unknown instruction".to_owned())
+        _ => return Err(InterpError::UnknownMnemonic(mne.to_string()))
     };
 
-    emu.updatePC();
-    ret
+    set_value(emu, dest, size, res as i64)?;
+    emu.recordFlags(Lazy::new(Op::Logic, val, count as i64, res as i64, size));
+    emu.setFlag(Flag::Carry, cf);
+    emu.setFlag(Flag::Overflow, of);
+    Ok(())
 }
 
-fn run_two_arg(mne: &str, src: &Argument, dest: &Argument, emu: &mut Emulator) -> Result<(), String> {
-    use self::Argument::*;
+// Intel Manual: SHLD/SHRD (bits are shifted in from `src`)
+fn do_double_shift(emu: &mut Emulator, mne: &str, size: Size, count: u32, src: &Argument, dest: &Argument) -> Result<(), InterpError> {
+    if count == 0 {
+        return Ok(());
+    }
 
-    let ret = match mne {
-        "movs" => Err("Unimplemented instruction: `movs`".to_owned()),
-        "movz" => Err("Unimplemented instruction: `movz`".to_owned()),
-        "mov" => {
-            emu.clearFlags();
-            
-            let val = get_value(emu, src)?;
+    let bits = 8 * size.bytes() as u32;
+    let mask = (1u128 << bits) - 1;
+    let val = get_value(emu, dest, size)?;
+    let uval = size.truncate(val) as u128;
+    let usrc = size.truncate(get_value(emu, src, size)?) as u128;
 
-            match dest {
-                &Reg(ref r) => {
-                    let mut r = emu.getReg(r)?;
-                    r.set(val)
-                },
-                &Mem(ref base, off, _, _) => {
-                    let base = get_value(emu, &base)?;
-                    let mut m = emu.getMemory(base + off);
-                    m.set(val)
-                },
-                _ => Err("Invalid operand type to `mov`".to_owned())
-            }
-        },
-        "leaq" => Err("Unimplemented instruction: `leaq`".to_owned()),
-        "add" => {
-            emu.clearFlags();
-            
-            let val = get_value(emu, src)?;
+    // The bits are shifted through `dest:src:dest`
+    //   16bit counts above 16 are undefined, this matches the P6 and later processors
+    //   (the flags are undefined too, they're computed the same way as smaller counts)
+    let full = (uval << (2 * bits)) | (usrc << bits) | uval;
+    let (res, cf) = match mne {
+        "shld" => ((full >> (2 * bits - count)) & mask, (full >> (3 * bits - count)) & 1 == 1),
+        _ => ((full >> count) & mask, (full >> (count - 1)) & 1 == 1)
+    };
 
-            match dest {
-                &Reg(ref r) => {
-                    let mut r = emu.getReg(r)?;
-                    r += val;
-                    Ok(())
-                },
-                &Mem(ref base, off, _, _) => {
-                    let base = get_value(emu, &base)?;
-                    let mut m = emu.getMemory(base + off);
-                    m += val;
-                    Ok(())
-                },
-                _ => Err("Invalid operand type to `add`".to_owned())
-            }
+    // OF is set if the sign bit changed
+    let of = ((res ^ uval) >> (bits - 1)) & 1 == 1;
 
-            // TODO: Set OF, SF, ZF, AF, CF, PF flags
-        },
-        "sub" => {
-            emu.clearFlags();
-            
-            let val = get_value(emu, src)?;
+    set_value(emu, dest, size, res as i64)?;
+    emu.recordFlags(Lazy::new(Op::Logic, val, count as i64, res as i64, size));
+    emu.setFlag(Flag::Carry, cf);
+    emu.setFlag(Flag::Overflow, of);
+    Ok(())
+}
 
-            match dest {
-                &Reg(ref r) => {
-                    let mut r = emu.getReg(r)?;
-                    r -= val;
-                    Ok(())
-                },
-                &Mem(ref base, off, _, _) => {
-                    let base = get_value(emu, &base)?;
-                    let mut m = emu.getMemory(base + off);
-                    m -= val;
-                    Ok(())
-                },
-                _ => Err("Invalid operand type to `sub`".to_owned())
-            }
+fn get_value(emu: &mut Emulator, arg: &Argument, size: Size) -> Result<i64, InterpError> {
+    match *arg {
+        Argument::Reg(ref r) => Ok(emu.getReg(r)?.get()),
+        Argument::Literal(i) => Ok(i),
+        Argument::Address(ref lbl, off) => Ok(resolve_label(emu, lbl)?.wrapping_add(off as i64)),
+        Argument::Target(addr) => Ok(addr as i64),
+        Argument::Mem(..) | Argument::Label(_) => {
+            let addr = effective_address(emu, arg)?;
+            Ok(emu.getMemory(addr, size)?.get())
+        }
+    }
+}
+
+fn set_value(emu: &mut Emulator, arg: &Argument, size: Size, val: i64) -> Result<(), InterpError> {
+    let val = size.truncate(val) as i64;
 
-            // TODO: Set OF, SF, ZF, AF, CF, PF flags
+    match *arg {
+        Argument::Reg(ref r) if is_instruction_pointer(r) => Err(ip_write_error()),
+        Argument::Reg(ref r) => emu.getReg(r)?.set(val),
+        Argument::Mem(..) | Argument::Label(_) => {
+            let addr = effective_address(emu, arg)?;
+            emu.getMemory(addr, size)?.set(val)
         },
-        "imul" => {
-            emu.clearFlags();
-            
-            let val = get_value(emu, src)?;
+        _ => Err(InterpError::InvalidOperand("Attempt to call `set_value` with a non-location type".to_owned()))
+    }
+}
 
-            match dest {
-                &Reg(ref r) => {
-                    let mut r = emu.getReg(r)?;
-                    r *= val;
-                    Ok(())
-                },
-                &Mem(ref base, off, _, _) => {
-                    let base = get_value(emu, &base)?;
-                    let mut m = emu.getMemory(base + off);
-                    m *= val;
-                    Ok(())
-                },
-                _ => Err("Invalid operand type to `imul`".to_owned())
-            }
+// Perform `dest = dest <op> src` and record the resulting flags
+fn update(emu: &mut Emulator, op: Op, size: Size, src: &Argument, dest: &Argument, calc: fn(i64, i64) -> i64) -> Result<(), InterpError> {
+    let lhs = get_value(emu, dest, size)?;
+    let rhs = get_value(emu, src, size)?;
+    let res = calc(lhs, rhs);
+    set_value(emu, dest, size, res)?;
+    emu.recordFlags(Lazy::new(op, lhs, rhs, res, size));
+    Ok(())
+}
 
-            // TODO: Set SF, CF, OF flags
-        },
-        "xor" => {
-            emu.clearFlags();
-            
-            let val = get_value(emu, src)?;
+// Compute the address referenced by a memory operand (`disp(base, index, scale)`)
+//   Outside of jumps, a plain label refers to the memory at its address
+fn effective_address(emu: &mut Emulator, arg: &Argument) -> Result<i64, InterpError> {
+    match *arg {
+        Argument::Label(ref lbl) => resolve_label(emu, lbl),
+        Argument::Mem(ref base, ref idx, scale, disp, ref lbl) => {
+            let (base_reg, idx_reg) = (base.as_ref().map(|b| &**b), idx.as_ref().map(|i| &**i));
 
-            match dest {
-                &Reg(ref r) => {
-                    let mut r = emu.getReg(r)?;
-                    r ^= val;
-                    Ok(())
-                },
-                &Mem(ref base, off, _, _) => {
-                    let base = get_value(emu, &base)?;
-                    let mut m = emu.getMemory(base + off);
-                    m ^= val;
-                    Ok(())
-                },
-                _ => Err("Invalid operand type to `xor`".to_owned())
-            }
+            // Addresses are computed at the full width of the registers
+            //   `msg(%rip)` is the address of `msg` itself (the assembler makes it relative)
+            let base = match *base {
+                Some(ref base) if lbl.is_some() && is_instruction_pointer_arg(base) => 0,
+                Some(ref base) => get_value(emu, base, Size::Quad)?,
+                None => 0
+            };
+            let idx = match *idx {
+                Some(ref idx) => get_value(emu, idx, Size::Quad)?,
+                None => 0
+            };
 
-            // TODO: Set OF, CF, SF, ZF, PF flags
-        },
-        "or" => {
-            emu.clearFlags();
-            
-            let val = get_value(emu, src)?;
+            let disp = match *lbl {
+                Some(ref lbl) => resolve_label(emu, lbl)?.wrapping_add(disp as i64),
+                None => disp as i64
+            };
 
-            match dest {
-                &Reg(ref r) => {
-                    let mut r = emu.getReg(r)?;
-                    r |= val;
-                    Ok(())
-                },
-                &Mem(ref base, off, _, _) => {
-                    let base = get_value(emu, &base);
-                    let mut m = emu.getMemory(base? + off);
-                    m |= val;
-                    Ok(())
-                },
-                _ => Err("Invalid operand type to `or`".to_owned())
-            }
+            let addr = base.wrapping_add(idx.wrapping_mul(scale as i64)).wrapping_add(disp);
 
-            // TODO: Set OF, CF, SF, ZF, PF flags
+            // 32bit address registers (ie. `(%eax,%ecx,4)`) wrap the address to 32bits,
+            //   as does every address in 32bit mode (ie. a sign extended absolute address)
+            if is_long_register(base_reg) || is_long_register(idx_reg) || emu.getMode() == Mode::Bits32 {
+                Ok(Size::Long.truncate(addr) as i64)
+            } else {
+                Ok(addr)
+            }
         },
-        "and" => {
-            emu.clearFlags();
-            
-            let val = get_value(emu, src)?;
+        _ => Err(InterpError::InvalidOperand("Attempt to call `effective_address` with a non-memory type".to_owned()))
+    }
+}
 
-            match dest {
-                &Reg(ref r) => {
-                    let mut r = emu.getReg(r)?;
-                    r &= val;
-                    Ok(())
-                },
-                &Mem(ref base, off, _, _) => {
-                    let base = get_value(emu, &base)?;
-                    let mut m = emu.getMemory(base + off);
-                    m &= val;
-                    Ok(())
-                },
-                _ => Err("Invalid operand type to `and`".to_owned())
-            }
+// Labels in both the code and data sections resolve to their address
+fn resolve_label(emu: &Emulator, lbl: &str) -> Result<i64, InterpError> {
+    match emu.getSymbol(lbl) {
+        Some(addr) => Ok(addr as i64),
+        None => emu.getLabel(lbl).map(|addr| addr as i64).ok_or_else(|| InterpError::UndefinedLabel(lbl.to_string()))
+    }
+}
 
-            // TODO: Set OF, CF, SF, ZF, PF flags
-        },
-        "sal" => Err("Unimplemented instruction: `sal`".to_owned()),
-        "shl" => Err("Unimplemented instruction: `shl`".to_owned()),
-        "sar" => Err("Unimplemented instruction: `sar`".to_owned()),
-        "shr" => Err("Unimplemented instruction: `shr`".to_owned()),
-        "cmp" => Err("Unimplemented instruction: `cmp`".to_owned()),
-        "test" => Err("Unimplemented instruction: `test`".to_owned()),
-        "cmove" => Err("Unimplemented instruction: `cmove`".to_owned()),
-        "cmovz" => Err("Unimplemented instruction: `cmovz`".to_owned()),
-        "cmovne" => Err("Unimplemented instruction: `cmovne`".to_owned()),
-        "cmovnz" => Err("Unimplemented instruction: `cmovnz`".to_owned()),
-        "cmovs" => Err("Unimplemented instruction: `cmovs`".to_owned()),
-        "cmovns" => Err("Unimplemented instruction: `cmovns`".to_owned()),
-        "cmovg" => Err("Unimplemented instruction: `cmovg`".to_owned()),
-        "cmovnle" => Err("Unimplemented instruction: `cmovnle`".to_owned()),
-        "cmovge" => Err("Unimplemented instruction: `cmovge`".to_owned()),
-        "cmovnl" => Err("Unimplemented instruction: `cmovnl`".to_owned()),
-        "cmovl" => Err("Unimplemented instruction: `cmovl`".to_owned()),
-        "cmovnge" => Err("Unimplemented instruction: `cmovnge`".to_owned()),
-        "cmovle" => Err("Unimplemented instruction: `cmovle`".to_owned()),
-        "cmovng" => Err("Unimplemented instruction: `cmovng`".to_owned()),
-        "cmova" => Err("Unimplemented instruction: `cmova`".to_owned()),
-        "cmovnbe" => Err("Unimplemented instruction: `cmovnbe`".to_owned()),
-        "cmovae" => Err("Unimplemented instruction: `cmovae`".to_owned()),
-        "cmovnb" => Err("Unimplemented instruction: `cmovnb`".to_owned()),
-        "cmovb" => Err("Unimplemented instruction: `cmovb`".to_owned()),
-        "cmovnae" => Err("Unimplemented instruction: `cmovnae`".to_owned()),
-        "cmovbe" => Err("Unimplemented instruction: `cmovbe`".to_owned()),
-        "cmovna" => Err("Unimplemented instruction: `cmovna`".to_owned()),
-        _ => Err("Unknown instruction".to_owned())
-    };
+fn is_long_register(arg: Option<&Argument>) -> bool {
+    match arg {
+        Some(Argument::Reg(r)) => ximpl::reg_location(r).map(|(_, _, len)| len) == Some(4),
+        _ => false
+    }
+}
 
-    emu.updatePC();
-    ret
+// Handle the common code for all stack manipulations
+fn push_value(emu: &mut Emulator, val: i64, size: Size) -> Result<(), InterpError> {
+    let sp = emu.getReg("rsp")?.get() - size.bytes() as i64;
+    emu.getMemory(sp, size)?.set(val)?;
+    emu.getReg("rsp")?.set(sp)
 }
 
-fn get_value(emu: &mut Emulator, arg: &Argument) -> Result<i32, String> {
-    match arg {
-        &Argument::Reg(ref r) => Ok(emu.getReg(r)?.get()),
-        &Argument::Literal(i) => Ok(i),
-        &Argument::Mem(ref base, off, _, _) => {
-            let base = get_value(emu, base)?;
-            Ok(emu.getMemory(base + off).get())
-        },
-        _ => Err("Attempt to call `get_value` with a non-value type".to_owned())
+fn pop_value(emu: &mut Emulator, size: Size) -> Result<i64, InterpError> {
+    let sp = emu.getReg("rsp")?.get();
+    let val = emu.getMemory(sp, size)?.get();
+    emu.getReg("rsp")?.set(sp + size.bytes() as i64)?;
+    Ok(val)
+}
+
+// Select the stack/frame pointers matching the stack operation size
+fn frame_registers(size: Size) -> Result<(&'static str, &'static str), InterpError> {
+    match size {
+        Size::Word => Ok(("sp", "bp")),
+        Size::Long => Ok(("esp", "ebp")),
+        Size::Quad => Ok(("rsp", "rbp")),
+        Size::Byte => Err(InterpError::InvalidOperand("Stack frames can't be byte sized".to_owned()))
     }
 }
 
 // Handle the common code for all jump commands
-fn do_jump(emu: &mut Emulator, arg: &Argument) -> Result<(), String> {
-    match arg {
-        &Argument::Label(ref s) => {
-            emu.gotoLabel(s);
-            // TODO: Remove once I no longer have the 'updatePC' at the bottom
-            let pc = emu.getPC();
-            emu.setPC(pc - 1);
+//   Register and memory operands hold the target address (ie. `jmp *%eax` or `call *(%ebx)`)
+fn do_jump(emu: &mut Emulator, size: Size, arg: &Argument) -> Result<(), InterpError> {
+    match *arg {
+        Argument::Label(ref s) => emu.gotoLabel(s),
+        Argument::Target(addr) => {
+            emu.setPC(addr);
             Ok(())
         },
-        // TODO: Be sure to divide the result by 4 (or whatever)
-        &Argument::Reg(_) => Err("TODO: Add in register jump addressing".to_owned()),
-        &Argument::Mem(_, _, _, _) => Err("TODO: Add in memory jump addressing".to_owned()),
-        _ => Err("Encountered invalid jump argument type".to_owned())
+        Argument::Reg(_) | Argument::Mem(..) => {
+            let addr = get_value(emu, arg, size)?;
+            emu.setPC(size.truncate(addr));
+            Ok(())
+        },
+        _ => Err(InterpError::InvalidOperand("Encountered invalid jump argument type".to_owned()))
     }
-}
\ No newline at end of file
+}
+
+// `%rip` can be read (ie. `lea 0(%rip), %rax`), but only jumps change it
+fn is_instruction_pointer(reg: &str) -> bool {
+    ximpl::reg_location(reg).map(|(idx, _, _)| idx) == Some(ximpl::RIP)
+}
+
+fn is_instruction_pointer_arg(arg: &Argument) -> bool {
+    match *arg {
+        Argument::Reg(ref r) => is_instruction_pointer(r),
+        _ => false
+    }
+}
+
+fn ip_write_error() -> InterpError {
+    InterpError::InvalidOperand("`%rip` can only be changed by jumps, calls and returns".to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use emu::Emulator;
+    use error::InterpError;
+    use host::NullIo;
+    use memory::{Layout, DEFAULT_STACK_SIZE};
+    use ximpl::{Flag, Size, Syntax};
+
+    fn run(layout: Layout, code: &str) -> Emulator {
+        run_syntax(layout, Syntax::ATT, code)
+    }
+
+    fn run_syntax(layout: Layout, syntax: Syntax, code: &str) -> Emulator {
+        let mut emu = Emulator::with_layout(layout, DEFAULT_STACK_SIZE);
+        emu.setIo(Box::new(NullIo));
+        emu.load(code.lines().map(|l| l.to_owned()), syntax).unwrap();
+        emu.execute().unwrap();
+        emu
+    }
+
+    fn reg(emu: &mut Emulator, reg: &str) -> u64 {
+        emu.getReg(reg).unwrap().get_unsigned()
+    }
+
+    fn mem(emu: &mut Emulator, addr: u64, size: Size) -> u64 {
+        emu.getMemory(addr as i64, size).unwrap().get_unsigned()
+    }
+
+    // Three nested frames: `enter $8, $2` copies the display of the level 1 frame
+    fn check_nested_enter(layout: Layout, sp: &str, bp: &str, size: Size) {
+        let code = "enter $0, $0\nenter $0, $1\nenter $8, $2";
+        let mut emu = run(layout, code);
+        let word = size.bytes() as u64;
+
+        let mut start = Emulator::with_layout(layout, DEFAULT_STACK_SIZE);
+        let top = reg(&mut start, sp);
+
+        // Each frame points at the saved frame pointer, followed by the display of enclosing frames
+        let outer = top - word;
+        let middle = outer - word;
+        let inner = middle - 2 * word;
+
+        assert_eq!(reg(&mut emu, bp), inner);
+        assert_eq!(reg(&mut emu, sp), inner - 2 * word - 8);
+        assert_eq!(mem(&mut emu, inner, size), middle);
+        assert_eq!(mem(&mut emu, inner - word, size), middle);
+        assert_eq!(mem(&mut emu, inner - 2 * word, size), inner);
+        assert_eq!(mem(&mut emu, middle, size), outer);
+        assert_eq!(mem(&mut emu, middle - word, size), middle);
+    }
+
+    #[test]
+    fn nested_enter_i386() {
+        check_nested_enter(Layout::I386, "esp", "ebp", Size::Long);
+    }
+
+    #[test]
+    fn nested_enter_x86_64() {
+        check_nested_enter(Layout::X86_64, "rsp", "rbp", Size::Quad);
+    }
+
+    fn flag(emu: &Emulator, flag: Flag) -> bool {
+        emu.getFlag(flag)
+    }
+
+    #[test]
+    fn add_and_subtract_with_carry() {
+        // 0x1_ffffffff + 0x1 in %edx:%eax, then back again
+        let mut emu = run(Layout::I386, "movl $-1, %eax\nmovl $1, %edx\naddl $1, %eax\nadcl $0, %edx\n\
+                                         movl %eax, %ebx\nmovl %edx, %ecx\nsubl $1, %ebx\nsbbl $0, %ecx");
+        assert_eq!((reg(&mut emu, "edx"), reg(&mut emu, "eax")), (2, 0));
+        assert_eq!((reg(&mut emu, "ecx"), reg(&mut emu, "ebx")), (1, 0xffff_ffff));
+        assert!(!flag(&emu, Flag::Carry));
+
+        // The carry in is enough to carry out (and borrow out)
+        //   `movb $0, %cl; cmpb $1, %cl` sets the carry flag
+        let mut emu = run(Layout::I386, "movb $0xff, %al\nmovb $0, %cl\ncmpb $1, %cl\nadcb $0, %al");
+        assert_eq!(reg(&mut emu, "al"), 0);
+        assert!(flag(&emu, Flag::Carry) && flag(&emu, Flag::Zero) && !flag(&emu, Flag::Overflow));
+
+        let mut emu = run(Layout::I386, "movb $0, %al\nmovb $0, %cl\ncmpb $1, %cl\nsbbb $0, %al");
+        assert_eq!(reg(&mut emu, "al"), 0xff);
+        assert!(flag(&emu, Flag::Carry) && flag(&emu, Flag::Sign) && !flag(&emu, Flag::Overflow));
+
+        let mut emu = run(Layout::X86_64, "movq $0x7fffffffffffffff, %rax\nmovb $0, %cl\ncmpb $1, %cl\nadcq $0, %rax");
+        assert_eq!(reg(&mut emu, "rax"), 0x8000_0000_0000_0000);
+        assert!(!flag(&emu, Flag::Carry) && flag(&emu, Flag::Overflow));
+    }
+
+    #[test]
+    fn one_operand_multiply() {
+        let mut emu = run(Layout::I386, "movl $0x10000, %eax\nmovl $0x10000, %ebx\nmull %ebx");
+        assert_eq!((reg(&mut emu, "edx"), reg(&mut emu, "eax")), (1, 0));
+        assert!(flag(&emu, Flag::Carry) && flag(&emu, Flag::Overflow));
+
+        // CF/OF are only set when the upper half is needed (for `imul`, more than a sign extension)
+        let mut emu = run(Layout::I386, "movl $3, %eax\nmovl $4, %ecx\nmull %ecx");
+        assert_eq!((reg(&mut emu, "edx"), reg(&mut emu, "eax")), (0, 12));
+        assert!(!flag(&emu, Flag::Carry) && !flag(&emu, Flag::Overflow));
+
+        let mut emu = run(Layout::I386, "movw $-1, %ax\nmovw $2, %bx\nimulw %bx");
+        assert_eq!((reg(&mut emu, "dx"), reg(&mut emu, "ax")), (0xffff, 0xfffe));
+        assert!(!flag(&emu, Flag::Carry));
+
+        // Byte forms write all of `%ax`
+        let mut emu = run(Layout::I386, "movb $-2, %al\nmovb $3, %bl\nimulb %bl\nmovw %ax, %cx\n\
+                                         movb $100, %al\nimulb %bl");
+        assert_eq!(reg(&mut emu, "cx"), 0xfffa);
+        assert_eq!(reg(&mut emu, "ax"), 300);
+        assert!(flag(&emu, Flag::Carry) && flag(&emu, Flag::Overflow));
+        let mut emu = run(Layout::I386, "movb $200, %al\nmovb $2, %bl\nmulb %bl");
+        assert_eq!(reg(&mut emu, "ax"), 400);
+
+        let mut emu = run(Layout::X86_64, "movq $-1, %rax\nmovq $-1, %rbx\nmulq %rbx");
+        assert_eq!((reg(&mut emu, "rdx"), reg(&mut emu, "rax")), (0xffff_ffff_ffff_fffe, 1));
+    }
+
+    #[test]
+    fn two_and_three_operand_multiply() {
+        let mut emu = run(Layout::I386, "movl $-3, %eax\nmovl $7, %ebx\nimull %eax, %ebx\n\
+                                         movl $6, %ecx\nimull $-5, %ecx, %edx\nmovl $9, %esi\nimull $3, %esi");
+        assert_eq!(reg(&mut emu, "ebx") as i32, -21);
+        assert_eq!(reg(&mut emu, "edx") as i32, -30);
+        assert_eq!(reg(&mut emu, "esi"), 27);
+        assert!(!flag(&emu, Flag::Carry) && !flag(&emu, Flag::Overflow));
+
+        // The result is truncated to the destination
+        let mut emu = run(Layout::I386, "movl $0x10000, %eax\nimull %eax, %eax\nmovw $0x100, %bx\nimulw $0x100, %bx, %cx");
+        assert_eq!(reg(&mut emu, "eax"), 0);
+        assert_eq!(reg(&mut emu, "cx"), 0);
+        assert!(flag(&emu, Flag::Carry) && flag(&emu, Flag::Overflow));
+    }
+
+    #[test]
+    fn divide() {
+        let mut emu = run(Layout::I386, "movl $0, %edx\nmovl $100, %eax\nmovl $7, %ecx\ndivl %ecx");
+        assert_eq!((reg(&mut emu, "eax"), reg(&mut emu, "edx")), (14, 2));
+
+        // The quotient is truncated towards 0, and the remainder has the sign of the dividend
+        let mut emu = run(Layout::I386, "movl $-100, %eax\ncltd\nmovl $7, %ecx\nidivl %ecx");
+        assert_eq!((reg(&mut emu, "eax") as i32, reg(&mut emu, "edx") as i32), (-14, -2));
+
+        let mut emu = run(Layout::I386, "movw $1000, %ax\nmovb $10, %bl\ndivb %bl\nmovw %ax, %cx\n\
+                                         movw $-7, %ax\nmovb $2, %bl\nidivb %bl");
+        assert_eq!(reg(&mut emu, "cx"), 100);
+        assert_eq!((reg(&mut emu, "al"), reg(&mut emu, "ah")), (0xfd, 0xff));
+
+        let mut emu = run(Layout::X86_64, "movq $1, %rdx\nmovq $0, %rax\nmovq $2, %rcx\ndivq %rcx");
+        assert_eq!((reg(&mut emu, "rax"), reg(&mut emu, "rdx")), (1 << 63, 0));
+    }
+
+    #[test]
+    fn divide_errors() {
+        let fault = |code: &str| {
+            let mut emu = Emulator::with_layout(Layout::I386, DEFAULT_STACK_SIZE);
+            emu.setIo(Box::new(NullIo));
+            emu.load(code.lines().map(|l| l.to_owned()), Syntax::ATT).unwrap();
+            match emu.execute() {
+                Err(InterpError::Located(line, _, err)) => (line, *err),
+                res => panic!("`{}` didn't fault: {:?}", code, res)
+            }
+        };
+        let too_large = InterpError::DivideError("quotient is too large".to_owned());
+
+        assert_eq!(fault("movl $0, %edx\nmovl $1, %eax\nmovl $0, %ecx\ndivl %ecx"),
+                   (4, InterpError::DivideError("division by zero".to_owned())));
+        assert_eq!(fault("movl $1, %edx\nmovl $0, %eax\nmovl $1, %ecx\ndivl %ecx"), (4, too_large.clone()));
+        assert_eq!(fault("movl $0x80000000, %eax\ncltd\nmovl $-1, %ecx\nidivl %ecx"), (4, too_large.clone()));
+        assert_eq!(fault("movw $0x100, %ax\nmovb $1, %bl\ndivb %bl"), (3, too_large.clone()));
+        assert_eq!(fault("movw $128, %ax\nmovb $1, %bl\nidivb %bl"), (3, too_large));
+    }
+
+    #[test]
+    fn extension_moves() {
+        let mut emu = run(Layout::I386, "movl $0xff, %ebx\nmovsbl %bl, %eax\nmovzbl %bl, %ecx\nmovsbw %bl, %dx\n\
+                                         movl $0x8000, %ebx\nmovswl %bx, %esi\nmovzwl %bx, %edi");
+        assert_eq!(reg(&mut emu, "eax"), 0xffff_ffff);
+        assert_eq!(reg(&mut emu, "ecx"), 0xff);
+        assert_eq!(reg(&mut emu, "dx"), 0xffff);
+        assert_eq!(reg(&mut emu, "esi"), 0xffff_8000);
+        assert_eq!(reg(&mut emu, "edi"), 0x8000);
+
+        // `movsx`/`movzx` take the source size from the register, or memory operand suffix
+        let mut emu = run(Layout::I386, ".data\nb: .byte 0x80\n.text\nmovb $-2, %al\nmovsx %al, %ecx\nmovzx %al, %edx\n\
+                                         movsbl b, %esi\nmovzbl b, %edi\nmovl $0x12345678, %ebx\nmovzbw %al, %bx");
+        assert_eq!(reg(&mut emu, "ecx"), 0xffff_fffe);
+        assert_eq!(reg(&mut emu, "edx"), 0xfe);
+        assert_eq!(reg(&mut emu, "esi"), 0xffff_ff80);
+        assert_eq!(reg(&mut emu, "edi"), 0x80);
+        assert_eq!(reg(&mut emu, "ebx"), 0x1234_00fe);      // a 16bit destination keeps the upper half
+
+        let mut emu = run(Layout::X86_64, "movq $-1, %rax\nmovl $-3, %ebx\nmovslq %ebx, %rcx\nmovsxd %ebx, %rdx\nmovzbl %bl, %eax");
+        assert_eq!(reg(&mut emu, "rcx"), (-3i64) as u64);
+        assert_eq!(reg(&mut emu, "rdx"), (-3i64) as u64);
+        assert_eq!(reg(&mut emu, "rax"), 0xfd);
+    }
+
+    #[test]
+    fn accumulator_extension() {
+        let mut emu = run(Layout::I386, "movl $0x12340080, %eax\ncbtw\nmovl %eax, %ecx\n\
+                                         movl $0x8000, %eax\ncwtl\nmovl %eax, %esi\nmovw $0x8000, %ax\ncwtd");
+        assert_eq!(reg(&mut emu, "ecx"), 0x1234_ff80);        // only `%ax` is written
+        assert_eq!(reg(&mut emu, "esi"), 0xffff_8000);
+        assert_eq!(reg(&mut emu, "dx"), 0xffff);
+
+        let mut emu = run(Layout::I386, "movl $-5, %eax\ncltd\nmovl %edx, %ebx\nmovl $5, %eax\ncdq");
+        assert_eq!(reg(&mut emu, "ebx"), 0xffff_ffff);
+        assert_eq!(reg(&mut emu, "edx"), 0);
+
+        let mut emu = run(Layout::X86_64, "movl $0x80000000, %eax\ncltq\nmovq %rax, %rbx\ncqto");
+        assert_eq!(reg(&mut emu, "rbx"), 0xffff_ffff_8000_0000);
+        assert_eq!(reg(&mut emu, "rdx"), u64::MAX);
+    }
+
+    #[test]
+    fn conditional_moves() {
+        // (lhs, rhs, conditions that hold after `cmpl $rhs, lhs`)
+        //   1 < 2 either way, -1 is above 1 but less than it, and 3 == 3
+        let cases: [(i32, i32, &[&str]); 3] = [
+            (1, 2, &["b", "c", "nae", "ne", "nz", "be", "na", "s", "l", "nge", "le", "ng", "no", "p", "pe"]),
+            (-1, 1, &["ae", "nb", "nc", "ne", "nz", "a", "nbe", "s", "l", "nge", "le", "ng", "no", "po", "np"]),
+            (3, 3, &["ae", "nb", "nc", "e", "z", "be", "na", "ns", "ge", "nl", "le", "ng", "no", "p", "pe"]),
+        ];
+        let all = ["o", "no", "b", "c", "nae", "ae", "nb", "nc", "e", "z", "ne", "nz", "be", "na", "a", "nbe",
+                   "s", "ns", "p", "pe", "np", "po", "l", "nge", "ge", "nl", "le", "ng", "g", "nle"];
+
+        for &(lhs, rhs, holds) in cases.iter() {
+            for cond in all.iter() {
+                let code = format!("movl ${}, %eax\ncmpl ${}, %eax\nmovl $0, %ebx\nmovl $1, %ecx\ncmov{} %ecx, %ebx", lhs, rhs, cond);
+                let mut emu = run(Layout::I386, &code);
+                assert_eq!(reg(&mut emu, "ebx") == 1, holds.contains(cond), "cmov{} after {} vs {}", cond, lhs, rhs);
+            }
+        }
+
+        // Memory sources, and 16bit operands
+        let mut emu = run(Layout::I386, ".data\nval: .long 42\n.text\nmovl $0, %eax\ntestl %eax, %eax\ncmovzl val, %edx\n\
+                                         movl $0x12345678, %esi\nmovw $0x9999, %cx\ncmovew %cx, %si");
+        assert_eq!(reg(&mut emu, "edx"), 42);
+        assert_eq!(reg(&mut emu, "esi"), 0x1234_9999);
+
+        // A 32bit destination is zero extended even when the condition doesn't hold
+        let mut emu = run(Layout::X86_64, "movq $-1, %rbx\nmovl $2, %ecx\nmovl $0, %eax\ncmpl $1, %eax\ncmovel %ecx, %ebx");
+        assert_eq!(reg(&mut emu, "rbx"), 0xffff_ffff);
+    }
+
+    #[test]
+    fn exchange() {
+        let mut emu = run(Layout::I386, "movl $1, %eax\nmovl $2, %ebx\nxchg %eax, %ebx\n\
+                                         movl $3, %ecx\npushl $4\nxchg %ecx, (%esp)\npopl %edx");
+        assert_eq!((reg(&mut emu, "eax"), reg(&mut emu, "ebx")), (2, 1));
+        assert_eq!((reg(&mut emu, "ecx"), reg(&mut emu, "edx")), (4, 3));
+
+        // 32bit destinations are zero extended (even when swapping `%eax` with itself)
+        let mut emu = run(Layout::X86_64, "movq $-1, %rax\nmovq $-1, %rbx\nxchgl %eax, %ebx\nmovq $-1, %rcx\nxchg %ecx, %ecx");
+        assert_eq!(reg(&mut emu, "rax"), 0xffff_ffff);
+        assert_eq!(reg(&mut emu, "rbx"), 0xffff_ffff);
+        assert_eq!(reg(&mut emu, "rcx"), 0xffff_ffff);
+    }
+
+    #[test]
+    fn not_keeps_the_flags() {
+        let mut emu = run(Layout::I386, "movl $0x0f0f0f0f, %eax\nmovb $0, %cl\ncmpb $1, %cl\nnotl %eax\nmovb $0, %bl\nnotb %bl");
+        assert_eq!(reg(&mut emu, "eax"), 0xf0f0_f0f0);
+        assert_eq!(reg(&mut emu, "bl"), 0xff);
+        assert!(flag(&emu, Flag::Carry));
+    }
+
+    #[test]
+    fn shift_counts_are_masked() {
+        // 32bit counts are masked to 5 bits, 64bit ones to 6 bits
+        let mut emu = run(Layout::X86_64, "movl $1, %eax\nmovb $33, %cl\nshll %cl, %eax\n\
+                                           movq $1, %rbx\nmovb $65, %cl\nshlq %cl, %rbx\nmovq $1, %rdx\nshlq $33, %rdx");
+        assert_eq!(reg(&mut emu, "eax"), 2);
+        assert_eq!(reg(&mut emu, "rbx"), 2);
+        assert_eq!(reg(&mut emu, "rdx"), 1 << 33);
+
+        // A (masked) count of 0 leaves the flags alone
+        let mut emu = run(Layout::I386, "movl $5, %eax\nmovb $0, %cl\ncmpb $1, %cl\nmovb $32, %cl\nshrl %cl, %eax\nroll $0, %eax");
+        assert_eq!(reg(&mut emu, "eax"), 5);
+        assert!(flag(&emu, Flag::Carry) && !flag(&emu, Flag::Zero));
+
+        // 8bit rotates go round more than once (and rcl/rcr by 9 is a full turn through the carry)
+        let mut emu = run(Layout::I386, "movb $0x81, %al\nmovb $9, %cl\nrolb %cl, %al\nmovb $0x81, %bl\nrclb %cl, %bl");
+        assert_eq!(reg(&mut emu, "al"), 0x03);
+        assert_eq!(reg(&mut emu, "bl"), 0x81);
+    }
+
+    #[test]
+    fn shift_and_rotate_flags() {
+        let expected = [
+            // (code, result, CF, OF)
+            ("movl $0x80000001, %eax\nshll $1, %eax", 0x2, true, true),
+            ("movl $0x40000000, %eax\nshll $1, %eax", 0x8000_0000, false, true),
+            ("movl $3, %eax\nshrl $1, %eax", 0x1, true, false),
+            ("movl $0x80000000, %eax\nshrl $1, %eax", 0x4000_0000, false, true),
+            ("movl $-8, %eax\nsarl $2, %eax", 0xffff_fffe, false, false),
+            ("movl $-7, %eax\nsarl $1, %eax", 0xffff_fffc, true, false),
+            ("movl $0x81, %eax\nrolb $1, %al", 0x03, true, true),
+            ("movl $0x01, %eax\nrorb $1, %al", 0x80, true, true),
+            ("movl $0x40, %eax\nrorb $1, %al", 0x20, false, false),
+            ("movl $0x80, %eax\nmovb $0, %cl\ncmpb $1, %cl\nrclb $1, %al", 0x01, true, true),
+            ("movl $0x01, %eax\nmovb $0, %cl\ncmpb $1, %cl\nrcrb $1, %al", 0x80, true, true),
+        ];
+        for &(code, res, cf, of) in expected.iter() {
+            let mut emu = run(Layout::I386, code);
+            assert_eq!(reg(&mut emu, "eax"), res, "{}", code);
+            assert_eq!((flag(&emu, Flag::Carry), flag(&emu, Flag::Overflow)), (cf, of), "{}", code);
+        }
+    }
+
+    #[test]
+    fn double_shifts() {
+        let mut emu = run(Layout::I386, "movl $0x12345678, %eax\nmovl $0x9abcdef0, %ebx\nshldl $8, %ebx, %eax\n\
+                                         movl $0x12345678, %ecx\nshrdl $4, %ebx, %ecx");
+        assert_eq!(reg(&mut emu, "eax"), 0x3456_789a);
+        assert_eq!(reg(&mut emu, "ecx"), 0x0123_4567);
+        assert!(flag(&emu, Flag::Carry));      // bit 3 of `%ecx` was shifted out last
+
+        // Counts above 16 are undefined for 16bit operands, but don't fault (results from a Skylake)
+        let expected = [(17, 0xacf0, 0x2b3c), (20, 0x6781, 0x4567), (24, 0x7812, 0x3456), (31, 0x091a, 0x2468)];
+        for &(count, shld, shrd) in expected.iter() {
+            let code = format!("movw $0x1234, %ax\nmovw $0x5678, %bx\nmovb ${}, %cl\nshldw %cl, %bx, %ax\n\
+                                movw $0x1234, %dx\nshrdw %cl, %bx, %dx", count);
+            let mut emu = run(Layout::I386, &code);
+            assert_eq!(reg(&mut emu, "ax"), shld, "shld by {}", count);
+            assert_eq!(reg(&mut emu, "dx"), shrd, "shrd by {}", count);
+        }
+    }
+
+    #[test]
+    fn leave_unwinds_nested_enter() {
+        let mut emu = run(Layout::I386, "mov %esp, %ebx\nenter $0, $0\nenter $16, $2\nleave\nleave");
+        let start = reg(&mut emu, "ebx");
+        assert_eq!(reg(&mut emu, "esp"), start);
+    }
+
+    #[test]
+    fn intel_operand_order() {
+        // The destination comes first
+        let mut emu = run_syntax(Layout::I386, Syntax::Intel, "mov eax, 5\nmov ebx, eax\nsub ebx, 2\nmov ecx, 1\ncmp ecx, 2\n\
+                                                               imul edx, ebx, 4\nshl eax, 3");
+        assert_eq!((reg(&mut emu, "eax"), reg(&mut emu, "ebx")), (40, 3));
+        assert_eq!(reg(&mut emu, "edx"), 12);
+        assert!(!flag(&emu, Flag::Carry));      // `shl` overwrote the borrow of `cmp ecx, 2`
+
+        let code = ".data\nvals: .long 1, 2, 3, 4\n.text\nmov ebx, offset vals\nmov ecx, 2\n\
+                    mov eax, dword ptr [ebx+ecx*4]\nmov dword ptr [ebx + 4], 9\nadd eax, [vals + 4]\n\
+                    movzx edx, byte ptr [ebx + ecx*4 - 4]\nmov esi, [ebx + 1*ecx + 10]";
+        let mut emu = run_syntax(Layout::I386, Syntax::Intel, code);
+        assert_eq!(reg(&mut emu, "eax"), 12);
+        assert_eq!(reg(&mut emu, "edx"), 9);
+        assert_eq!(reg(&mut emu, "esi"), 4);
+
+        // Switching syntax part way through the program
+        let mut emu = run(Layout::I386, "movl $7, %eax\n.intel_syntax noprefix\nmov ebx, eax\nsub ebx, 1\n.att_syntax\nsubl $1, %ebx");
+        assert_eq!(reg(&mut emu, "ebx"), 5);
+        let mut emu = run_syntax(Layout::X86_64, Syntax::Intel, "mov rax, -1\nmov eax, 2\nmov rbx, -1\nsub rbx, rax");
+        assert_eq!(reg(&mut emu, "rax"), 2);
+        assert_eq!(reg(&mut emu, "rbx"), (-3i64) as u64);
+    }
+}
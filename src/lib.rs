@@ -1,38 +1,33 @@
 extern crate libc;
 extern crate nom;
-extern crate bit_vec;
 
 mod x86;
 mod ximpl;
+mod flags;
 mod parse;
 mod emu;
 mod inter;
 mod view;
+mod memory;
+mod data;
+mod encode;
+mod decode;
+mod elf;
+mod syscall;
+mod host;
+mod debugger;
+mod error;
+mod ffi;
 
-use std::io::BufReader;
-use std::fs::File;
-use std::io::prelude::*;
-use x86::interpret_iter;
+// Pass on the `interpret_code` functions for rust usage
+pub use x86::{interpret_code, interpret_code_syntax, interpret_code_with_layout, run_program};
+pub use emu::{Emulator, StopReason};
+pub use decode::Disassembly;
+pub use host::{GuestIo, Stream, HostIo, BufferIo, NullIo};
+pub use memory::{Layout, Region, DEFAULT_STACK_SIZE, PAGE_SIZE};
+pub use ximpl::Syntax;
+pub use error::InterpError;
+pub use debugger::debug;
+pub use parse::parse_int;
 
-// Pass on the `interpret_code` function for rust usage
-pub use x86::interpret_code;
-
-// Add in a C FFI interface as well
-#[no_mangle]
-pub extern "C" fn interpret_file(file: *const libc::c_char) {
-    let file = BufReader::new(
-        File::open(&to_rust_string(file)).expect("Unable to open file"));
-
-    interpret_iter(file.lines().map(|l| l.unwrap()));
-}
-
-#[no_mangle]
-pub extern "C" fn interpret_string(c_str: *const libc::c_char) {
-    interpret_code(&to_rust_string(c_str));
-}
-
-fn to_rust_string(c_str: *const libc::c_char) -> String {
-    unsafe {
-         ::std::ffi::CStr::from_ptr(c_str).to_string_lossy().into_owned()
-    }
-}
\ No newline at end of file
+// The C FFI interface is defined in `ffi.rs` (with the header in `include/`)
@@ -1,20 +1,15 @@
-extern crate nom;
-extern crate bit_vec;
-
-mod parse;
-mod ximpl;
-mod emu;
-mod inter;
-mod view;
-mod x86;
+extern crate x86_interpreter;
 
+use std::env;
+use std::fs;
 use std::io;
+use std::process;
 use std::vec::Vec;
+use x86_interpreter::{Emulator, Layout, Syntax, DEFAULT_STACK_SIZE, parse_int, run_program};
 
 // TODO: Ensure all implemented instructions handle cpu flags
     // Look into whether the current approach is "good"
-// TODO: Implement `test` and `cmp` instructions
-// TODO: Move from panics to Results (make ffi exception-safe)
+// TODO: Make the ffi exception-safe
 // TODO: Implement complete x86 instructions
     // Move over to implementing from the other sheet though
 // TODO: Add in command line interfacing ???
@@ -24,27 +19,193 @@ use std::vec::Vec;
 // TODO: Work on displaying of register/memory dumps
     // Add in finer grain control of memory dumps from x86 "special" instructions
     // ie. When do they get displayed/allow for control of display
-// TODO: Add in parsing of non-AT&T assembly syntax
 // TODO: Add in rust tests
 
 fn main() {
+    let (layout, stack_size, debug, program) = match parse_args(env::args().skip(1)) {
+        Ok(args) => args,
+        Err(msg) => {
+            eprintln!("{}", msg);
+            eprintln!("Usage: x86_inter [-l|--layout i386|x86_64] [-s|--stack <bytes>[K|M|G]] [-d|--debug] [<program> [<args>...]]");
+            process::exit(1);
+        }
+    };
+
+    // Run (or debug) a program file instead of starting the REPL
+    if !program.is_empty() {
+        let mut emu = match load_file(&program, layout, stack_size) {
+            Ok(emu) => emu,
+            Err(msg) => {
+                eprintln!("{}", msg);
+                process::exit(1);
+            }
+        };
+        process::exit(if debug { x86_interpreter::debug(&mut emu) } else { run_loaded(&mut emu) });
+    }
+
+    // The last program is kept around for `:dis`
+    let mut last = None;
+
     loop {
         match read_multiline() {
+            Some(ref s) if s.starts_with(':') => {
+                if let Err(msg) = run_command(s, &mut last, layout, stack_size) {
+                    println!("{}", msg);
+                }
+                println!();
+            },
             Some(ref s) => {
-                x86::interpret_code(s);
-                println!("");   
+                let mut emu = Emulator::with_layout(layout, stack_size);
+                let lines = s.split("\n").map(|s| s.to_owned());
+                let res = if debug {
+                    emu.load(lines, Syntax::ATT).map(|_| { x86_interpreter::debug(&mut emu); })
+                } else {
+                    run_program(&mut emu, lines, Syntax::ATT)
+                };
+                if let Err(e) = res {
+                    println!("{}", e);
+                }
+                println!();
+                last = Some(emu);
             },
             None => break,
         }
     }
 }
 
+// REPL commands
+//   `:dis [<addr> <len>]` disassembles memory of the last program (all of its code by default)
+//   `:hex <bytes>` runs raw machine code (ie. `:hex b8 04 00 00 00`)
+fn run_command(cmd: &str, last: &mut Option<Emulator>, layout: Layout, stack_size: u64) -> Result<(), String> {
+    let mut words = cmd.split_whitespace();
+
+    match words.next() {
+        Some(":dis") => {
+            let emu = last.as_mut().ok_or("There is no program to disassemble")?;
+            let (addr, len) = match (words.next(), words.next()) {
+                (None, _) => {
+                    let (start, end) = emu.getCodeRange().ok_or("There is no program to disassemble")?;
+                    (start, end - start)
+                },
+                (Some(addr), Some(len)) => {
+                    let addr = parse_int(addr).ok_or(format!("Invalid address: `{}`", addr))?;
+                    (addr, parse_int(len).ok_or(format!("Invalid length: `{}`", len))?)
+                },
+                _ => return Err("Usage: :dis [<addr> <len>]".to_owned())
+            };
+
+            for inst in emu.disassemble(addr, len).map_err(|e| e.to_string())? {
+                println!("{}", inst);
+            }
+            Ok(())
+        },
+        Some(":hex") => {
+            let bytes = parse_hex(&words.collect::<String>()).ok_or("Expected machine code as hex bytes (ie. `b8 04 00 00 00`)")?;
+
+            let mut emu = Emulator::with_layout(layout, stack_size);
+            let res = emu.loadBytes(&bytes).and_then(|_| emu.execute());
+            emu.dump_all();
+            *last = Some(emu);
+            res.map_err(|e| e.to_string())
+        },
+        _ => Err(format!("Unknown command: `{}` (expected `:dis`, `:hex` or `:q`)", cmd))
+    }
+}
+
+// Machine code is a string of hex digit pairs (whitespace is ignored)
+fn parse_hex(digits: &str) -> Option<Vec<u8>> {
+    if digits.is_empty() || !digits.len().is_multiple_of(2) || !digits.is_ascii() {
+        return None;
+    }
+
+    (0..digits.len()).step_by(2).map(|idx| u8::from_str_radix(&digits[idx..idx + 2], 16).ok()).collect()
+}
+
+// Returns the memory layout and stack size requested on the command line, whether to debug
+//   the programs, along with the program to run (and its arguments)
+fn parse_args<I: Iterator<Item=String>>(mut args: I) -> Result<(Layout, u64, bool, Vec<String>), String> {
+    let mut layout = Layout::I386;
+    let mut stack_size = DEFAULT_STACK_SIZE;
+    let mut debug = false;
+    let mut program = Vec::new();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-l" | "--layout" => {
+                layout = match args.next().as_deref() {
+                    Some("i386") => Layout::I386,
+                    Some("x86_64") => Layout::X86_64,
+                    Some(other) => return Err(format!("Unknown layout: `{}`", other)),
+                    None => return Err(format!("Missing value for `{}`", arg))
+                };
+            },
+            "-s" | "--stack" => {
+                let size = args.next().ok_or(format!("Missing value for `{}`", arg))?;
+                stack_size = parse_size(&size).ok_or(format!("Invalid stack size: `{}`", size))?;
+            },
+            "-d" | "--debug" => debug = true,
+            _ if !arg.starts_with('-') => {
+                program.push(arg);
+                program.extend(args.by_ref());
+            },
+            _ => return Err(format!("Unknown argument: `{}`", arg))
+        }
+    }
+
+    Ok((layout, stack_size, debug, program))
+}
+
+// Load a static ELF executable (its class picks the layout) or an assembly source file
+fn load_file(program: &[String], layout: Layout, stack_size: u64) -> Result<Emulator, String> {
+    let image = fs::read(&program[0]).map_err(|e| format!("Unable to read `{}`: {}", program[0], e))?;
+
+    let mut emu = Emulator::with_layout(layout, stack_size);
+    let res = if image.starts_with(b"\x7fELF") {
+        let env = env::vars().map(|(key, val)| format!("{}={}", key, val)).collect::<Vec<_>>();
+        emu.loadElf(&image, program, &env)
+    } else {
+        let source = String::from_utf8_lossy(&image).lines().map(|line| line.to_owned()).collect::<Vec<_>>();
+        emu.load(source.into_iter(), Syntax::ATT)
+    };
+    res.map(|_| emu).map_err(|e| e.to_string())
+}
+
+// Run a loaded program, returns its exit status (see the `exit` system call)
+//   The registers are dumped if the program stops with an error
+fn run_loaded(emu: &mut Emulator) -> i32 {
+    match emu.execute() {
+        Ok(()) => emu.getExitStatus(),
+        Err(e) => {
+            emu.dump_all();
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
+
+// Sizes are given in bytes, optionally with a K/M/G (binary) suffix
+fn parse_size(size: &str) -> Option<u64> {
+    let (digits, scale) = match size.chars().last()?.to_ascii_uppercase() {
+        'K' => (&size[..size.len() - 1], 1 << 10),
+        'M' => (&size[..size.len() - 1], 1 << 20),
+        'G' => (&size[..size.len() - 1], 1 << 30),
+        _ => (size, 1)
+    };
+
+    digits.parse::<u64>().ok()?.checked_mul(scale)
+}
+
 fn read_multiline() -> Option<String> {
     let input = io::stdin();
     let mut in_strs = Vec::new();
     let mut in_str = String::new();
 
-    if let Ok(_) = input.read_line(&mut in_str) {
+    if input.read_line(&mut in_str).is_ok() {
+        // Commands (ie. `:dis`) are a single line
+        if in_str.trim().starts_with(':') && in_str.trim() != ":q" {
+            return Some(in_str.trim().to_owned());
+        }
+
         if in_str.trim() != ":q" {
             in_strs.push(in_str.clone().trim().to_owned());
 
@@ -53,7 +214,7 @@ fn read_multiline() -> Option<String> {
                 match input.read_line(&mut in_str) {
                     Ok(_) => {
                         let in_str = in_str.clone().trim().to_owned();
-                        if in_str.len() == 0 {
+                        if in_str.is_empty() {
                             break
                         }
 
@@ -81,5 +242,5 @@ fn read_multiline() -> Option<String> {
      }
      */
 
-    if in_strs.len() == 0 { None } else { Some(in_strs.join("\n")) }
+    if in_strs.is_empty() { None } else { Some(in_strs.join("\n")) }
 }
\ No newline at end of file
@@ -0,0 +1,273 @@
+use std::collections::BTreeMap;
+use error::InterpError;
+use view::Memory;
+use ximpl::Mode;
+
+// The guest address space is a list of named regions (text, data, stack, ...)
+//   Regions are backed by pages that are only allocated once they're touched,
+//   so mapping a 48bit address space costs nothing up front
+
+pub const PAGE_SIZE: u64 = 4096;
+
+// Stack size when none is given (the usual `ulimit -s`)
+pub const DEFAULT_STACK_SIZE: u64 = 8 * 1024 * 1024;
+
+// Room reserved for the text/rodata/data/bss sections and the heap
+const SECTION_SIZE: u64 = 1024 * 1024;
+const HEAP_SIZE: u64 = 16 * 1024 * 1024;
+
+// Where the regions are placed (following the Linux ELF defaults)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Layout {
+    I386,               // text at 0x08048000, stack below 0xc0000000
+    X86_64              // text at 0x400000, stack below 0x7ffffffff000
+}
+
+impl Layout {
+    // Code in the 32bit layout runs in protected mode, the 64bit layout in long mode
+    pub fn mode(&self) -> Mode {
+        match *self {
+            Layout::I386 => Mode::Bits32,
+            Layout::X86_64 => Mode::Bits64
+        }
+    }
+
+    fn text_base(&self) -> u64 {
+        match *self {
+            Layout::I386 => 0x0804_8000,
+            Layout::X86_64 => 0x40_0000
+        }
+    }
+
+    fn stack_top(&self) -> u64 {
+        match *self {
+            Layout::I386 => 0xc000_0000,
+            Layout::X86_64 => 0x7fff_ffff_f000
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Region {
+    pub name: String,
+    pub start: u64,
+    pub end: u64,       // exclusive
+}
+
+impl Region {
+    pub fn size(&self) -> u64 {
+        self.end - self.start
+    }
+
+    pub fn contains(&self, addr: u64) -> bool {
+        self.start <= addr && addr < self.end
+    }
+}
+
+pub struct AddressSpace {
+    regions: Vec<Region>,
+    // Page number -> contents
+    pages: BTreeMap<u64, Box<[u8]>>,
+}
+
+impl AddressSpace {
+    pub fn new(layout: Layout, stack_size: u64) -> AddressSpace {
+        let mut space = AddressSpace::empty();
+
+        let mut base = layout.text_base();
+        let sections = [("text", SECTION_SIZE), ("rodata", SECTION_SIZE), ("data", SECTION_SIZE),
+                        ("bss", SECTION_SIZE), ("heap", HEAP_SIZE)];
+        for &(name, size) in sections.iter() {
+            space.map(name, base, size).unwrap();
+            base += size;
+        }
+
+        space.map_stack(layout, stack_size).unwrap();
+        space
+    }
+
+    // An address space with nothing mapped (ie. for laying out an ELF executable)
+    pub fn empty() -> AddressSpace {
+        AddressSpace{ regions: Vec::new(), pages: BTreeMap::new() }
+    }
+
+    // Add the heap just past the highest region
+    pub fn map_heap(&mut self) -> Result<(), InterpError> {
+        let base = round_to_page(self.regions.last().map(|r| r.end).unwrap_or(0));
+        self.map("heap", base, HEAP_SIZE)
+    }
+
+    // Add the stack below the layout's stack top (it can't grow into the regions below it)
+    pub fn map_stack(&mut self, layout: Layout, stack_size: u64) -> Result<(), InterpError> {
+        let top = layout.stack_top();
+        let base = self.regions.iter().map(|r| r.end).filter(|&end| end <= top).max().unwrap_or(0);
+        let size = round_to_page(stack_size).min(top - base);
+        self.map("stack", top - size, size)
+    }
+
+    // Add a region (regions can't overlap)
+    pub fn map(&mut self, name: &str, start: u64, size: u64) -> Result<(), InterpError> {
+        let end = match start.checked_add(size) {
+            Some(end) => end,
+            None => return Err(InterpError::InvalidOperand(format!("Region `{}` wraps around the address space", name)))
+        };
+
+        if let Some(other) = self.regions.iter().find(|r| start < r.end && r.start < end) {
+            return Err(InterpError::InvalidOperand(
+                format!("Region `{}` overlaps with `{}` ({:#x} - {:#x})", name, other.name, other.start, other.end)));
+        }
+
+        let pos = self.regions.iter().position(|r| r.start > start).unwrap_or(self.regions.len());
+        self.regions.insert(pos, Region{ name: name.to_string(), start, end });
+        Ok(())
+    }
+
+    // Regions in address order
+    pub fn regions(&self) -> &[Region] {
+        &self.regions
+    }
+
+    pub fn region(&self, name: &str) -> Option<&Region> {
+        self.regions.iter().find(|r| r.name == name)
+    }
+
+    pub fn region_at(&self, addr: u64) -> Option<&Region> {
+        self.regions.iter().find(|r| r.contains(addr))
+    }
+
+    // Number of bytes in the region that have been allocated
+    pub fn resident(&self, region: &Region) -> u64 {
+        let first = region.start / PAGE_SIZE;
+        let last = region.end.div_ceil(PAGE_SIZE);
+        self.pages.range(first..last).count() as u64 * PAGE_SIZE
+    }
+
+    // Whether every byte in `start..end` lies within some region
+    fn is_mapped(&self, start: u64, end: u64) -> bool {
+        let mut addr = start;
+        while addr < end {
+            match self.region_at(addr) {
+                Some(region) => addr = region.end,
+                None => return false
+            }
+        }
+        true
+    }
+
+    // View `len` bytes of memory (the access may cross a page boundary)
+    pub fn access(&mut self, loc: i64, len: usize) -> Result<Memory<'_>, InterpError> {
+        let start = loc as u64;
+        let end = match start.checked_add(len as u64) {
+            Some(end) if loc >= 0 && self.is_mapped(start, end) => end,
+            _ => return Err(InterpError::MemoryFault(loc, len))
+        };
+
+        let first = start / PAGE_SIZE;
+        let last = (end - 1) / PAGE_SIZE;
+        for page in first..last + 1 {
+            self.pages.entry(page).or_insert_with(|| vec![0; PAGE_SIZE as usize].into_boxed_slice());
+        }
+
+        let off = (start % PAGE_SIZE) as usize;
+        let mut pages = self.pages.range_mut(first..last + 1).map(|(_, page)| page);
+        let lo = pages.next().unwrap();
+        match pages.next() {
+            Some(hi) => {
+                let split = PAGE_SIZE as usize - off;
+                Ok(Memory::split(&mut lo[off..], &mut hi[..len - split]))
+            },
+            None => Ok(Memory::new(&mut lo[off..off + len]))
+        }
+    }
+}
+
+pub fn round_to_page(size: u64) -> u64 {
+    size.saturating_add(PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use error::InterpError;
+
+    fn read(space: &mut AddressSpace, addr: u64, len: usize) -> u64 {
+        space.access(addr as i64, len).unwrap().get_unsigned()
+    }
+
+    #[test]
+    fn page_crossing_access() {
+        let mut space = AddressSpace::empty();
+        space.map("a", 0x1000, 0x2000).unwrap();
+
+        space.access(0x1ffe, 4).unwrap().set(0x1122_3344).unwrap();
+        assert_eq!(read(&mut space, 0x1ffe, 4), 0x1122_3344);
+        assert_eq!(read(&mut space, 0x1ffe, 1), 0x44);
+        assert_eq!(read(&mut space, 0x2001, 1), 0x11);
+
+        // Every split of an 8 byte value between the two pages
+        for off in 1..8 {
+            let addr = 0x2000 - off;
+            space.access(addr as i64, 8).unwrap().set(0x0102_0304_0506_0708i64).unwrap();
+            assert_eq!(read(&mut space, addr, 8), 0x0102_0304_0506_0708, "split at {}", off);
+            assert_eq!(read(&mut space, 0x2000, 1), 8 - off, "split at {}", off);
+        }
+        assert_eq!(space.resident(space.region("a").unwrap()), 2 * PAGE_SIZE);
+    }
+
+    #[test]
+    fn unmapped_access() {
+        let mut space = AddressSpace::empty();
+        space.map("a", 0x1000, 0x1000).unwrap();
+        space.map("b", 0x2000, 0x1000).unwrap();
+        space.map("c", 0x4000, 0x1000).unwrap();
+
+        // Adjacent regions can be accessed as one, but not across a gap (or past the end)
+        space.access(0x1ffe, 4).unwrap().set(-1).unwrap();
+        assert_eq!(read(&mut space, 0x2000, 2), 0xffff);
+        assert_eq!(space.access(0x2ffe, 4).err(), Some(InterpError::MemoryFault(0x2ffe, 4)));
+        assert_eq!(space.access(0x4ffc, 8).err(), Some(InterpError::MemoryFault(0x4ffc, 8)));
+        assert_eq!(space.access(0xfff, 1).err(), Some(InterpError::MemoryFault(0xfff, 1)));
+        assert_eq!(space.access(-4, 4).err(), Some(InterpError::MemoryFault(-4, 4)));
+
+        // A failed access doesn't allocate anything
+        assert_eq!(space.resident(space.region("c").unwrap()), 0);
+    }
+
+    #[test]
+    fn sparse_residency() {
+        let mut space = AddressSpace::new(Layout::X86_64, DEFAULT_STACK_SIZE);
+        for region in space.regions().to_vec() {
+            assert_eq!(space.resident(&region), 0, "{}", region.name);
+        }
+
+        // Only the touched pages are allocated (and untouched memory reads as 0)
+        let heap = space.region("heap").unwrap().clone();
+        let stack = space.region("stack").unwrap().clone();
+        let addr = heap.start + heap.size() / 2;
+        assert_eq!(read(&mut space, addr, 8), 0);
+        space.access(stack.end as i64 - 8, 8).unwrap().set(-1).unwrap();
+        space.access(stack.start as i64, 1).unwrap().set(1).unwrap();
+
+        assert_eq!(space.resident(&heap), PAGE_SIZE);
+        assert_eq!(space.resident(&stack), 2 * PAGE_SIZE);
+        assert_eq!(space.resident(space.region("data").unwrap()), 0);
+        assert_eq!(stack.size(), DEFAULT_STACK_SIZE);
+    }
+
+    #[test]
+    fn regions_dont_overlap() {
+        let mut space = AddressSpace::empty();
+        space.map("a", 0x1000, 0x2000).unwrap();
+        assert!(space.map("b", 0x2000, 0x1000).is_err());
+        assert!(space.map("b", 0, 0x1001).is_err());
+        assert!(space.map("b", u64::MAX - 0x1fff, 0x1000).is_ok());
+        assert!(space.map("c", u64::MAX - 0xfff, 0x1000).is_err());      // the end would wrap to 0
+
+        // Regions are kept in address order
+        space.map("d", 0, 0x1000).unwrap();
+        let names: Vec<_> = space.regions().iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, ["d", "a", "b"]);
+        assert_eq!(space.region_at(0x2fff).map(|r| r.name.as_str()), Some("a"));
+        assert_eq!(space.region_at(0x3000), None);
+    }
+}
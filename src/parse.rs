@@ -1,7 +1,10 @@
 
 use nom::*;
+use std::convert::TryFrom;
 use std::str;
-use ximpl::{Command, Argument};
+use ximpl;
+use ximpl::{Command, Argument, Datum, Size, Syntax, Term};
+use error::InterpError;
 
 // Nom resources
 // http://hermanradtke.com/2016/08/08/introduction-to-nom-rust-parsing-combinator-framework.html
@@ -14,25 +17,189 @@ use ximpl::{Command, Argument};
 pub fn x86_instruction(input: &str) -> IResult<&[u8], Command> {
     _x86_instruction(input.as_bytes())
 }
+pub fn intel_instruction(input: &str) -> IResult<&[u8], Command> {
+    _intel_instruction(input.as_bytes())
+}
 pub fn label(input: &str) -> IResult<&[u8], Command> {
     _label(input.as_bytes())
 }
 
+// Split a data directive into its name (without the `.`) and operands
+pub fn data_directive(input: &str) -> Option<(String, Vec<Datum>)> {
+    match _data_directive(input.as_bytes()) {
+        IResult::Done(_, res) => Some(res),
+        _ => None
+    }
+}
+
+// Split a symbol assignment (`len = . - msg`, `.set len, . - msg` or `.equ`) into its name and terms
+//   Terms are paired with their sign (1 or -1)
+pub fn assignment(input: &str) -> Option<(String, Vec<(i64, Term)>)> {
+    match _assignment(input.as_bytes()) {
+        IResult::Done(_, res) => Some(res),
+        _ => None
+    }
+}
+
+// Check whether the line switches sections (ie. `.data` or `.section .rodata`)
+//   Returns the section name with its leading `.`, and the section's flags if they're given
+//   (ie. `"aw"` in `.section .init_array,"aw"`)
+pub fn section_directive(input: &str) -> Option<(String, Option<String>)> {
+    match _section_directive(input.as_bytes()) {
+        IResult::Done(_, res) => Some(res),
+        _ => None
+    }
+}
+
+// Split an alignment directive (`.align`, `.balign` or `.p2align`) into its name and operands
+//   Operands can be left out (ie. `.p2align 4,,10` has no fill byte)
+pub fn align_directive(input: &str) -> Option<(String, Vec<Option<i64>>)> {
+    match _align_directive(input.as_bytes()) {
+        IResult::Done(_, res) => Some(res),
+        _ => None
+    }
+}
+
+// Check whether the line switches the syntax of the following lines
+pub fn syntax_directive(input: &str) -> Option<Syntax> {
+    match _syntax_directive(input.as_bytes()) {
+        IResult::Done(_, syntax) => Some(syntax),
+        _ => None
+    }
+}
+
+// A number typed at the command line (decimal, or hex with `0x`)
+pub fn parse_int(num: &str) -> Option<u64> {
+    match num.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => num.parse().ok()
+    }
+}
+
+
+// Remove a `#` comment from the end of a line (`#` within strings and character
+//   literals, ie. `.ascii "#1"` or `$'#'`, doesn't start a comment)
+pub fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+
+    for (idx, c) in line.char_indices() {
+        match (quote, c) {
+            (Some(_), _) if escaped => escaped = false,
+            (Some(_), '\\') => escaped = true,
+            (Some(q), _) if c == q => quote = None,
+            (Some(_), _) => (),
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (None, '#') => return &line[..idx],
+            (None, _) => ()
+        }
+    }
+    line
+}
 
 // Parser implementation
 named!(_x86_instruction<Command>, ws!(alt!(
-    directive | no_arg_inst | one_arg_inst | two_arg_inst | _label
+    directive | no_arg_inst | one_arg_inst | two_arg_inst | three_arg_inst | _label
 )));
 named!(directive<Command>, ws!(
     do_parse!(
         tag!(".") >>
-        val: map_res!(alpha, str::from_utf8) >>
+        val: map_res!(take_while1!(is_ident_char), str::from_utf8) >>
+        opt!(not_line_ending) >>
         (Command::Directive(val.to_string()))
     )
 ));
+named!(_syntax_directive<Syntax>, ws!(
+    do_parse!(
+        tag!(".") >>
+        syntax: alt!(
+            do_parse!(tag!("intel_syntax") >> opt!(complete!(ws!(alt!(tag!("noprefix") | tag!("prefix"))))) >> eof!() >> (Syntax::Intel)) |
+            do_parse!(tag!("att_syntax") >> opt!(complete!(ws!(alt!(tag!("noprefix") | tag!("prefix"))))) >> eof!() >> (Syntax::ATT))
+        ) >>
+        (syntax)
+    )
+));
+named!(_data_directive<(String, Vec<Datum>)>, ws!(
+    do_parse!(
+        tag!(".") >>
+        name: map_res!(take_while1!(is_ident_char), str::from_utf8) >>
+        args: separated_list!(complete!(tag!(",")), complete!(datum)) >>
+        eof!() >>
+        ((name.to_string(), args))
+    )
+));
+named!(datum<Datum>, ws!(alt!(
+    map!(string_literal, Datum::Str) |
+    map!(char_literal, |c| Datum::Int(c as i64)) |
+    map!(complete!(long_int), Datum::Int) |
+    map!(identifier, |s| Datum::Symbol(s.to_string()))
+)));
+named!(_assignment<(String, Vec<(i64, Term)>)>, ws!(
+    do_parse!(
+        name: alt!(
+            do_parse!(
+                name: identifier >>
+                tag!("=") >>
+                (name)
+            ) |
+            do_parse!(
+                alt!(tag!(".set") | tag!(".equ")) >>
+                many1!(one_of!(" \t")) >>
+                name: identifier >>
+                tag!(",") >>
+                (name)
+            )
+        ) >>
+        neg: opt!(tag!("-")) >>
+        first: term >>
+        rest: many0!(complete!(ws!(pair!(alt!(tag!("+") | tag!("-")), term)))) >>
+        eof!() >>
+        ({
+            let mut terms = vec![(if neg.is_some() { -1 } else { 1 }, first)];
+            terms.extend(rest.into_iter().map(|(sign, term)| (if sign == b"-" { -1 } else { 1 }, term)));
+            (name.to_string(), terms)
+        })
+    )
+));
+named!(term<Term>, ws!(alt!(
+    map!(char_literal, |c| Term::Int(c as i64)) |
+    map!(complete!(long_int), Term::Int) |
+    map!(identifier, |s| Term::Symbol(s.to_string())) |
+    value!(Term::Here, tag!("."))
+)));
+named!(_section_directive<(String, Option<String>)>, ws!(
+    do_parse!(
+        name: alt!(
+            map!(alt!(tag!(".text") | tag!(".data") | tag!(".bss")), |s| (s.to_vec(), None)) |
+            do_parse!(
+                tag!(".section") >>
+                name: map!(take_while1!(is_section_char), |s| s.to_vec()) >>
+                flags: opt!(complete!(ws!(preceded!(tag!(","), string_literal)))) >>
+                opt!(not_line_ending) >>
+                ((name, flags))
+            )
+        ) >>
+        eof!() >>
+        ((String::from_utf8_lossy(&name.0).into_owned(), name.1.map(|flags| String::from_utf8_lossy(&flags).into_owned())))
+    )
+));
+named!(_align_directive<(String, Vec<Option<i64>>)>, ws!(
+    do_parse!(
+        tag!(".") >>
+        name: map_res!(alt!(tag!("p2align") | tag!("balign") | tag!("align")), str::from_utf8) >>
+        first: complete!(long_int) >>
+        rest: many0!(complete!(ws!(preceded!(tag!(","), opt!(complete!(long_int)))))) >>
+        eof!() >>
+        ({
+            let mut args = vec![Some(first)];
+            args.extend(rest);
+            (name.to_string(), args)
+        })
+    )
+));
 named!(_label<Command>, ws!(
     do_parse!(
-        val: map_res!(alphanumeric, str::from_utf8) >>
+        val: identifier >>
         tag!(":") >>
         (Command::Label(val.to_string()))
     )
@@ -40,8 +207,9 @@ named!(_label<Command>, ws!(
 named!(one_arg_inst<Command>, ws!(
     do_parse!(
         mne: ws!(one_arg_mnemonic) >>
-        arg: operand >>
-        (Command::OneArg(mne.to_string(), arg))
+        arg: alt!(indirect_operand | operand) >>
+        eof!() >>
+        (Command::OneArg(mne.0, mne.1, arg))
     )
 ));
 named!(two_arg_inst<Command>, ws!(
@@ -50,132 +218,783 @@ named!(two_arg_inst<Command>, ws!(
         arg1: operand >>
         tag!(",") >>
         arg2: operand >>
-        (Command::TwoArg(mne.to_string(), arg1, arg2))
+        eof!() >>
+        (Command::TwoArg(mne.0, mne.1, arg1, arg2))
+    )
+));
+named!(three_arg_inst<Command>, ws!(
+    do_parse!(
+        mne: ws!(three_arg_mnemonic) >>
+        arg1: operand >>
+        tag!(",") >>
+        arg2: operand >>
+        tag!(",") >>
+        arg3: operand >>
+        eof!() >>
+        (Command::ThreeArg(mne.0, mne.1, arg1, arg2, arg3))
     )
 ));
 named!(no_arg_inst<Command>, ws!(
     do_parse!(
         mne: no_arg_mnemonic >>
-        (Command::NoArg(mne.to_string()))
+        eof!() >>
+        (Command::NoArg(mne.0, mne.1))
     )
 ));
 named!(operand<Argument>, ws!(
     alt!(literal | register | mem_access | label_use)
 ));
+// Indirect jump/call targets, `*%eax`, `*(%ebx)` or `*table(,%ecx,4)`
+//   A plain label is the memory at its address (`jmp label` is a direct jump)
+named!(indirect_operand<Argument>, do_parse!(
+    tag!("*") >>
+    arg: ws!(alt!(
+        register |
+        mem_access |
+        map!(identifier, |lbl| Argument::Mem(None, None, 1, 0, Some(lbl.to_string())))
+    )) >>
+    (arg)
+));
 named!(literal<Argument>, do_parse!(
     tag!("$") >>
-    val: int >>
+    val: alt!(
+        map!(complete!(long_int), Argument::Literal) |
+        map!(char_literal, |c| Argument::Literal(c as i64)) |
+        map!(symbol, |(lbl, off)| Argument::Address(lbl, off))
+    ) >>
 
-    (Argument::Literal(val))
+    (val)
+));
+// Full AT&T addressing form: `disp(base, index, scale)`
+//   Every component is optional, but a memory operand needs at least one of them
+//   The displacement may be a label (ie. `table(,%ecx,4)` or `msg+4`)
+named!(mem_access<Argument>, alt!(
+    complete!(do_parse!(
+        disp: opt!(displacement) >>
+        sib: ws!(delimited!(tag!("("), sib, tag!(")"))) >>
+        (match disp {
+            Some((disp, lbl)) => Argument::Mem(sib.0, sib.1, sib.2, disp, lbl),
+            None => Argument::Mem(sib.0, sib.1, sib.2, 0, None)
+        })
+    )) |
+    complete!(do_parse!(
+        disp: int >>
+        (Argument::Mem(None, None, 1, disp, None))
+    )) |
+    complete!(symbol_offset)
 ));
-// TODO: Augment with scaled indexing capabilities
-named!(mem_access<Argument>, do_parse!(
-    off: opt!(int) >>
-    tag!("(") >>
-    idx: operand >>
-    tag!(")") >>
-    (Argument::Mem(Box::new(idx), off.unwrap_or(0), 1, 0))
+named!(displacement<(i32, Option<String>)>, alt!(
+    map!(complete!(int), |disp| (disp, None)) |
+    map!(symbol, |(lbl, off)| (off, Some(lbl)))
 ));
+named!(sib<(Option<Box<Argument>>, Option<Box<Argument>>, i32)>, ws!(do_parse!(
+    base: opt!(register) >>
+    idx: opt!(ws!(preceded!(tag!(","), index_scale))) >>
+    verify!(value!(()), |_| base.is_some() || idx.is_some()) >>
+    (match idx {
+        Some((idx, scale)) => (base.map(Box::new), Some(Box::new(idx)), scale),
+        None => (base.map(Box::new), None, 1)
+    })
+)));
+named!(index_scale<(Argument, i32)>, ws!(do_parse!(
+    idx: register >>
+    verify!(value!(()), |_| match idx {
+        Argument::Reg(ref r) => r != "esp" && r != "rsp",
+        _ => false
+    }) >>
+    scale: opt!(ws!(preceded!(tag!(","), verify!(int, |s| s == 1 || s == 2 || s == 4 || s == 8)))) >>
+    ((idx, scale.unwrap_or(1)))
+)));
 named!(label_use<Argument>, do_parse!(
-    val: map_res!(alphanumeric, str::from_utf8) >>
+    val: identifier >>
     (Argument::Label(val.to_string()))
 ));
+// A label with an offset, `label+4` (plain labels are left to `label_use`)
+named!(symbol<(String, i32)>, do_parse!(
+    lbl: identifier >>
+    off: map_opt!(opt!(complete!(ws!(pair!(alt!(tag!("+") | tag!("-")), int)))), |off| match off {
+        Some((sign, off)) => signed_i32(sign == b"-", off as i64),
+        None => Some(0)
+    }) >>
+    ((lbl.to_string(), off))
+));
+named!(symbol_offset<Argument>, do_parse!(
+    lbl: identifier >>
+    off: map_opt!(ws!(pair!(alt!(tag!("+") | tag!("-")), int)), |(sign, off): (&[u8], i32)| signed_i32(sign == b"-", off as i64)) >>
+    (Argument::Mem(None, None, 1, off, Some(lbl.to_string())))
+));
+
+
+// Intel syntax implementation
+//   Operands are stored in AT&T order (ie. `mov eax, ebx` => TwoArg("mov", ebx, eax))
+named!(_intel_instruction<Command>, ws!(alt!(
+    directive | no_arg_inst | intel_one_arg_inst | intel_two_arg_inst | intel_three_arg_inst | _label
+)));
+named!(intel_one_arg_inst<Command>, ws!(
+    do_parse!(
+        mne: ws!(one_arg_mnemonic) >>
+        arg: intel_operand >>
+        eof!() >>
+        (Command::OneArg(mne.0, mne.1.or(arg.1), arg.0))
+    )
+));
+named!(intel_two_arg_inst<Command>, ws!(
+    do_parse!(
+        mne: ws!(two_arg_mnemonic) >>
+        dest: intel_operand >>
+        tag!(",") >>
+        src: intel_operand >>
+        eof!() >>
+        (match &mne.0[..] {
+            // `enter` keeps the same operand order in both syntaxes
+            "enter" => Command::TwoArg(mne.0, mne.1, dest.0, src.0),
+            // `movzx eax, byte ptr [ebx]` takes its size from the destination
+            _ if is_extension(&mne.0) => Command::TwoArg(extension_source(mne.0, src.1), mne.1.or(dest.1), src.0, dest.0),
+            _ => Command::TwoArg(mne.0, mne.1.or(dest.1).or(src.1), src.0, dest.0)
+        })
+    )
+));
+named!(intel_three_arg_inst<Command>, ws!(
+    do_parse!(
+        mne: ws!(three_arg_mnemonic) >>
+        dest: intel_operand >>
+        tag!(",") >>
+        src: intel_operand >>
+        tag!(",") >>
+        extra: intel_operand >>
+        eof!() >>
+        (Command::ThreeArg(mne.0, mne.1.or(dest.1).or(src.1), extra.0, src.0, dest.0))
+    )
+));
+named!(intel_operand<(Argument, Option<Size>)>, ws!(alt!(
+    intel_mem_access |
+    map!(alt!(intel_register | intel_literal | intel_offset | complete!(symbol_offset) | label_use), |arg| (arg, None))
+)));
+// `offset msg` is the address of a label as an immediate
+named!(intel_offset<Argument>, do_parse!(
+    tag_no_case!("offset") >>
+    sym: ws!(symbol) >>
+    (Argument::Address(sym.0, sym.1))
+));
+named!(intel_literal<Argument>, alt!(
+    map!(long_int, Argument::Literal) |
+    map!(char_literal, |c| Argument::Literal(c as i64))
+));
+// The optional `%` allows for `.intel_syntax prefix` code
+named!(intel_register<Argument>, do_parse!(
+    opt!(tag!("%")) >>
+    reg: map_res!(alphanumeric, str::from_utf8) >>
+    verify!(value!(()), |_| ximpl::reg_location(reg).is_some()) >>
+    (Argument::Reg(reg.to_string()))
+));
+named!(intel_mem_access<(Argument, Option<Size>)>, ws!(
+    do_parse!(
+        size: opt!(ws!(intel_ptr_size)) >>
+        tag!("[") >>
+        mem: intel_address >>
+        tag!("]") >>
+        ((mem, size))
+    )
+));
+named!(intel_ptr_size<Size>, ws!(
+    do_parse!(
+        size: alt!(
+            value!(Size::Byte, tag_no_case!("byte")) |
+            value!(Size::Word, tag_no_case!("word")) |
+            value!(Size::Long, tag_no_case!("dword")) |
+            value!(Size::Quad, tag_no_case!("qword"))
+        ) >>
+        opt!(tag_no_case!("ptr")) >>
+        (size)
+    )
+));
+named!(intel_address<Argument>, map_opt!(
+    ws!(pair!(
+        intel_term,
+        many0!(ws!(pair!(alt!(tag!("+") | tag!("-")), intel_term)))
+    )),
+    |(first, rest): (IntelTerm, Vec<(&[u8], IntelTerm)>)| {
+        let mut terms = vec![(true, first)];
+        terms.extend(rest.into_iter().map(|(sign, term)| (sign == b"+", term)));
+        build_intel_address(terms)
+    }
+));
+named!(intel_term<IntelTerm>, ws!(alt!(
+    do_parse!(
+        reg: intel_register >>
+        scale: opt!(ws!(preceded!(tag!("*"), int))) >>
+        (IntelTerm::Reg(reg, scale))
+    ) |
+    do_parse!(
+        scale: int >>
+        tag!("*") >>
+        reg: intel_register >>
+        (IntelTerm::Reg(reg, Some(scale)))
+    ) |
+    do_parse!(
+        n: opt!(tag!("-")) >>
+        val: unsigned >>
+        (IntelTerm::Disp(if n.is_some() { -val } else { val }))
+    ) |
+    do_parse!(
+        lbl: identifier >>
+        (IntelTerm::Label(lbl.to_string()))
+    )
+)));
+
+enum IntelTerm {
+    Reg(Argument, Option<i32>),
+    Disp(i64),
+    Label(String)
+}
+
+// Collapse the `[base + index*scale + disp]` terms into a memory operand
+fn build_intel_address(terms: Vec<(bool, IntelTerm)>) -> Option<Argument> {
+    let (mut base, mut idx, mut scale, mut disp, mut lbl) = (None, None, 1, 0i64, None);
+
+    // The displacement has to fit in 32bits (`[ebx+0x7fffffff+1]` is rejected)
+    for (positive, term) in terms {
+        match term {
+            IntelTerm::Disp(val) => disp = if positive { disp.checked_add(val)? } else { disp.checked_sub(val)? },
+            IntelTerm::Label(name) if positive && lbl.is_none() => lbl = Some(name),
+            IntelTerm::Label(_) => return None,
+            IntelTerm::Reg(_, _) if !positive => return None,
+            IntelTerm::Reg(reg, None) if base.is_none() => base = Some(Box::new(reg)),
+            IntelTerm::Reg(reg, s) if idx.is_none() => {
+                idx = Some(Box::new(reg));
+                scale = s.unwrap_or(1);
+            },
+            _ => return None
+        }
+    }
+
+    let valid_idx = match idx {
+        Some(ref reg) => match **reg {
+            Argument::Reg(ref r) => r != "esp" && r != "rsp",
+            _ => false
+        },
+        None => true
+    };
+
+    let disp = i32::try_from(disp).ok()?;
+    match scale {
+        1 | 2 | 4 | 8 if valid_idx => Some(Argument::Mem(base, idx, scale, disp, lbl)),
+        _ => None
+    }
+}
+
 
 // Helper functions
-named!(digits<&str>, map_res!(digit, str::from_utf8));
-named!(int<i32>, do_parse!(
+named!(digits<&'a str>, map_res!(digit, str::from_utf8));
+named!(hex_digits<&'a str>, map_res!(preceded!(tag!("0x"), hex_digit), str::from_utf8));
+// Displacements and scales have to fit in 32bits (after the sign is applied)
+named!(int<i32>, map_opt!(
+    pair!(opt!(tag!("-")), unsigned),
+    |(n, val): (Option<&[u8]>, i64)| signed_i32(n.is_some(), val)
+));
+named!(unsigned<i64>, alt!(
+    map_res!(complete!(hex_digits), |h| i64::from_str_radix(h, 16)) |
+    map_res!(digits, str::FromStr::from_str)
+));
+named!(long_int<i64>, do_parse!(
     n: opt!(tag!("-")) >>
-    val: map_res!(digits, str::FromStr::from_str) >>
+    val: alt!(
+        map_res!(complete!(hex_digits), |h| u64::from_str_radix(h, 16).map(|v| v as i64)) |
+        map_res!(digits, str::FromStr::from_str)
+    ) >>
     (match n {
-        Some(_) => -1 * val,
+        Some(_) => val.wrapping_neg(),
         None => val
     })
 ));
 
+// Apply a parsed sign, `None` if the value doesn't fit in 32bits
+fn signed_i32(negative: bool, val: i64) -> Option<i32> {
+    let val = if negative { val.checked_neg()? } else { val };
+    i32::try_from(val).ok()
+}
+// Symbol names can't start with a digit (`0xffffffff(%eax)` is a displacement, not a label)
+//   Compiler generated names start with or contain a `.` (ie. `.L2`, `.LC0` or `counter.0`)
+named!(identifier<&'a str>, map_res!(
+    recognize!(pair!(
+        opt!(complete!(tag!("."))),
+        verify!(take_while1!(is_symbol_char), |name: &[u8]| is_ident_char(name[0]) && !name[0].is_ascii_digit())
+    )),
+    str::from_utf8
+));
+fn is_ident_char(c: u8) -> bool {
+    is_alphanumeric(c) || c == b'_'
+}
+fn is_symbol_char(c: u8) -> bool {
+    is_ident_char(c) || c == b'.'
+}
+fn is_section_char(c: u8) -> bool {
+    is_ident_char(c) || c == b'.' || c == b'-'
+}
+
+// Quoted strings/characters (with C style escapes)
+fn string_literal(input: &[u8]) -> IResult<&[u8], Vec<u8>> {
+    if input.first() != Some(&b'"') {
+        return IResult::Error(error_position!(ErrorKind::Custom(0), input));
+    }
+
+    let mut res = Vec::new();
+    let mut idx = 1;
+    while idx < input.len() {
+        match input[idx] {
+            b'"' => return IResult::Done(&input[idx + 1..], res),
+            b'\\' => match input.get(idx + 1).and_then(|&c| unescape(c)) {
+                Some(c) => {
+                    res.push(c);
+                    idx += 2;
+                },
+                None => return IResult::Error(error_position!(ErrorKind::Custom(0), input))
+            },
+            c => {
+                res.push(c);
+                idx += 1;
+            }
+        }
+    }
+
+    IResult::Error(error_position!(ErrorKind::Custom(0), input))
+}
+fn char_literal(input: &[u8]) -> IResult<&[u8], u8> {
+    let res = match input {
+        [b'\'', b'\\', c, b'\'', ..] => unescape(*c).map(|c| (c, 4)),
+        [b'\'', c, b'\'', ..] if *c != b'\\' => Some((*c, 3)),
+        _ => None
+    };
+
+    match res {
+        Some((c, len)) => IResult::Done(&input[len..], c),
+        None => IResult::Error(error_position!(ErrorKind::Custom(0), input))
+    }
+}
+fn unescape(c: u8) -> Option<u8> {
+    match c {
+        b'n' => Some(b'\n'),
+        b't' => Some(b'\t'),
+        b'r' => Some(b'\r'),
+        b'0' => Some(0),
+        b'\\' | b'"' | b'\'' => Some(c),
+        _ => None
+    }
+}
+
 
-// Instruction/Register Mnemonic Setss
-named!(one_arg_mnemonic<&str>,
-    map_res!(alt!(
-        tag!("push") | tag!("pop") |
-        tag!("inc") | tag!("dec") |
-        tag!("neg") | tag!("not") |
-        tag!("imulq") | tag!("mulq") |
-        tag!("idivq") | tag!("divq") |
-        tag!("sete") | tag!("setz") |
-        tag!("setne") | tag!("setnz") |
-        tag!("sets") | tag!("setns") |
-        tag!("setg") | tag!("setnle") |
-        tag!("setge") | tag!("setnl") |
-        tag!("setl") | tag!("setnge") |
-        tag!("setle") | tag!("setng") |
-        tag!("seta") | tag!("setnbe") |
-        tag!("setae") | tag!("setnb") |
-        tag!("setb") | tag!("setnae") |
-        tag!("setbe") | tag!("setna") |
-        tag!("jmp") | tag!("je") |
-        tag!("jz") | tag!("jne") |
-        tag!("jnz") | tag!("js") |
-        tag!("jns") | tag!("jg") |
-        tag!("jnle") | tag!("jge") |
-        tag!("jnl") | tag!("jl") |
-        tag!("jnge") | tag!("jle") |
-        tag!("jng") | tag!("ja") |
-        tag!("jnbe") | tag!("jae") |
-        tag!("jnb") | tag!("jb") |
-        tag!("jnae") | tag!("jbe") |
-        tag!("jna") | tag!("call")
-    ), str::from_utf8)
-);
-named!(two_arg_mnemonic<&str>, map_res!(
-    alt!(
-        tag!("movs") | tag!("movz") |
-        tag!("mov") | tag!("leaq") |
-        tag!("add") | tag!("sub") |
-        tag!("imul") | tag!("xor") |
-        tag!("or") | tag!("and") |
-        tag!("sal") | tag!("shl") |
-        tag!("sar") | tag!("shr") |
-        tag!("cmp") | tag!("test") |
-        tag!("cmove") | tag!("cmovz") |
-        tag!("cmovne") | tag!("cmovnz") |
-        tag!("cmovs") | tag!("cmovns") |
-        tag!("cmovg") | tag!("cmovnle") |
-        tag!("cmovge") | tag!("cmovnl") |
-        tag!("cmovl") | tag!("cmovnge") |
-        tag!("cmova") | tag!("cmovnbe") |
-        tag!("cmovae") | tag!("cmovnb") |
-        tag!("cmovb") | tag!("cmovnae") |
-        tag!("cmobe") | tag!("cmovna")
-    ), str::from_utf8
-));
-named!(no_arg_mnemonic<&str>, map_res!(
-    alt!(
-        tag!("cwtl") | tag!("cltq") |
-        tag!("cqto") | tag!("leave") |
-        tag!("ret") | tag!("exit") |
-        tag!("dump")
-    ), str::from_utf8
+// Instruction/Register Mnemonic Sets
+//   Mnemonics may carry an operand size suffix (ie. `movl` or `pushq`), and can contain digits (`endbr64`)
+named!(one_arg_mnemonic<(String, Option<Size>)>, map_opt!(
+    map_res!(alphanumeric, str::from_utf8),
+    |mne| sized_mnemonic(mne, ONE_ARG_MNEMONICS)
+));
+named!(two_arg_mnemonic<(String, Option<Size>)>, map_opt!(
+    map_res!(alphanumeric, str::from_utf8),
+    |mne| extension_mnemonic(mne).or_else(|| sized_mnemonic(mne, TWO_ARG_MNEMONICS))
+));
+named!(three_arg_mnemonic<(String, Option<Size>)>, map_opt!(
+    map_res!(alphanumeric, str::from_utf8),
+    |mne| sized_mnemonic(mne, THREE_ARG_MNEMONICS)
 ));
+named!(no_arg_mnemonic<(String, Option<Size>)>, map_opt!(
+    map_res!(alphanumeric, str::from_utf8),
+    |mne| sized_mnemonic(mne, NO_ARG_MNEMONICS)
+));
+
+const ONE_ARG_MNEMONICS: &[&str] = &[
+    "push", "pop",
+    "inc", "dec",
+    "neg", "not",
+    "imul", "mul",
+    "idiv", "div",
+    "seto", "setno",
+    "setb", "setc",
+    "setnae", "setae",
+    "setnb", "setnc",
+    "sete", "setz",
+    "setne", "setnz",
+    "setbe", "setna",
+    "seta", "setnbe",
+    "sets", "setns",
+    "setp", "setpe",
+    "setnp", "setpo",
+    "setl", "setnge",
+    "setge", "setnl",
+    "setle", "setng",
+    "setg", "setnle",
+    "jmp", "jo",
+    "jno", "jb",
+    "jc", "jnae",
+    "jae", "jnb",
+    "jnc", "je",
+    "jz", "jne",
+    "jnz", "jbe",
+    "jna", "ja",
+    "jnbe", "js",
+    "jns", "jp",
+    "jpe", "jnp",
+    "jpo", "jl",
+    "jnge", "jge",
+    "jnl", "jle",
+    "jng", "jg",
+    "jnle", "call",
+    "ret",
+    "sal", "shl",
+    "sar", "shr",
+    "rol", "ror",
+    "rcl", "rcr",
+    "int"
+];
+const TWO_ARG_MNEMONICS: &[&str] = &[
+    "mov", "movabs", "lea",
+    "add", "sub",
+    "adc", "sbb",
+    "xchg",
+    "imul", "xor",
+    "or", "and",
+    "sal", "shl",
+    "sar", "shr",
+    "rol", "ror",
+    "rcl", "rcr",
+    "cmp", "test",
+    "cmovo", "cmovno",
+    "cmovb", "cmovc",
+    "cmovnae", "cmovae",
+    "cmovnb", "cmovnc",
+    "cmove", "cmovz",
+    "cmovne", "cmovnz",
+    "cmovbe", "cmovna",
+    "cmova", "cmovnbe",
+    "cmovs", "cmovns",
+    "cmovp", "cmovpe",
+    "cmovnp", "cmovpo",
+    "cmovl", "cmovnge",
+    "cmovge", "cmovnl",
+    "cmovle", "cmovng",
+    "cmovg", "cmovnle",
+    "enter"
+];
+const THREE_ARG_MNEMONICS: &[&str] = &[
+    "imul",
+    "shld", "shrd"
+];
+const NO_ARG_MNEMONICS: &[&str] = &[
+    "cbtw", "cbw",
+    "cwtl", "cwde",
+    "cltq", "cdqe",
+    "cwtd", "cwd",
+    "cltd", "cdq",
+    "cqto", "cqo",
+    "leave",
+    "ret", "exit",
+    "nop", "endbr32", "endbr64",
+    "syscall",
+    "dump"
+];
+
+// Split a mnemonic into its base instruction and size suffix
+//   Exact matches take priority so `shl` isn't read as `sh` + `l`
+fn sized_mnemonic(mne: &str, mnemonics: &[&str]) -> Option<(String, Option<Size>)> {
+    if mnemonics.contains(&mne) {
+        return Some((mne.to_string(), None));
+    }
+
+    let (base, suffix) = mne.split_at(mne.len() - 1);
+    match Size::from_suffix(suffix) {
+        Some(size) if mnemonics.contains(&base) => Some((base.to_string(), Some(size))),
+        _ => None
+    }
+}
+
+// Sign/zero extension moves carry both operand sizes (ie. `movsbl` is byte => long)
+//   These become `movs`/`movz` + the source suffix, with the destination as the operand size
+//   `movsx`/`movzx` leave the source size to be inferred from the register (`movsxd` is always long)
+fn extension_mnemonic(mne: &str) -> Option<(String, Option<Size>)> {
+    if !mne.starts_with("movs") && !mne.starts_with("movz") {
+        return None;
+    }
+
+    let (base, suffixes) = mne.split_at(4);
+    match suffixes {
+        "x" => Some((base.to_string(), None)),
+        "xd" if base == "movs" => Some(("movsl".to_string(), None)),
+        _ if suffixes.len() == 2 => {
+            let (src, dest) = suffixes.split_at(1);
+            match (Size::from_suffix(src), Size::from_suffix(dest)) {
+                (Some(src), Some(dest)) => Some((format!("{}{}", base, src.suffix()), Some(dest))),
+                _ => None
+            }
+        },
+        _ => None
+    }
+}
+
+fn is_extension(mne: &str) -> bool {
+    matches!(mne, "movs" | "movsb" | "movsw" | "movsl" |
+                  "movz" | "movzb" | "movzw" | "movzl")
+}
+
+// Add the source size given by an Intel `ptr` operand
+fn extension_source(mne: String, src_size: Option<Size>) -> String {
+    match src_size {
+        Some(size) if mne.len() == 4 => format!("{}{}", mne, size.suffix()),
+        _ => mne
+    }
+}
+
+// Infer the operand size from the register operands (when not given by the suffix)
+pub fn resolve_size(cmd: Command) -> Result<Command, InterpError> {
+    match cmd {
+        Command::OneArg(mne, size, arg) => {
+            let size = memory_size(&mne, operand_size(&mne, size, &[&arg])?, &[&arg])?;
+            Ok(Command::OneArg(mne, size, arg))
+        },
+        Command::TwoArg(mne, size, src, dest) => {
+            if is_extension(&mne) {
+                return resolve_extension(mne, size, src, dest);
+            }
+
+            // The shift count (`%cl`) doesn't determine the operand size
+            let args = if has_count_operand(&mne) { vec![&dest] } else { vec![&src, &dest] };
+            let size = memory_size(&mne, operand_size(&mne, size, &args)?, &args)?;
+            Ok(Command::TwoArg(mne, size, src, dest))
+        },
+        Command::ThreeArg(mne, size, a1, a2, a3) => {
+            let args = if has_count_operand(&mne) { vec![&a2, &a3] } else { vec![&a1, &a2, &a3] };
+            let size = memory_size(&mne, operand_size(&mne, size, &args)?, &args)?;
+            Ok(Command::ThreeArg(mne, size, a1, a2, a3))
+        },
+        cmd => Ok(cmd)
+    }
+}
+
+// The source of an extension move must be narrower than its destination
+fn resolve_extension(mne: String, size: Option<Size>, src: Argument, dest: Argument) -> Result<Command, InterpError> {
+    let src_size = operand_size(&mne, Size::from_suffix(&mne[4..]), &[&src])?;
+    let size = operand_size(&mne, size, &[&dest])?;
+
+    match (src_size, size) {
+        (Some(Size::Long), _) if mne.starts_with("movz") =>
+            Err(InterpError::InvalidOperand(format!("Invalid operand sizes for `{}`: 32bit values are zero extended by `movl`", mne))),
+        (Some(s), Some(d)) if s.bytes() < d.bytes() =>
+            Ok(Command::TwoArg(format!("{}{}", &mne[..4], s.suffix()), Some(d), src, dest)),
+        (Some(s), Some(d)) => Err(InterpError::InvalidOperand(format!("Invalid operand sizes for `{}`: cannot extend {:?} to {:?}", mne, s, d))),
+        (Some(_), None) => Err(InterpError::InvalidOperand(format!("Ambiguous destination operand size for `{}`", mne))),
+        (None, _) => Err(InterpError::InvalidOperand(format!("Ambiguous source operand size for `{}`", mne)))
+    }
+}
+
+fn has_count_operand(mne: &str) -> bool {
+    matches!(mne, "sal" | "shl" | "sar" | "shr" |
+                  "rol" | "ror" | "rcl" | "rcr" |
+                  "shld" | "shrd")
+}
+
+// Without a suffix or a register operand, the size of a memory operand is ambiguous (ie. `add $1, (%esp)`)
+//   unless the instruction implies it (ie. `push (%eax)`, `jmp *(%eax)` or `sete (%eax)`)
+fn memory_size(mne: &str, size: Option<Size>, args: &[&Argument]) -> Result<Option<Size>, InterpError> {
+    let memory = args.iter().any(|arg| matches!(**arg, Argument::Mem(..) | Argument::Label(_)));
+    let implied = matches!(mne, "push" | "pop" | "call" | "jmp" | "ret" | "enter" | "int") ||
+                  mne.starts_with('j') || mne.starts_with("loop") || mne.starts_with("set");
+
+    if size.is_none() && memory && !implied {
+        return Err(InterpError::InvalidOperand(
+            format!("Ambiguous operand size for `{}`: add a size suffix (or `dword ptr` in Intel syntax)", mne)));
+    }
+    Ok(size)
+}
+
+fn operand_size(mne: &str, mut size: Option<Size>, args: &[&Argument]) -> Result<Option<Size>, InterpError> {
+    for arg in args {
+        if let &Argument::Reg(r) = arg {
+            let reg_size = ximpl::reg_location(r).and_then(|(_, _, len)| Size::from_bytes(len));
+
+            match (size, reg_size) {
+                (Some(s), Some(rs)) if s != rs =>
+                    return Err(InterpError::InvalidOperand(format!("Operand size mismatch: `{}` expects {:?} operands but `%{}` is {:?}", mne, s, r, rs))),
+                (None, rs) => size = rs,
+                _ => ()
+            }
+        }
+    }
+
+    Ok(size)
+}
 named!(register<Argument>, do_parse!(
     tag!("%") >>
-    reg: map_res!(
-        alt!(
-            tag!("ah") | tag!("al") |
-            tag!("ch") | tag!("cl") |
-            tag!("dh") | tag!("dl") |
-            tag!("bh") | tag!("bl") |
-            tag!("ax") | tag!("cx") |
-            tag!("dx") | tag!("bx") |
-            tag!("sp") | tag!("bp") |
-            tag!("si") | tag!("di") |
-            tag!("eax") | tag!("ecx") |
-            tag!("edx") | tag!("ebx") |
-            tag!("esp") | tag!("ebp") |
-            tag!("esi") | tag!("edi") |
-            tag!("rax") | tag!("rcx") |
-            tag!("rdx") | tag!("rbx") |
-            tag!("rsp") | tag!("rbp") |
-            tag!("rsi") | tag!("rdi")
-        ), str::from_utf8) >>
+    reg: map_res!(alphanumeric, str::from_utf8) >>
+    verify!(value!(reg), |reg: &str| ximpl::reg_location(reg).is_some()) >>
     (Argument::Reg(reg.to_string()))
-));
\ No newline at end of file
+));
+
+#[cfg(test)]
+mod tests {
+    use nom::IResult;
+    use super::*;
+
+    fn no_arg(res: IResult<&[u8], Command>) -> String {
+        match res {
+            IResult::Done(_, Command::NoArg(mne, None)) => mne,
+            res => panic!("Expected an instruction without operands, got {:?}", res)
+        }
+    }
+
+    #[test]
+    fn mnemonics_with_digits() {
+        assert_eq!(no_arg(x86_instruction("endbr64")), "endbr64");
+        assert_eq!(no_arg(x86_instruction("  endbr32  ")), "endbr32");
+        assert_eq!(no_arg(intel_instruction("endbr64")), "endbr64");
+    }
+
+    fn literal_source(res: IResult<&[u8], Command>) -> i64 {
+        match res {
+            IResult::Done(_, Command::TwoArg(_, _, Argument::Literal(val), _)) => val,
+            res => panic!("Expected an immediate source operand, got {:?}", res)
+        }
+    }
+
+    #[test]
+    fn char_immediates() {
+        assert_eq!(literal_source(x86_instruction("movl $'A', %ebx")), 65);
+        assert_eq!(literal_source(x86_instruction("movb $'#', %al  ")), 35);
+        assert_eq!(literal_source(x86_instruction("movb $'\\n', %al")), 10);
+        assert_eq!(literal_source(x86_instruction("cmpb $'\\'', (%esi)")), 39);
+        assert_eq!(literal_source(intel_instruction("mov al, 'z'")), 122);
+        assert_eq!(strip_comment("movb $'#', %al # a hash"), "movb $'#', %al ");
+    }
+
+    // The displacement of a `disp(%eax)` source operand (`None` if the instruction doesn't parse)
+    fn displacement(input: &str) -> Option<i32> {
+        match x86_instruction(input) {
+            IResult::Done(_, Command::TwoArg(_, _, Argument::Mem(_, _, _, disp, None), _)) => Some(disp),
+            IResult::Done(_, cmd) => panic!("Expected a memory source operand, got {:?}", cmd),
+            _ => None
+        }
+    }
+
+    #[test]
+    fn displacement_limits() {
+        assert_eq!(displacement("movl -0x80000000(%eax), %ebx"), Some(i32::MIN));
+        assert_eq!(displacement("movl -2147483648(%eax), %ebx"), Some(i32::MIN));
+        assert_eq!(displacement("movl 0x7fffffff(%eax), %ebx"), Some(i32::MAX));
+        assert_eq!(displacement("movl 0xffffffff(%eax), %ebx"), None);
+        assert_eq!(displacement("movl -0x80000001(%eax), %ebx"), None);
+        assert_eq!(displacement("movl 2147483648(%eax), %ebx"), None);
+    }
+
+    fn intel_displacement(input: &str) -> Option<i32> {
+        match intel_instruction(input) {
+            IResult::Done(_, Command::TwoArg(_, _, Argument::Mem(_, _, _, disp, _), _)) => Some(disp),
+            IResult::Done(_, cmd) => panic!("Expected a memory source operand, got {:?}", cmd),
+            _ => None
+        }
+    }
+
+    #[test]
+    fn intel_displacement_limits() {
+        assert_eq!(intel_displacement("mov eax, [ebx+0x7fffffff]"), Some(i32::MAX));
+        assert_eq!(intel_displacement("mov eax, [ebx-0x80000000]"), Some(i32::MIN));
+        assert_eq!(intel_displacement("mov eax, [ebx+0x7fffffff+1-2]"), Some(0x7fff_fffe));
+        assert_eq!(intel_displacement("mov eax, [ebx+4*ecx+-8]"), Some(-8));
+        assert_eq!(intel_displacement("mov eax, [ebx+0x7fffffff+1]"), None);
+        assert_eq!(intel_displacement("mov eax, [ebx-0x80000000-1]"), None);
+        assert_eq!(intel_displacement("mov eax, [0xffffffff]"), None);
+    }
+
+    #[test]
+    fn local_labels() {
+        assert_eq!(label(".L2:").map(|cmd| cmd.to_string()), IResult::Done(&b""[..], ".L2:".to_string()));
+        assert_eq!(label("counter.0:\tmovl $1, %eax").map(|cmd| cmd.to_string()),
+                   IResult::Done(&b"movl $1, %eax"[..], "counter.0:".to_string()));
+
+        let parsed = |line| match x86_instruction(line) {
+            IResult::Done(_, cmd) => cmd.to_string(),
+            res => panic!("`{}` didn't parse: {:?}", line, res)
+        };
+        assert_eq!(parsed("jmp .L2"), "jmp .L2");
+        assert_eq!(parsed("movl $.LC0, %eax"), "mov $.LC0,%eax");
+        assert_eq!(parsed("movl .LC0+4(,%eax,4), %ebx"), "mov .LC0+0x4(,%eax,4),%ebx");
+        assert_eq!(parsed("leaq .LC1(%rip), %rdi"), "lea .LC1(%rip),%rdi");
+        assert_eq!(parsed("incl counter.0"), "incl counter.0");
+
+        // A lone `.` is still the current address
+        assert_eq!(assignment("len = . - .LC0").map(|(_, terms)| terms),
+                   Some(vec![(1, Term::Here), (-1, Term::Symbol(".LC0".to_string()))]));
+    }
+
+    #[test]
+    fn local_labels_in_source() {
+        use emu::Emulator;
+        use host::NullIo;
+
+        let code = ".section .rodata
+.LC0: .long 5, 7
+.text
+                    movl $.LC0, %esi
+movl $0, %ecx
+movl $0, %eax
+                    .L2:
+addl (%esi,%ecx,4), %eax
+incl %ecx
+cmpl $2, %ecx
+jne .L2
+                    jmp .L3
+movl $0, %eax
+.L3: movl .LC0+4, %ebx";
+        let mut emu = Emulator::new();
+        emu.setIo(Box::new(NullIo));
+        emu.load(code.lines().map(|l| l.to_owned()), Syntax::ATT).unwrap();
+        emu.execute().unwrap();
+        assert_eq!(emu.getReg("eax").unwrap().get(), 12);
+        assert_eq!(emu.getReg("ebx").unwrap().get(), 7);
+    }
+
+    fn sized(line: &str, syntax: Syntax) -> Result<Option<Size>, InterpError> {
+        let cmd = match syntax {
+            Syntax::ATT => x86_instruction(line),
+            Syntax::Intel => intel_instruction(line)
+        };
+        match cmd {
+            IResult::Done(_, cmd) => match resolve_size(cmd)? {
+                Command::OneArg(_, size, _) | Command::TwoArg(_, size, _, _) | Command::ThreeArg(_, size, _, _, _) => Ok(size),
+                cmd => panic!("Expected an instruction with operands, got {:?}", cmd)
+            },
+            res => panic!("`{}` didn't parse: {:?}", line, res)
+        }
+    }
+
+    #[test]
+    fn ambiguous_memory_operands() {
+        for line in ["add $1, (%esp)", "inc counter", "not (%eax)", "shl (%eax)", "shr %cl, 4(%ebx)", "test $1, (%eax)"].iter() {
+            let size = sized(line, Syntax::ATT);
+            assert!(size.is_err(), "`{}` gave {:?}", line, size);
+        }
+        assert!(sized("add [esp], 1", Syntax::Intel).is_err());
+        assert!(sized("inc [eax]", Syntax::Intel).is_err());
+
+        assert_eq!(sized("addl $1, (%esp)", Syntax::ATT).unwrap(), Some(Size::Long));
+        assert_eq!(sized("add %al, (%esp)", Syntax::ATT).unwrap(), Some(Size::Byte));
+        assert_eq!(sized("shrw %cl, 4(%ebx)", Syntax::ATT).unwrap(), Some(Size::Word));
+        assert_eq!(sized("add dword ptr [esp], 1", Syntax::Intel).unwrap(), Some(Size::Long));
+        assert_eq!(sized("mov [esp], ax", Syntax::Intel).unwrap(), Some(Size::Word));
+
+        // The instruction implies the size (or there's no memory operand)
+        for line in ["push (%eax)", "pop counter", "jmp *(%eax)", "call *4(%ebx)", "sete (%eax)", "push $1", "int $0x80"].iter() {
+            assert_eq!(sized(line, Syntax::ATT).unwrap(), None, "`{}`", line);
+        }
+    }
+
+    #[test]
+    fn endbr_in_source() {
+        use emu::Emulator;
+        use host::NullIo;
+
+        let code = "_start:\nendbr32\nmovl $7, %eax\nendbr64";
+        let mut emu = Emulator::new();
+        emu.setIo(Box::new(NullIo));
+        emu.load(code.lines().map(|l| l.to_owned()), Syntax::ATT).unwrap();
+        emu.execute().unwrap();
+        assert_eq!(emu.getReg("eax").unwrap().get(), 7);
+    }
+}
@@ -0,0 +1,263 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use emu::Emulator;
+use error::InterpError;
+use host::Stream;
+use ximpl::Size;
+
+// Linux system calls, made with `int $0x80` (the i386 ABI) or `syscall` (the x86-64 ABI)
+//   Failures return `-errno` like the kernel does, only faults in the emulator itself are errors
+
+// Syscall tables: arch/x86/entry/syscalls/syscall_{32,64}.tbl in the Linux source
+// https://github.com/torvalds/linux/tree/master/arch/x86/entry/syscalls
+
+// The process ID reported to the program (runs are reproducible)
+const PID: i64 = 1000;
+
+// `errno` values
+const EBADF: i64 = 9;
+const EIO: i64 = 5;
+const EFAULT: i64 = 14;
+const ENOSYS: i64 = 38;
+
+#[derive(Clone, Copy, Debug)]
+enum Call {
+    Exit,
+    ExitGroup,
+    Read,
+    Write,
+    Getpid,
+    Brk,
+    Time
+}
+
+// `int $0x80`: number in `%eax`, arguments in `%ebx`, `%ecx`, `%edx`, `%esi`, `%edi`, `%ebp`
+//   (also available in 64bit mode, where only the low halves are used)
+pub fn int80(emu: &mut Emulator) -> Result<(), InterpError> {
+    let call = match emu.getReg("eax")?.get_unsigned() {
+        1 => Some(Call::Exit),
+        3 => Some(Call::Read),
+        4 => Some(Call::Write),
+        13 => Some(Call::Time),
+        20 => Some(Call::Getpid),
+        45 => Some(Call::Brk),
+        252 => Some(Call::ExitGroup),
+        _ => None
+    };
+
+    let args = arguments(emu, &["ebx", "ecx", "edx", "esi", "edi", "ebp"])?;
+    let res = match call {
+        Some(call) => run(emu, call, &args, Size::Long)?,
+        None => -ENOSYS
+    };
+    emu.getReg("eax")?.set(res)
+}
+
+// `syscall`: number in `%rax`, arguments in `%rdi`, `%rsi`, `%rdx`, `%r10`, `%r8`, `%r9`
+//   `%rcx` and `%r11` are overwritten with the return address and the flags
+pub fn syscall(emu: &mut Emulator) -> Result<(), InterpError> {
+    let call = match emu.getReg("rax")?.get_unsigned() {
+        0 => Some(Call::Read),
+        1 => Some(Call::Write),
+        12 => Some(Call::Brk),
+        39 => Some(Call::Getpid),
+        60 => Some(Call::Exit),
+        201 => Some(Call::Time),
+        231 => Some(Call::ExitGroup),
+        _ => None
+    };
+
+    let ret = emu.getPC();
+    let flags = emu.getEflags();
+    emu.getReg("rcx")?.set(ret as i64)?;
+    emu.getReg("r11")?.set(flags as i64)?;
+
+    let args = arguments(emu, &["rdi", "rsi", "rdx", "r10", "r8", "r9"])?;
+    let res = match call {
+        Some(call) => run(emu, call, &args, Size::Quad)?,
+        None => -ENOSYS
+    };
+    emu.getReg("rax")?.set(res)
+}
+
+fn arguments(emu: &mut Emulator, regs: &[&str]) -> Result<Vec<u64>, InterpError> {
+    regs.iter().map(|reg| emu.getReg(reg).map(|r| r.get_unsigned())).collect()
+}
+
+// Returns the value for the accumulator (`word` is the size of `long`/pointers in the ABI)
+fn run(emu: &mut Emulator, call: Call, args: &[u64], word: Size) -> Result<i64, InterpError> {
+    match call {
+        // There's only the one thread, so both end the process
+        //   (only the low byte of the status reaches the parent)
+        Call::Exit | Call::ExitGroup => {
+            emu.exitWith(args[0] as i32 & 0xff);
+            Ok(0)
+        },
+        Call::Write => {
+            let bytes = match emu.readBytes(args[1], args[2]) {
+                Ok(bytes) => bytes,
+                Err(_) => return Ok(-EFAULT)
+            };
+
+            let stream = match args[0] {
+                1 => Stream::Stdout,
+                2 => Stream::Stderr,
+                _ => return Ok(-EBADF)
+            };
+            let res = emu.getIo().write(stream, &bytes);
+            Ok(res.map(|_| bytes.len() as i64).unwrap_or(-EIO))
+        },
+        Call::Read => {
+            if args[0] != 0 {
+                return Ok(-EBADF);
+            }
+            // Check the buffer before taking any input
+            let mut buf = match emu.readBytes(args[1], args[2]) {
+                Ok(buf) => buf,
+                Err(_) => return Ok(-EFAULT)
+            };
+
+            // A read can return less than `count` bytes: a terminal stops at the end of the line,
+            //   while a `BufferIo` returns up to `count` of the bytes that are left (newlines included)
+            let res = emu.getIo().read(&mut buf);
+            match res {
+                Ok(len) => {
+                    emu.writeBytes(args[1], &buf[..len])?;
+                    Ok(len as i64)
+                },
+                Err(_) => Ok(-EIO)
+            }
+        },
+        Call::Getpid => Ok(PID),
+        // Returns the new break, or the current one if it can't be moved there
+        //   (the whole heap region is mapped, so it's just bookkeeping)
+        Call::Brk => {
+            let heap = emu.getRegion("heap").cloned();
+            match heap {
+                Some(ref heap) if args[0] >= heap.start && args[0] <= heap.end => emu.setBreak(args[0]),
+                _ => ()
+            }
+            Ok(emu.getBreak() as i64)
+        },
+        Call::Time => {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0) as i64;
+            if args[0] != 0 && emu.getMemory(args[0] as i64, word).and_then(|mut m| m.set(now)).is_err() {
+                return Ok(-EFAULT);
+            }
+            Ok(now)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use emu::Emulator;
+    use host::BufferIo;
+    use memory::{Layout, DEFAULT_STACK_SIZE};
+    use ximpl::Syntax;
+    use super::*;
+
+    // Run a program with the given stdin, the program's output is left in the returned `BufferIo`
+    fn run(layout: Layout, code: &str, stdin: &[u8]) -> (Emulator, BufferIo) {
+        let io = BufferIo::new(stdin);
+        let mut emu = Emulator::with_layout(layout, DEFAULT_STACK_SIZE);
+        emu.setIo(Box::new(io.clone()));
+        emu.load(code.lines().map(|l| l.to_owned()), Syntax::ATT).unwrap();
+        emu.execute().unwrap();
+        (emu, io)
+    }
+
+    fn reg(emu: &mut Emulator, reg: &str) -> i64 {
+        emu.getReg(reg).unwrap().get()
+    }
+
+    const MESSAGE: &str = ".data\nmsg: .ascii \"hello\\n\"\n.text\n";
+
+    #[test]
+    fn write() {
+        let code = format!("{}movl $4, %eax\nmovl $1, %ebx\nmovl $msg, %ecx\nmovl $6, %edx\nint $0x80\nmovl %eax, %esi\n\
+                            movl $4, %eax\nmovl $2, %ebx\nmovl $3, %edx\nint $0x80", MESSAGE);
+        let (mut emu, io) = run(Layout::I386, &code, b"");
+        assert_eq!(reg(&mut emu, "esi"), 6);
+        assert_eq!(reg(&mut emu, "eax"), 3);
+        assert_eq!(io.stdout(), "hello\n");
+        assert_eq!(io.stderr(), "hel");
+    }
+
+    #[test]
+    fn write_errors() {
+        let code = format!("{}movl $4, %eax\nmovl $7, %ebx\nmovl $msg, %ecx\nmovl $6, %edx\nint $0x80\nmovl %eax, %esi\n\
+                            movl $4, %eax\nmovl $1, %ebx\nmovl $0, %ecx\nint $0x80", MESSAGE);
+        let (mut emu, io) = run(Layout::I386, &code, b"");
+        assert_eq!(reg(&mut emu, "esi"), -EBADF);
+        assert_eq!(reg(&mut emu, "eax"), -EFAULT);
+        assert_eq!(io.stdout(), "");
+    }
+
+    #[test]
+    fn read() {
+        let code = ".bss\nbuf: .zero 8\n.text\nmovl $3, %eax\nmovl $0, %ebx\nmovl $buf, %ecx\nmovl $8, %edx\nint $0x80\n\
+                    movl %eax, %esi\nmovl buf, %edi\nmovl $3, %eax\nint $0x80\nmovl %eax, %ebp\n\
+                    movl $3, %eax\nmovl $1, %ebx\nint $0x80";
+        let (mut emu, _) = run(Layout::I386, code, b"hi!");
+        assert_eq!(reg(&mut emu, "esi"), 3);
+        assert_eq!(reg(&mut emu, "edi"), 0x0021_6968);
+        // At the end of the input, and from a stream that isn't stdin
+        assert_eq!(reg(&mut emu, "ebp"), 0);
+        assert_eq!(reg(&mut emu, "eax"), -EBADF);
+    }
+
+    #[test]
+    fn buffered_reads_span_lines() {
+        let code = ".bss\nbuf: .zero 8\n.text\nmovl $3, %eax\nmovl $0, %ebx\nmovl $buf, %ecx\nmovl $5, %edx\nint $0x80\n\
+                    movl %eax, %esi\nmovl $3, %eax\nmovl $buf+5, %ecx\nint $0x80\nmovl %eax, %edi\n\
+                    movl $3, %eax\nint $0x80";
+        let (mut emu, _) = run(Layout::I386, code, b"ab\ncd\n");
+        assert_eq!(reg(&mut emu, "esi"), 5);
+        assert_eq!(reg(&mut emu, "edi"), 1);
+        assert_eq!(reg(&mut emu, "eax"), 0);
+
+        let buf = emu.getSymbol("buf").unwrap();
+        assert_eq!(emu.readBytes(buf, 6).unwrap(), b"ab\ncd\n");
+    }
+
+    #[test]
+    fn process_calls() {
+        let code = "movl $20, %eax\nint $0x80\nmovl %eax, %esi\nmovl $1000, %eax\nint $0x80\nmovl %eax, %edi\n\
+                    movl $45, %eax\nmovl $0, %ebx\nint $0x80";
+        let (mut emu, _) = run(Layout::I386, code, b"");
+        assert_eq!(reg(&mut emu, "esi"), PID);
+        assert_eq!(reg(&mut emu, "edi"), -ENOSYS);
+        let heap = emu.getRegion("heap").unwrap().start as i64;
+        assert_eq!(reg(&mut emu, "eax"), heap);
+
+        // The break moves within the heap, and stays put otherwise
+        let code = format!("movl $45, %eax\nmovl ${}, %ebx\nint $0x80\nmovl %eax, %esi\nmovl $45, %eax\nmovl $-1, %ebx\nint $0x80", heap + 0x1000);
+        let (mut emu, _) = run(Layout::I386, &code, b"");
+        assert_eq!(reg(&mut emu, "esi"), heap + 0x1000);
+        assert_eq!(reg(&mut emu, "eax"), heap + 0x1000);
+    }
+
+    #[test]
+    fn exit_status() {
+        let (mut emu, _) = run(Layout::I386, "movl $0, %esi\nmovl $252, %eax\nmovl $0x1ff, %ebx\nint $0x80\nmovl $1, %esi", b"");
+        assert_eq!(emu.getExitStatus(), 0xff);
+        assert!(!emu.run());
+        assert_eq!(reg(&mut emu, "esi"), 0);
+    }
+
+    #[test]
+    fn syscall_64() {
+        let code = format!("{}movq $1, %rax\nmovq $1, %rdi\nleaq msg(%rip), %rsi\nmovq $6, %rdx\nsyscall\nmovq %rax, %rbx\n\
+                            movq $39, %rax\nsyscall\nmovq %rax, %r12\nmovq $60, %rax\nmovq $3, %rdi\nsyscall", MESSAGE);
+        let (mut emu, io) = run(Layout::X86_64, &code, b"");
+        assert_eq!(io.stdout(), "hello\n");
+        assert_eq!(reg(&mut emu, "rbx"), 6);
+        assert_eq!(reg(&mut emu, "r12"), PID);
+        assert_eq!(emu.getExitStatus(), 3);
+
+        // `%rcx` holds the address following the last `syscall`, `%r11` the flags
+        let end = emu.getCodeRange().unwrap().1 as i64;
+        assert_eq!(reg(&mut emu, "rcx"), end);
+        assert_eq!(reg(&mut emu, "r11") & 0x202, 0x202);
+    }
+}
@@ -1,12 +1,15 @@
-use std::{mem, ops};
-use bit_vec::BitVec;
+use std::ops;
+use error::InterpError;
 
 // Note: I considered having 'Reg8'/'Reg16'/etc. views instead
 // This allows for overriding 'DerefMut', an easier implementation
 // However, the rust type system currently doesn't support this option
 pub struct Memory<'a> {
     loc: &'a mut [u8],
-    pub cpu_flags: &'a BitVec,
+    // Continuation of `loc` (ie. the start of the next page for accesses that cross pages)
+    rest: &'a mut [u8],
+    // Bytes that get cleared on every write (ie. the upper half of `%rax` when writing `%eax`)
+    ext: &'a mut [u8],
 }
 
 // pub struct Memory<'a, T> {
@@ -21,105 +24,110 @@ pub struct Memory<'a> {
 // }
 
 impl<'a> Memory<'a> {
-    pub fn new(vec: &'a BitVec, loc: &'a mut [u8]) -> Memory<'a> {
-        Memory{ cpu_flags: vec, loc: loc }
+    pub fn new(loc: &'a mut [u8]) -> Memory<'a> {
+        Memory{ loc, rest: &mut [], ext: &mut [] }
     }
 
-    fn imm_transmute<T>(&self) -> &'a T {
-        unsafe { mem::transmute(self.loc.as_ptr()) }
+    // A view made up of the bytes in `loc` followed by those in `rest`
+    pub fn split(loc: &'a mut [u8], rest: &'a mut [u8]) -> Memory<'a> {
+        Memory{ loc, rest, ext: &mut [] }
     }
 
-    fn transmute<T>(&mut self) -> &'a mut T {
-        unsafe { mem::transmute(self.loc.as_mut_ptr()) }
+    // Writes through this view will zero out `ext` (x86-64 32bit register semantics)
+    pub fn zero_extended(loc: &'a mut [u8], ext: &'a mut [u8]) -> Memory<'a> {
+        Memory{ loc, rest: &mut [], ext }
     }
 
-    fn check_value_size(&self, val: i32) -> bool {
-        match self.loc.len() {
-            4 => val > i32::max_value(),
-            2 => val > i16::max_value() as i32,
-            1 => val > i8::max_value() as i32,
-            _ => true
+    // Memory is stored in little-endian byte order
+    fn read(&self) -> u64 {
+        self.loc.iter().chain(self.rest.iter()).rev().fold(0, |acc, &byt| (acc << 8) | byt as u64)
+    }
+
+    fn len(&self) -> usize {
+        self.loc.len() + self.rest.len()
+    }
+
+    fn write(&mut self, value: i64) {
+        let mut value = value as u64;
+        for byt in self.loc.iter_mut().chain(self.rest.iter_mut()) {
+            *byt = value as u8;
+            value >>= 8;
+        }
+
+        for byt in self.ext.iter_mut() {
+            *byt = 0;
+        }
+    }
+
+    // Values may be given in either their signed or unsigned representation
+    //   Views are always 1, 2, 4 or 8 bytes (see `Emulator::getMemorySized`)
+    fn check_value_size(&self, val: i64) -> bool {
+        match self.len() {
+            8 => false,
+            n => {
+                let bits = 8 * n as u32;
+                val < -(1 << (bits - 1)) || val >= (1 << bits)
+            }
         }
     }
 
     // 'Getters'
-    pub fn get_unsigned(&self) -> u32 {
-        *self.imm_transmute()
+    pub fn get_unsigned(&self) -> u64 {
+        self.read()
     }
-    pub fn get(&self) -> i32 {
-        *self.imm_transmute()
+    pub fn get(&self) -> i64 {
+        let shift = 64 - 8 * self.len() as u32;
+        ((self.read() << shift) as i64) >> shift
     }
 
     // 'Setters'
-    pub fn set<T: Into<i32>>(&mut self, value: T) -> Result<(), String> {
+    pub fn set<T: Into<i64>>(&mut self, value: T) -> Result<(), InterpError> {
         let value = value.into();
         if self.check_value_size(value) {
-            Err("Memory segment is too small for the given value type".to_owned())
+            Err(InterpError::InvalidOperand(
+                format!("Value {} is too large for a {} byte location", value, self.len())))
         } else {
-            match self.loc.len() {
-                4 => Ok(*self.transmute::<i32>() = value),
-                2 => Ok(*self.transmute::<i16>() = value as i16),
-                1 => Ok(*self.transmute::<i8>() = value as i8),
-                _ => panic!("Code should not be able to reach this point due to the size check")
-            }
+            self.write(value);
+            Ok(())
         }
     }
 }
 
+// The result is truncated to the width of the memory segment (like the instructions themselves)
 macro_rules! impl_assign_op {
-    ($_type:ident, $_fn: ident) => {
-        impl<'a, T: Into<i32>> ops::$_type<T> for Memory<'a> {
+    ($_type:ident, $_fn: ident, $_op: expr) => {
+        impl<'a, T: Into<i64>> ops::$_type<T> for Memory<'a> {
             fn $_fn(&mut self, value: T) {
-                let value = value.into();
-                if self.check_value_size(value) {
-                    panic!("Memory is too small for the given value type")
-                }
-
-                match self.loc.len() {
-                    4 => self.transmute::<i32>().$_fn(value),
-                    2 => self.transmute::<i16>().$_fn(value as i16),
-                    1 => self.transmute::<i8>().$_fn(value as i8),
-                    _ => panic!("Invalid Memory size")
-                }
+                let res = $_op(self.get(), value.into());
+                self.write(res)
             }
         }
-        
-        // NOTE: `i32` probably implements `Into<u32> + Into<i32>`
+
+        // NOTE: `i64` probably implements `Into<u64> + Into<i64>`
         // That makes the unsigned interface unhandleable
-        // impl<'a> ops::$_type<u32> for Memory<'a> {}
+        // impl<'a> ops::$_type<u64> for Memory<'a> {}
     };
 }
 
-impl_assign_op!(AddAssign, add_assign);
-impl_assign_op!(BitAndAssign, bitand_assign);
-impl_assign_op!(BitOrAssign, bitor_assign);
-impl_assign_op!(BitXorAssign, bitxor_assign);
-impl_assign_op!(DivAssign, div_assign);
-impl_assign_op!(MulAssign, mul_assign);
-impl_assign_op!(RemAssign, rem_assign);
-impl_assign_op!(SubAssign, sub_assign);
+impl_assign_op!(AddAssign, add_assign, i64::wrapping_add);
+impl_assign_op!(BitAndAssign, bitand_assign, ops::BitAnd::bitand);
+impl_assign_op!(BitOrAssign, bitor_assign, ops::BitOr::bitor);
+impl_assign_op!(BitXorAssign, bitxor_assign, ops::BitXor::bitxor);
+impl_assign_op!(MulAssign, mul_assign, i64::wrapping_mul);
+impl_assign_op!(SubAssign, sub_assign, i64::wrapping_sub);
+// Division is left to `div`/`idiv` so it can raise a divide error
 
 
 impl<'a, T: Into<i8>> ops::ShlAssign<T> for Memory<'a> {
     fn shl_assign(&mut self, value: T) {
-        let value = value.into();
-        match self.loc.len() {
-            4 => *self.transmute::<i32>() <<= value,
-            2 => *self.transmute::<i16>() <<= value,
-            1 => *self.transmute::<i8>() <<= value,
-            _ => panic!("Invalid Memory size")
-        }
+        let res = self.get().wrapping_shl(value.into() as u32);
+        self.write(res)
     }
 }
 
 impl<'a, T: Into<i8>> ops::ShrAssign<T> for Memory<'a> {
     fn shr_assign(&mut self, value: T) {
-        let value = value.into();
-        match self.loc.len() {
-            4 => *self.transmute::<i32>() >>= value,
-            2 => *self.transmute::<i16>() >>= value,
-            1 => *self.transmute::<i8>() >>= value,
-            _ => panic!("Invalid Memory size")
-        }
+        let res = self.get().wrapping_shr(value.into() as u32);
+        self.write(res)
     }
-}
\ No newline at end of file
+}
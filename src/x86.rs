@@ -1,66 +1,76 @@
 use emu;
-use inter;
+use encode;
+use memory;
+use memory::Layout;
 use parse;
 use nom::IResult;
-use ximpl::{Code, Command};
+use ximpl;
+use ximpl::{Code, Command, Mode, Syntax};
+use error::InterpError;
 
-pub fn interpret_code(code_str: &str) {
-    interpret_iter(code_str.split("\n").map(|s| s.to_owned()));
+pub fn interpret_code(code_str: &str) -> Result<(), InterpError> {
+    interpret_code_syntax(code_str, Syntax::ATT)
 }
 
-pub fn interpret_iter<I: Iterator<Item=String>>(code_iter: I) {
-    // Perform initial splitting of code
-    let mut code = first_parse(code_iter);
-
-    // Initialize registers and other assembly resources
-    let mut emu = emu::Emulator::new();
-    inter::collect_labels(&mut code, &mut emu);
-
-    // Run the interpretation loop
-    while emu.run() {
-
-        // I'm not sure why, but 'rustc' flags this section as forgetting to use 'Result', which it does
-        #![allow(unused_must_use)]
-        match fetch(&mut code, emu.getPC()) {
-            Ok(Some(&Code::Parsed(ref inst))) => inter::dispatch(inst, &mut emu),
-            Err(e) => {
-                println!("{}", e);
-                break
-            }
-            _ => break
-        };
-    }
+// Interpret code that starts out in the given syntax (`.intel_syntax`/`.att_syntax` can still switch)
+pub fn interpret_code_syntax(code_str: &str, syntax: Syntax) -> Result<(), InterpError> {
+    interpret_code_with_layout(code_str, syntax, Layout::I386, memory::DEFAULT_STACK_SIZE)
+}
+
+// Interpret code in the given memory layout with a `stack_size` byte stack
+pub fn interpret_code_with_layout(code_str: &str, syntax: Syntax, layout: Layout, stack_size: u64) -> Result<(), InterpError> {
+    interpret_iter(code_str.split("\n").map(|s| s.to_owned()), syntax, layout, stack_size)
+}
+
+// The registers are dumped even if the program stops with an error
+pub fn interpret_iter<I: Iterator<Item=String>>(code_iter: I, syntax: Syntax, layout: Layout, stack_size: u64) -> Result<(), InterpError> {
+    let mut emu = emu::Emulator::with_layout(layout, stack_size);
+    run_program(&mut emu, code_iter, syntax)
+}
+
+// Load and run a program on an existing emulator (ie. to look at its memory afterwards)
+pub fn run_program<I: Iterator<Item=String>>(emu: &mut emu::Emulator, code_iter: I, syntax: Syntax) -> Result<(), InterpError> {
+    // Faults (ie. `#DE` from `div`) stop the program just like parse errors
+    let res = emu.load(code_iter, syntax).and_then(|_| emu.execute());
 
-    emu.dumpRegisters();
-    emu.dumpLabels();
+    emu.dump_all();
+    res
 
     // println!("\n   ::: x86 Emulator Instruction Dump :::");
     // println!("{:?}", code);
 }
 
 // Grab and decode the next instruction
-fn fetch<'a>(code: &'a mut Vec<Code>, pc: usize) -> Result<Option<&'a Code>, String> {
+pub fn fetch(code: &mut [Code], pc: usize) -> Result<Option<&Code>, InterpError> {
     let res = match code.get(pc) {
-        Some(&Code::Unread(ref line)) if line.trim() == "" => Code::Parsed(Command::NOP),
-        Some(&Code::Unread(ref line)) =>
-            match second_parse(line) {
-                Ok(line) => Code::Parsed(line),
-                Err(e) => return Err(e)
+        Some(&Code::Unread(ref text, _, line)) if text.trim() == "" => Code::Parsed(Command::Nop, line),
+        Some(&Code::Unread(ref text, syntax, line)) =>
+            match second_parse(text, syntax) {
+                Ok(inst) => Code::Parsed(inst, line),
+                Err(e) => return Err(e.at(line + 1, text))
             },
         _ => Code::EndProgram
     };
 
-    if let Code::Parsed(line) = res {
-        code[pc] = Code::Parsed(line);
+    if let Code::Parsed(inst, line) = res {
+        code[pc] = Code::Parsed(inst, line);
     }
 
     Ok(code.get(pc))
 }
 
 // Perform initial organization of the input string
-fn first_parse<I: Iterator<Item=String>>(code_iter: I) -> Vec<Code> {
-    let mut ret = code_iter.map(|s| Code::Unread(s))
-                           .collect::<Vec<_>>();
+//   Syntax directives apply to every line that follows them in the source,
+//   and comments are dropped (the source keeps them for error messages)
+pub fn first_parse(source: &[String], mut syntax: Syntax) -> Vec<Code> {
+    let mut ret = source.iter()
+                        .enumerate()
+                        .map(|(line, s)| {
+                            let s = parse::strip_comment(s);
+                            syntax = parse::syntax_directive(s).unwrap_or(syntax);
+                            Code::Unread(s.to_string(), syntax, line)
+                        })
+                        .collect::<Vec<_>>();
 
     // Add an instruction at the end to avoid indexing issues
     // Not necessary, but removing this will break `collect_labels`
@@ -69,11 +79,42 @@ fn first_parse<I: Iterator<Item=String>>(code_iter: I) -> Vec<Code> {
     ret
 }
 
+// Parse every line of code (instructions have to be parsed before they can be assembled)
+pub fn parse_code(code: &mut [Code]) -> Result<(), InterpError> {
+    for pc in 0..code.len() {
+        fetch(code, pc)?;
+    }
+    Ok(())
+}
+
+// Assign an address to every line of code (starting at `base`)
+//   Instructions take up as many bytes as they assemble to, while lines without one
+//   (blank lines and directives) take up no space (and are never executed) apart from alignment padding,
+//   the final entry (`EndProgram`) is the address just past the program
+pub fn code_addresses(code: &[Code], base: u64, mode: Mode, source: &[String]) -> Result<Vec<u64>, InterpError> {
+    let mut addr = base;
+    code.iter().map(|entry| {
+        let start = addr;
+        match *entry {
+            // Alignment padding depends on the address
+            Code::Parsed(Command::Align(align, _, max), _) => addr += ximpl::align_padding(align, max, addr),
+            Code::Parsed(ref inst, line) => addr += encode::length(inst, mode).map_err(|e| e.at(line + 1, &source[line]))? as u64,
+            _ => ()
+        }
+        Ok(start)
+    }).collect()
+}
+
 // TODO: Maybe look at abstracting this further (ie. break down the construction a bit more)
 // TODO: Figure out what I mean by ^
-fn second_parse(inst_str: &str) -> Result<Command, String> {
-    match parse::x86_instruction(inst_str) {
-        IResult::Done(_, res) => Ok(res),
-        _ => Err(format!("Invalid x86 instruction string: {}", inst_str))
+fn second_parse(inst_str: &str, syntax: Syntax) -> Result<Command, InterpError> {
+    let res = match syntax {
+        Syntax::ATT => parse::x86_instruction(inst_str),
+        Syntax::Intel => parse::intel_instruction(inst_str)
+    };
+
+    match res {
+        IResult::Done(_, res) => parse::resolve_size(res),
+        _ => Err(InterpError::Parse(inst_str.trim().to_string()))
     }
 }
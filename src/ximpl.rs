@@ -2,35 +2,166 @@ use std::fmt;
 
 // Types
 // TODO: Merge `Code` and `Command` (for nicer debug printing)
-//   ie. Parsed(Command) => `Command` | NOP => EndProgram
+//   ie. Parsed(Command) => `Command` | Nop => EndProgram
+//   Lines remember their (0-based) position in the source for error reporting
 pub enum Code {
-    Parsed(Command),
-    Unread(String),
+    Parsed(Command, usize),
+    Unread(String, Syntax, usize),
     EndProgram
 }
 
+// Operand sizes (named after the AT&T mnemonic suffixes)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Size {
+    Byte,               // b
+    Word,               // w
+    Long,               // l
+    Quad                // q
+}
+
+impl Size {
+    pub fn bytes(&self) -> usize {
+        match *self {
+            Size::Byte => 1,
+            Size::Word => 2,
+            Size::Long => 4,
+            Size::Quad => 8
+        }
+    }
+
+    pub fn from_bytes(len: usize) -> Option<Size> {
+        match len {
+            1 => Some(Size::Byte),
+            2 => Some(Size::Word),
+            4 => Some(Size::Long),
+            8 => Some(Size::Quad),
+            _ => None
+        }
+    }
+
+    // Reduce a value to its unsigned representation at this width
+    pub fn truncate(&self, val: i64) -> u64 {
+        match *self {
+            Size::Quad => val as u64,
+            _ => (val as u64) & ((1 << (8 * self.bytes())) - 1)
+        }
+    }
+
+    // Reinterpret the low bytes of a value as a signed value of this width
+    pub fn sign_extend(&self, val: u64) -> i64 {
+        let shift = 64 - 8 * self.bytes();
+        ((val << shift) as i64) >> shift
+    }
+
+    pub fn sign_bit(&self, val: u64) -> bool {
+        (val >> (8 * self.bytes() - 1)) & 1 == 1
+    }
+
+    pub fn from_suffix(suffix: &str) -> Option<Size> {
+        match suffix {
+            "b" => Some(Size::Byte),
+            "w" => Some(Size::Word),
+            "l" => Some(Size::Long),
+            "q" => Some(Size::Quad),
+            _ => None
+        }
+    }
+
+    pub fn suffix(&self) -> &'static str {
+        match *self {
+            Size::Byte => "b",
+            Size::Word => "w",
+            Size::Long => "l",
+            Size::Quad => "q"
+        }
+    }
+}
+
+// Assembly syntax that a line of code was written in
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Syntax {
+    ATT,
+    Intel
+}
+
+// Processor mode that code is assembled and run in (picked by the memory layout)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    Bits32,             // protected mode (no 64bit registers)
+    Bits64              // long mode
+}
+
+impl Mode {
+    // Width of stack operations, return addresses and indirect jump targets
+    pub fn stack_size(&self) -> Size {
+        match *self {
+            Mode::Bits32 => Size::Long,
+            Mode::Bits64 => Size::Quad
+        }
+    }
+}
+
+// Operand size of an instruction that has neither a suffix nor a register operand
+pub fn default_size(mne: &str, mode: Mode) -> Size {
+    match mne {
+        "push" | "pop" | "call" | "ret" | "jmp" | "leave" | "enter" => mode.stack_size(),
+        _ => Size::Long
+    }
+}
+
+// Padding that aligns `addr` (none if it needs more than `max` bytes)
+pub fn align_padding(align: u64, max: Option<u64>, addr: u64) -> u64 {
+    let pad = (align - addr % align) % align;
+    match max {
+        Some(max) if pad > max => 0,
+        _ => pad
+    }
+}
+
 #[derive(Debug)]
 pub enum Command {
     Directive(String),
     Label(String),
-    NoArg(String),
-    OneArg(String, Argument),
-    TwoArg(String, Argument, Argument),
-    NOP
+    NoArg(String, Option<Size>),
+    OneArg(String, Option<Size>, Argument),
+    TwoArg(String, Option<Size>, Argument, Argument),
+    ThreeArg(String, Option<Size>, Argument, Argument, Argument),
+    Align(u64, u8, Option<u64>),            // alignment, fill byte and most padding allowed (ie. `.p2align 4,,10`)
+    Nop
 }
 
 #[derive(Debug)]
 #[allow(dead_code)]
 pub enum Argument {
-    Literal(i32),                           // value
+    Literal(i64),                           // value
+    Address(String, i32),                   // label, offset (the address as an immediate, ie. `$msg+4`)
     Reg(String),                            // register
-    // NOTE: scale, mul properties are currently not parsed
-    Mem(Box<Argument>, i32, i32, i32),      // idx, off, scale, mul
-    Label(String),                          // label
+    Mem(Option<Box<Argument>>,              // base
+        Option<Box<Argument>>, i32, i32,    // index, scale, disp
+        Option<String>),                    // label (added to disp, ie. `table(,%ecx,4)`)
+    Label(String),                          // label (a jump target, or the memory at its address)
+    Target(u64),                            // address of a jump target (decoded from machine code)
+}
+
+// Operands of data directives (ie. `.long 4, -1, table` or `.ascii "text"`)
+#[derive(Debug, Clone, PartialEq)]
+pub enum Datum {
+    Int(i64),                               // value (character literals included)
+    Str(Vec<u8>),                           // unescaped string contents
+    Symbol(String),                         // address of a label
+}
+
+// Terms of a symbol assignment (ie. `len = . - msg`), each added or subtracted
+#[derive(Debug, Clone, PartialEq)]
+pub enum Term {
+    Here,                                   // `.`, the current location
+    Int(i64),                               // value
+    Symbol(String),                         // value of a label
 }
 
 // Enum for all CPU Flags
 #[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Flag {
     Carry,              // 0
     Parity,             // 2
@@ -48,12 +179,12 @@ pub enum Flag {
     Alignment,          // 18
     VInterrupt,         // 19
     PendingInt,         // 20
-    CPUID               // 21
+    Cpuid               // 21
 }
 
 pub fn mask_shift(f: Flag) -> usize {
     match f {
-        Flag::Carry => 1,
+        Flag::Carry => 0,
         Flag::Parity => 2,
         Flag::Adjust => 4,
         Flag::Zero => 6,
@@ -68,16 +199,173 @@ pub fn mask_shift(f: Flag) -> usize {
         Flag::Alignment => 18,
         Flag::VInterrupt => 19,
         Flag::PendingInt => 20,
-        Flag::CPUID => 21
+        Flag::Cpuid => 21
     }
 }
 
+// Names of the 16 general purpose registers (in x86 encoding order)
+pub const REGISTERS: [&str; 16] = [
+    "rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi",
+    "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15"
+];
+
+// Index of `%rip` within the register file (after the general purpose registers)
+pub const RIP: usize = 16;
+// Index of the stack pointer (`%rsp`)
+pub const RSP: usize = 4;
+
+// Find where a register name lives within the register file
+//   Returns the (register index, byte offset, byte width) of the name
+pub fn reg_location(name: &str) -> Option<(usize, usize, usize)> {
+    let loc = match name {
+        // Access the 64bit registers
+        "rax" => (0, 0, 8), "rcx" => (1, 0, 8),
+        "rdx" => (2, 0, 8), "rbx" => (3, 0, 8),
+        "rsp" => (4, 0, 8), "rbp" => (5, 0, 8),
+        "rsi" => (6, 0, 8), "rdi" => (7, 0, 8),
+
+        // Access the 32bit registers
+        "eax" => (0, 0, 4), "ecx" => (1, 0, 4),
+        "edx" => (2, 0, 4), "ebx" => (3, 0, 4),
+        "esp" => (4, 0, 4), "ebp" => (5, 0, 4),
+        "esi" => (6, 0, 4), "edi" => (7, 0, 4),
+
+        // Access the 16bit registers
+        "ax" => (0, 0, 2), "cx" => (1, 0, 2),
+        "dx" => (2, 0, 2), "bx" => (3, 0, 2),
+        "sp" => (4, 0, 2), "bp" => (5, 0, 2),
+        "si" => (6, 0, 2), "di" => (7, 0, 2),
+
+        // Access the 8bit registers
+        "al" => (0, 0, 1), "ah" => (0, 1, 1),
+        "cl" => (1, 0, 1), "ch" => (1, 1, 1),
+        "dl" => (2, 0, 1), "dh" => (2, 1, 1),
+        "bl" => (3, 0, 1), "bh" => (3, 1, 1),
+        "spl" => (4, 0, 1), "bpl" => (5, 0, 1),
+        "sil" => (6, 0, 1), "dil" => (7, 0, 1),
+
+        // Access the instruction pointer
+        "rip" => (RIP, 0, 8), "eip" => (RIP, 0, 4),
+
+        // Access the extended registers (ie. r8, r8d, r8w, r8b)
+        _ if name.starts_with("r") => {
+            let digits = name[1..].trim_end_matches(['d', 'w', 'b']);
+            let width = match &name[1 + digits.len()..] {
+                "" => 8,
+                "d" => 4,
+                "w" => 2,
+                "b" => 1,
+                _ => return None
+            };
+
+            match digits.parse::<usize>() {
+                Ok(idx) if (8..16).contains(&idx) && !digits.starts_with("0") => (idx, 0, width),
+                _ => return None
+            }
+        },
+
+        _ => return None
+    };
+
+    Some(loc)
+}
+
+// Instructions print in AT&T syntax (ie. `movl $0x4,(%eax)`)
+//   The suffix is left off when a register operand gives the size (except for `movz`/`movs`)
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (mne, size, args) = match *self {
+            Command::NoArg(ref mne, size) => (mne, size, vec![]),
+            Command::OneArg(ref mne, size, ref a1) => (mne, size, vec![a1]),
+            Command::TwoArg(ref mne, size, ref a1, ref a2) => (mne, size, vec![a1, a2]),
+            Command::ThreeArg(ref mne, size, ref a1, ref a2, ref a3) => (mne, size, vec![a1, a2, a3]),
+            Command::Directive(ref dir) => return write!(f, ".{}", dir),
+            Command::Label(ref lbl) => return write!(f, "{}:", lbl),
+            Command::Align(align, fill, Some(max)) => return write!(f, ".balign {},{},{}", align, fill, max),
+            Command::Align(align, fill, None) => return write!(f, ".balign {},{}", align, fill),
+            Command::Nop => return Ok(())
+        };
+
+        let suffix = match size {
+            Some(size) if mne.starts_with("movz") || mne.starts_with("movs") => size.suffix(),
+            Some(size) if !args.iter().any(|arg| arg.register_size() == Some(size)) => size.suffix(),
+            _ => ""
+        };
+        write!(f, "{}{}", mne, suffix)?;
+
+        for (idx, arg) in args.iter().enumerate() {
+            write!(f, "{}", if idx == 0 { " " } else { "," })?;
+
+            // Indirect jumps (ie. `jmp *%eax`)
+            let indirect = match **arg {
+                Argument::Reg(_) | Argument::Mem(..) => mne == "jmp" || mne == "call",
+                _ => false
+            };
+            write!(f, "{}{}", if indirect { "*" } else { "" }, arg)?;
+        }
+        Ok(())
+    }
+}
+
+impl Argument {
+    // Width of a register operand
+    fn register_size(&self) -> Option<Size> {
+        match *self {
+            Argument::Reg(ref r) => reg_location(r).and_then(|(_, _, len)| Size::from_bytes(len)),
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for Argument {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Argument::Literal(val) => write!(f, "${}", hex(val)),
+            Argument::Address(ref lbl, 0) => write!(f, "${}", lbl),
+            Argument::Address(ref lbl, off) => write!(f, "${}{}", lbl, signed_hex(off as i64)),
+            Argument::Reg(ref r) => write!(f, "%{}", r),
+            Argument::Mem(ref base, ref idx, scale, disp, ref lbl) => {
+                match *lbl {
+                    Some(ref lbl) if disp != 0 => write!(f, "{}{}", lbl, signed_hex(disp as i64))?,
+                    Some(ref lbl) => write!(f, "{}", lbl)?,
+                    None if disp != 0 || (base.is_none() && idx.is_none()) => write!(f, "{}", hex(disp as i64))?,
+                    None => ()
+                }
+
+                match (base, idx) {
+                    (Some(base), None) => write!(f, "({})", base),
+                    (_, Some(idx)) => {
+                        let base = base.as_ref().map(|b| b.to_string()).unwrap_or_default();
+                        write!(f, "({},{},{})", base, idx, scale)
+                    },
+                    (None, None) => Ok(())
+                }
+            },
+            Argument::Label(ref lbl) => write!(f, "{}", lbl),
+            Argument::Target(addr) => write!(f, "{:#x}", addr)
+        }
+    }
+}
+
+fn hex(val: i64) -> String {
+    if val < 0 {
+        format!("-{:#x}", val.unsigned_abs())
+    } else {
+        format!("{:#x}", val)
+    }
+}
+
+// Hex with an explicit sign (ie. the `+0x4` of `msg+0x4`)
+fn signed_hex(val: i64) -> String {
+    if val < 0 { hex(val) } else { format!("+{}", hex(val)) }
+}
+
 impl fmt::Debug for Code {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            &Code::Unread(ref s) => write!(f, "Unread({:?})", s),
+            &Code::Unread(ref s, syntax, line) => write!(f, "Unread({:?}, {:?}, {})", s, syntax, line),
             &Code::EndProgram => write!(f, "EndProgram"),
-            &Code::Parsed(ref inst) => write!(f, "{:?}", inst)
+            Code::Parsed(inst, _) => write!(f, "{:?}", inst)
         }
     }
 }
\ No newline at end of file